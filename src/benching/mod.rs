@@ -10,6 +10,7 @@ use std::time::Duration;
 use std_semaphore::Semaphore;
 
 pub mod result;
+pub mod statistics;
 
 /// Runs a benchmark on the kernel
 /// The ProQue needs to have profiling enabled
@@ -18,20 +19,36 @@ pub fn enqueue_profiled(
     kernel: &Kernel,
     sem: &Semaphore,
 ) -> ocl::Result<Duration> {
-    log::trace!("Running kernel with profiling");
+    enqueue_profiled_all(pro_que, &[kernel], sem)
+}
+
+/// Runs the kernels one after another and returns the duration of all kernels
+/// The ProQue needs to have profiling enabled
+pub fn enqueue_profiled_all(
+    pro_que: &ProQue,
+    kernels: &[&Kernel],
+    sem: &Semaphore,
+) -> ocl::Result<Duration> {
+    log::trace!("Running {} kernels with profiling", kernels.len());
     log::trace!("Acquiring lock for enqueueing");
-    sem.acquire();
-    log::trace!("Enqueueing start event");
-    let event_start = pro_que.queue().enqueue_marker::<EventList>(None)?;
-    log::trace!("Enqueueing Kernel");
-
-    unsafe {
-        kernel.enq()?;
-    }
-    log::trace!("Enqueueing stop event");
-    let event_stop = pro_que.queue().enqueue_marker::<EventList>(None)?;
-    log::trace!("Releasing enqueueing lock");
-    sem.release();
+    let (event_start, event_stop) = {
+        // the guard releases the lock even if enqueueing fails
+        let _guard = sem.access();
+        log::trace!("Enqueueing start event");
+        let event_start = pro_que.queue().enqueue_marker::<EventList>(None)?;
+        log::trace!("Enqueueing Kernels");
+
+        for kernel in kernels {
+            unsafe {
+                kernel.enq()?;
+            }
+        }
+        log::trace!("Enqueueing stop event");
+        let event_stop = pro_que.queue().enqueue_marker::<EventList>(None)?;
+        log::trace!("Releasing enqueueing lock");
+
+        (event_start, event_stop)
+    };
 
     log::trace!("Waiting for start event");
     wait_for_event(&event_start)?;
@@ -14,6 +14,7 @@ where
 {
     duration: Duration,
     value: T,
+    device: usize,
 }
 
 impl<T> ProfiledResult<T>
@@ -22,7 +23,21 @@ where
 {
     /// Creates a new profiled result with the given duration and value
     pub fn new(duration: Duration, value: T) -> Self {
-        Self { duration, value }
+        Self {
+            duration,
+            value,
+            device: 0,
+        }
+    }
+
+    /// Sets the index of the device the result was calculated on
+    pub fn set_device(&mut self, device: usize) {
+        self.device = device;
+    }
+
+    /// Returns the index of the device the result was calculated on
+    pub fn device(&self) -> usize {
+        self.device
     }
 
     /// Returns the execution duration
@@ -34,4 +49,9 @@ where
     pub fn value(&self) -> &T {
         &self.value
     }
+
+    /// Consumes the result and returns its value
+    pub fn into_value(self) -> T {
+        self.value
+    }
 }
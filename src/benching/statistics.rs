@@ -0,0 +1,269 @@
+/*
+ * opencl demos with rust
+ * Copyright (C) 2021 trivernis
+ * See LICENSE for more information
+ */
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::kernel_controller::bench::BenchStatistics;
+
+/// Two-sided 97.5% quantiles of the t-distribution for 1 to 30 degrees of freedom
+const T_QUANTILES: [f64; 30] = [
+    12.706, 4.303, 3.182, 2.776, 2.571, 2.447, 2.365, 2.306, 2.262, 2.228, 2.201, 2.179, 2.160,
+    2.145, 2.131, 2.120, 2.110, 2.101, 2.093, 2.086, 2.080, 2.074, 2.069, 2.064, 2.060, 2.056,
+    2.052, 2.048, 2.045, 2.042,
+];
+
+/// The 97.5% quantile of the normal distribution used for more than 30 degrees of freedom
+const Z_QUANTILE: f64 = 1.960;
+
+/// Statistics over a list of durations in milliseconds
+#[derive(Clone, Debug, Default)]
+pub struct DurationStatistics {
+    pub mean: f64,
+    pub median: f64,
+    pub min: f64,
+    pub max: f64,
+    /// The sample standard deviation
+    pub stddev: f64,
+    /// Lower bound of the 95% confidence interval of the mean
+    pub ci95_low: f64,
+    /// Upper bound of the 95% confidence interval of the mean
+    pub ci95_high: f64,
+}
+
+impl DurationStatistics {
+    /// Calculates the statistics for the given durations
+    pub fn new(durations: &[Duration]) -> Self {
+        if durations.is_empty() {
+            return Self::default();
+        }
+        let mut values = durations
+            .iter()
+            .map(|d| d.as_secs_f64() * 1000f64)
+            .collect::<Vec<f64>>();
+        values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+        let n = values.len();
+        let mean = values.iter().sum::<f64>() / n as f64;
+        let median = if n % 2 == 0 {
+            (values[n / 2 - 1] + values[n / 2]) / 2f64
+        } else {
+            values[n / 2]
+        };
+        let stddev = if n > 1 {
+            (values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (n - 1) as f64).sqrt()
+        } else {
+            0f64
+        };
+        let margin = if n > 1 {
+            let quantile = T_QUANTILES.get(n - 2).cloned().unwrap_or(Z_QUANTILE);
+            quantile * stddev / (n as f64).sqrt()
+        } else {
+            0f64
+        };
+
+        Self {
+            mean,
+            median,
+            min: values[0],
+            max: values[n - 1],
+            stddev,
+            ci95_low: mean - margin,
+            ci95_high: mean + margin,
+        }
+    }
+
+    /// Returns the names of the statistics prefixed with the given name
+    pub fn columns(prefix: &str) -> Vec<String> {
+        [
+            "mean",
+            "median",
+            "min",
+            "max",
+            "stddev",
+            "ci95_low",
+            "ci95_high",
+        ]
+        .iter()
+        .map(|name| format!("{}_{}", prefix, name))
+        .collect()
+    }
+
+    /// Returns the values in the order of the columns
+    pub fn values(&self) -> Vec<String> {
+        vec![
+            self.mean,
+            self.median,
+            self.min,
+            self.max,
+            self.stddev,
+            self.ci95_low,
+            self.ci95_high,
+        ]
+        .into_iter()
+        .map(|v| v.to_string())
+        .collect()
+    }
+}
+
+/// Statistics over all repetitions of a benchmark configuration
+#[derive(Clone, Debug)]
+pub struct AggregatedBenchStatistics {
+    pub calc_count: u32,
+    pub global_size: usize,
+    pub local_size: usize,
+    pub repetitions: usize,
+    pub write_duration: DurationStatistics,
+    pub calc_duration: DurationStatistics,
+    pub read_duration: DurationStatistics,
+}
+
+impl AggregatedBenchStatistics {
+    /// Aggregates the results of one configuration
+    pub fn new(results: &[BenchStatistics]) -> Self {
+        let durations = |f: fn(&BenchStatistics) -> Duration| {
+            DurationStatistics::new(&results.iter().map(f).collect::<Vec<Duration>>())
+        };
+
+        Self {
+            calc_count: results[0].calc_count,
+            global_size: results[0].global_size,
+            local_size: results[0].local_size,
+            repetitions: results.len(),
+            write_duration: durations(|s| s.write_duration),
+            calc_duration: durations(|s| s.calc_duration),
+            read_duration: durations(|s| s.read_duration),
+        }
+    }
+}
+
+/// Collects benchmark results until all repetitions of a configuration are available
+pub struct BenchAggregator {
+    repetitions: usize,
+    groups: HashMap<(usize, usize), Vec<BenchStatistics>>,
+}
+
+impl BenchAggregator {
+    pub fn new(repetitions: usize) -> Self {
+        Self {
+            repetitions: repetitions.max(1),
+            groups: HashMap::new(),
+        }
+    }
+
+    /// Adds a result and returns the aggregated statistics
+    /// if it was the last repetition of its configuration
+    pub fn add(&mut self, stats: BenchStatistics) -> Option<AggregatedBenchStatistics> {
+        let key = (stats.global_size, stats.local_size);
+        let group = self.groups.entry(key).or_default();
+        group.push(stats);
+
+        if group.len() >= self.repetitions {
+            let group = self.groups.remove(&key)?;
+            Some(AggregatedBenchStatistics::new(&group))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the statistics of configurations that didn't complete all repetitions
+    pub fn finish(self) -> Vec<AggregatedBenchStatistics> {
+        let mut groups = self.groups.into_iter().collect::<Vec<_>>();
+        groups.sort_by_key(|(key, _)| *key);
+
+        groups
+            .into_iter()
+            .map(|(_, group)| AggregatedBenchStatistics::new(&group))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn statistics(millis: &[u64]) -> DurationStatistics {
+        DurationStatistics::new(
+            &millis
+                .iter()
+                .map(|ms| Duration::from_millis(*ms))
+                .collect::<Vec<Duration>>(),
+        )
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn median_of_odd_and_even_counts() {
+        let odd = statistics(&[3, 1, 2]);
+        assert_close(odd.median, 2f64);
+        assert_close(odd.mean, 2f64);
+        assert_close(odd.min, 1f64);
+        assert_close(odd.max, 3f64);
+
+        let even = statistics(&[4, 1, 3, 2]);
+        assert_close(even.median, 2.5);
+        assert_close(even.mean, 2.5);
+    }
+
+    #[test]
+    fn single_duration() {
+        let single = statistics(&[5]);
+        for value in &[
+            single.mean,
+            single.median,
+            single.min,
+            single.max,
+            single.ci95_low,
+            single.ci95_high,
+        ] {
+            assert_close(*value, 5f64);
+        }
+        assert_close(single.stddev, 0f64);
+
+        let empty = statistics(&[]);
+        assert_close(empty.mean, 0f64);
+        assert_close(empty.ci95_high, 0f64);
+    }
+
+    #[test]
+    fn sample_standard_deviation() {
+        assert_close(
+            statistics(&[2, 4, 4, 4, 5, 5, 7, 9]).stddev,
+            (32f64 / 7f64).sqrt(),
+        );
+        assert_close(statistics(&[1, 2, 3, 4]).stddev, (5f64 / 3f64).sqrt());
+        assert_close(statistics(&[7, 7, 7]).stddev, 0f64);
+    }
+
+    #[test]
+    fn confidence_interval_uses_the_t_distribution() {
+        // the quantiles for 1, 2, 9 and 30 degrees of freedom and the normal quantile above
+        for (n, quantile) in &[
+            (2, 12.706),
+            (3, 4.303),
+            (10, 2.262),
+            (31, 2.042),
+            (32, 1.960),
+            (100, 1.960),
+        ] {
+            let millis = (1..=*n as u64).collect::<Vec<u64>>();
+            let stats = statistics(&millis);
+            let margin = quantile * stats.stddev / (*n as f64).sqrt();
+            assert_close(stats.ci95_low, stats.mean - margin);
+            assert_close(stats.ci95_high, stats.mean + margin);
+        }
+        let stats = statistics(&[1, 2, 3]);
+        assert_close(stats.ci95_high - stats.ci95_low, 2f64 * 4.303 / 3f64.sqrt());
+    }
+}
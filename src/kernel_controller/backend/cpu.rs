@@ -0,0 +1,275 @@
+/*
+ * opencl demos with rust
+ * Copyright (C) 2021 trivernis
+ * See LICENSE for more information
+ */
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use rayon::prelude::*;
+
+use crate::benching::result::ProfiledResult;
+use crate::kernel_controller::backend::{Candidates, ComputeBackend};
+use crate::kernel_controller::bench::BenchStatistics;
+use crate::kernel_controller::factor::factorize;
+use crate::kernel_controller::prime_cache::PrimeCache;
+use crate::kernel_controller::primes::PrimeAlgorithm;
+use crate::kernel_controller::primes::{integer_sqrt, is_prime, is_prime_mr, sieve_segment};
+
+/// Backend that executes the kernel semantics on the cpu with rayon
+pub struct CpuBackend {
+    threads: usize,
+}
+
+impl CpuBackend {
+    pub fn new() -> Self {
+        let threads = rayon::current_num_threads();
+        println!("Using device CPU ({} threads)", threads);
+
+        Self { threads }
+    }
+}
+
+impl ComputeBackend for CpuBackend {
+    fn device_name(&self) -> String {
+        format!("CPU ({} threads)", self.threads)
+    }
+
+    fn driver_version(&self) -> String {
+        env!("CARGO_PKG_VERSION").to_string()
+    }
+
+    fn is_cpu(&self) -> bool {
+        true
+    }
+
+    /// Prints information about the cpu
+    fn print_info(&self) -> ocl::Result<()> {
+        println!("Type: CPU");
+        println!("LogicalCores: {}", num_cpus::get());
+        println!("PhysicalCores: {}", num_cpus::get_physical());
+        println!("WorkerThreads: {}", self.threads);
+        println!();
+
+        Ok(())
+    }
+
+    /// The local size only controls how work is split between threads so there's no limit
+    fn max_work_group_size(&self) -> ocl::Result<usize> {
+        Ok(usize::MAX)
+    }
+
+    fn kernel_work_group_size(&self, _kernel: &str) -> ocl::Result<usize> {
+        Ok(usize::MAX)
+    }
+
+    /// Buffers are regular vectors that are only limited by the system memory
+    fn max_mem_alloc_size(&self) -> ocl::Result<u64> {
+        Ok(u64::MAX)
+    }
+
+    fn local_mem_size(&self) -> ocl::Result<u64> {
+        Ok(u64::MAX)
+    }
+
+    /// Checks the numbers like the 'check_prime' kernel
+    fn check_prime(
+        &self,
+        candidates: Candidates,
+        _local_size: usize,
+    ) -> ocl::Result<ProfiledResult<Vec<u64>>> {
+        let start = Instant::now();
+        let primes = candidates
+            .into_numbers()
+            .into_par_iter()
+            .filter(|n| is_prime(*n))
+            .collect::<Vec<u64>>();
+        log::trace!("Calculated {} primes", primes.len());
+
+        Ok(ProfiledResult::new(start.elapsed(), primes))
+    }
+
+    /// Checks the numbers like the 'check_prime_cached' kernel
+    fn check_prime_cached(
+        &self,
+        candidates: Candidates,
+        _local_size: usize,
+        prime_cache: &PrimeCache,
+    ) -> ocl::Result<ProfiledResult<Vec<u64>>> {
+        let start = Instant::now();
+        let primes = candidates
+            .into_numbers()
+            .into_par_iter()
+            .filter(|n| is_prime_cached(*n, prime_cache.primes()))
+            .collect::<Vec<u64>>();
+        log::trace!("Calculated {} primes", primes.len());
+
+        Ok(ProfiledResult::new(start.elapsed(), primes))
+    }
+
+    /// Checks the numbers like the 'check_prime_mr' kernel
+    fn check_prime_mr(
+        &self,
+        candidates: Candidates,
+        _local_size: usize,
+    ) -> ocl::Result<ProfiledResult<Vec<u64>>> {
+        let start = Instant::now();
+        let primes = candidates
+            .into_numbers()
+            .into_par_iter()
+            .filter(|n| is_prime_mr(*n))
+            .collect::<Vec<u64>>();
+        log::trace!("Calculated {} primes", primes.len());
+
+        Ok(ProfiledResult::new(start.elapsed(), primes))
+    }
+
+    /// Sieves the segments in parallel like the 'sieve_segment' kernel
+    fn sieve_primes(
+        &self,
+        offset: u64,
+        count: usize,
+        segment_size: usize,
+        _local_size: usize,
+        base_primes: &[u32],
+    ) -> ocl::Result<ProfiledResult<Vec<u64>>> {
+        let start = Instant::now();
+        let segments = (count + segment_size - 1) / segment_size;
+        let primes = (0..segments)
+            .into_par_iter()
+            .map(|segment| {
+                let index = segment * segment_size;
+                let len = (count - index).min(segment_size);
+                sieve_segment(offset + 2 * index as u64, len, base_primes)
+            })
+            .collect::<Vec<Vec<u64>>>()
+            .concat();
+        log::trace!("Calculated {} primes", primes.len());
+
+        Ok(ProfiledResult::new(start.elapsed(), primes))
+    }
+
+    /// Counts the primes like the count kernels
+    fn count_primes(
+        &self,
+        candidates: Candidates,
+        _local_size: usize,
+        algorithm: PrimeAlgorithm,
+        prime_cache: Option<&PrimeCache>,
+    ) -> ocl::Result<ProfiledResult<u64>> {
+        let start = Instant::now();
+        let numbers = candidates.into_numbers().into_par_iter();
+        let count = match algorithm {
+            PrimeAlgorithm::TrialDivision { use_cache: true } => {
+                let primes = prime_cache.ok_or_else(|| {
+                    ocl::Error::from("The cached trial division needs a prime cache".to_string())
+                })?;
+                numbers
+                    .filter(|n| is_prime_cached(*n, primes.primes()))
+                    .count()
+            }
+            PrimeAlgorithm::TrialDivision { use_cache: false } => {
+                numbers.filter(|n| is_prime(*n)).count()
+            }
+            PrimeAlgorithm::MillerRabin => numbers.filter(|n| is_prime_mr(*n)).count(),
+            PrimeAlgorithm::Sieve { .. } => {
+                return Err(ocl::Error::from(
+                    "The sieve can't count the primes of arbitrary numbers".to_string(),
+                ))
+            }
+        } as u64;
+        log::trace!("Counted {} primes", count);
+
+        Ok(ProfiledResult::new(start.elapsed(), count))
+    }
+
+    /// Sieves the segments in parallel and counts the primes
+    fn count_sieved_primes(
+        &self,
+        offset: u64,
+        count: usize,
+        segment_size: usize,
+        _local_size: usize,
+        base_primes: &[u32],
+    ) -> ocl::Result<ProfiledResult<u64>> {
+        let start = Instant::now();
+        let segments = (count + segment_size - 1) / segment_size;
+        let primes = (0..segments)
+            .into_par_iter()
+            .map(|segment| {
+                let index = segment * segment_size;
+                let len = (count - index).min(segment_size);
+                sieve_segment(offset + 2 * index as u64, len, base_primes).len() as u64
+            })
+            .sum::<u64>();
+        log::trace!("Counted {} primes", primes);
+
+        Ok(ProfiledResult::new(start.elapsed(), primes))
+    }
+
+    /// Factors the numbers in parallel like the 'factor' kernel
+    /// but every composite factor is split
+    fn factor(
+        &self,
+        numbers: Vec<u64>,
+        _local_size: usize,
+        small_primes: &[u32],
+    ) -> ocl::Result<ProfiledResult<Vec<Vec<u64>>>> {
+        let start = Instant::now();
+        let factors = numbers
+            .into_par_iter()
+            .map(|n| factorize(n, small_primes))
+            .collect::<Vec<Vec<u64>>>();
+        log::trace!("Factored {} numbers", factors.len());
+
+        Ok(ProfiledResult::new(start.elapsed(), factors))
+    }
+
+    /// Runs the 'bench_int' calculation for every task
+    fn bench_int(
+        &self,
+        global_size: usize,
+        local_size: usize,
+        calc_count: u32,
+    ) -> ocl::Result<BenchStatistics> {
+        let start = Instant::now();
+        let output = (0..global_size)
+            .into_par_iter()
+            .with_min_len(local_size)
+            .map(|_| {
+                let mut num = 0i32;
+                for i in 0..calc_count as i32 {
+                    num = black_box(num.wrapping_add(i));
+                }
+                num
+            })
+            .collect::<Vec<i32>>();
+        let calc_duration = start.elapsed();
+        log::trace!("Calculated {} values", output.len());
+
+        Ok(BenchStatistics {
+            global_size,
+            calc_count,
+            local_size,
+            read_duration: Duration::from_nanos(0),
+            calc_duration,
+            write_duration: Duration::from_nanos(0),
+        })
+    }
+}
+
+/// Checks if a number is a prime by only dividing by the given lower primes
+fn is_prime_cached(num: u64, lower_primes: &[u64]) -> bool {
+    if num == 2 || num == 3 {
+        return true;
+    } else if num <= 1 {
+        return false;
+    }
+    let limit = integer_sqrt(num) + 1;
+
+    lower_primes
+        .iter()
+        .take_while(|p| **p < limit)
+        .all(|p| num % p != 0)
+}
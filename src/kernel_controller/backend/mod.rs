@@ -0,0 +1,186 @@
+/*
+ * opencl demos with rust
+ * Copyright (C) 2021 trivernis
+ * See LICENSE for more information
+ */
+
+use std::sync::Arc;
+
+use crate::benching::result::ProfiledResult;
+use crate::kernel_controller::backend::cpu::CpuBackend;
+use crate::kernel_controller::backend::opencl::OpenCLBackend;
+use crate::kernel_controller::bench::BenchStatistics;
+use crate::kernel_controller::prime_cache::PrimeCache;
+use crate::kernel_controller::primes::{odd_numbers, PrimeAlgorithm};
+use crate::utils::args::Backend;
+
+pub mod cpu;
+pub mod opencl;
+
+/// The numbers that are checked for primality
+pub enum Candidates {
+    /// Numbers that are uploaded to the device
+    Numbers(Vec<u64>),
+
+    /// Odd numbers beginning with the odd offset that are generated on the device
+    OddNumbers { offset: u64, count: usize },
+}
+
+impl Candidates {
+    /// Creates the candidates for up to `count` odd numbers beginning with the odd offset.
+    /// The numbers are only generated on the host if they need to be uploaded.
+    pub fn odd_numbers(offset: u64, count: usize, upload: bool) -> Self {
+        let numbers = odd_numbers(offset, count);
+
+        if upload {
+            Candidates::Numbers(numbers.collect())
+        } else {
+            Candidates::OddNumbers {
+                offset,
+                count: numbers.len(),
+            }
+        }
+    }
+
+    /// Returns the numbers on the host
+    pub fn into_numbers(self) -> Vec<u64> {
+        match self {
+            Candidates::Numbers(numbers) => numbers,
+            Candidates::OddNumbers { offset, count } => odd_numbers(offset, count).collect(),
+        }
+    }
+}
+
+/// A device that executes the calculation primitives
+/// used by the kernel controller
+pub trait ComputeBackend: Send + Sync {
+    /// Returns the name of the device the calculations are executed on
+    fn device_name(&self) -> String;
+
+    /// Returns the version of the driver the device is used with
+    fn driver_version(&self) -> String;
+
+    /// Returns if the calculations are executed on the host cpu
+    fn is_cpu(&self) -> bool;
+
+    /// Prints information about the device capabilities
+    fn print_info(&self) -> ocl::Result<()>;
+
+    /// Returns the maximum number of work items in a work group
+    fn max_work_group_size(&self) -> ocl::Result<usize>;
+
+    /// Returns the maximum number of work items in a work group of the given kernel
+    fn kernel_work_group_size(&self, kernel: &str) -> ocl::Result<usize>;
+
+    /// Returns the maximum size of a single buffer in bytes
+    fn max_mem_alloc_size(&self) -> ocl::Result<u64>;
+
+    /// Returns the size of the local memory of a work group in bytes
+    fn local_mem_size(&self) -> ocl::Result<u64>;
+
+    /// Checks every number for primality and returns the prime numbers.
+    /// Uploaded numbers need to be padded to be divisible by the local size.
+    fn check_prime(
+        &self,
+        candidates: Candidates,
+        local_size: usize,
+    ) -> ocl::Result<ProfiledResult<Vec<u64>>>;
+
+    /// Checks every number for primality by using the primes of the cache
+    /// for divisibility checks and returns the prime numbers.
+    /// The cache needs to contain all primes up to the square root of the largest number.
+    fn check_prime_cached(
+        &self,
+        candidates: Candidates,
+        local_size: usize,
+        prime_cache: &PrimeCache,
+    ) -> ocl::Result<ProfiledResult<Vec<u64>>>;
+
+    /// Checks every number with the deterministic Miller-Rabin test
+    /// and returns the prime numbers
+    fn check_prime_mr(
+        &self,
+        candidates: Candidates,
+        local_size: usize,
+    ) -> ocl::Result<ProfiledResult<Vec<u64>>>;
+
+    /// Sieves `count` odd numbers beginning with the offset in segments of `segment_size`
+    /// numbers per work group and returns the prime numbers.
+    /// The base primes need to contain all odd primes up to the square root of the last number.
+    fn sieve_primes(
+        &self,
+        offset: u64,
+        count: usize,
+        segment_size: usize,
+        local_size: usize,
+        base_primes: &[u32],
+    ) -> ocl::Result<ProfiledResult<Vec<u64>>>;
+
+    /// Counts the primes among the numbers with the trial division or the Miller-Rabin test.
+    /// The cached trial division needs a prime cache for the divisibility check.
+    fn count_primes(
+        &self,
+        candidates: Candidates,
+        local_size: usize,
+        algorithm: PrimeAlgorithm,
+        prime_cache: Option<&PrimeCache>,
+    ) -> ocl::Result<ProfiledResult<u64>>;
+
+    /// Sieves the odd numbers like `sieve_primes` and counts the primes
+    fn count_sieved_primes(
+        &self,
+        offset: u64,
+        count: usize,
+        segment_size: usize,
+        local_size: usize,
+        base_primes: &[u32],
+    ) -> ocl::Result<ProfiledResult<u64>>;
+
+    /// Factors every number by trial division with the small primes followed by
+    /// Pollard's rho and returns the prime factors of every number.
+    /// Composite factors that couldn't be split are returned as they are.
+    fn factor(
+        &self,
+        numbers: Vec<u64>,
+        local_size: usize,
+        small_primes: &[u32],
+    ) -> ocl::Result<ProfiledResult<Vec<Vec<u64>>>>;
+
+    /// Runs the integer benchmark with the given number of tasks
+    fn bench_int(
+        &self,
+        global_size: usize,
+        local_size: usize,
+        calc_count: u32,
+    ) -> ocl::Result<BenchStatistics>;
+}
+
+/// Creates the compute backends for the given backend type and OpenCL device selection.
+/// The automatic selection falls back to the cpu if no OpenCL device is available.
+pub fn create_backends(
+    backend: Backend,
+    platform: Option<&str>,
+    devices: &[String],
+) -> ocl::Result<Vec<Arc<dyn ComputeBackend>>> {
+    match backend {
+        Backend::OpenCL => Ok(opencl_backends(platform, devices)?),
+        Backend::Cpu => Ok(vec![Arc::new(CpuBackend::new())]),
+        Backend::Auto => match opencl_backends(platform, devices) {
+            Ok(backends) => Ok(backends),
+            Err(e) => {
+                log::warn!("OpenCL is not available ({}). Falling back to the cpu.", e);
+                Ok(vec![Arc::new(CpuBackend::new())])
+            }
+        },
+    }
+}
+
+fn opencl_backends(
+    platform: Option<&str>,
+    devices: &[String],
+) -> ocl::Result<Vec<Arc<dyn ComputeBackend>>> {
+    Ok(OpenCLBackend::open_devices(platform, devices)?
+        .into_iter()
+        .map(|backend| Arc::new(backend) as Arc<dyn ComputeBackend>)
+        .collect())
+}
@@ -0,0 +1,1023 @@
+/*
+ * opencl demos with rust
+ * Copyright (C) 2021 trivernis
+ * See LICENSE for more information
+ */
+
+use std::time::{Duration, Instant};
+
+use ocl::builders::KernelBuilder;
+use ocl::core::{DeviceInfo, KernelWorkGroupInfo};
+use ocl::enums::{DeviceInfoResult, KernelWorkGroupInfoResult};
+use ocl::{Buffer, CommandQueueProperties, Device, DeviceType, Kernel, Platform, ProQue};
+use ocl_stream::traits::ToOclBuffer;
+use parking_lot::Mutex;
+use std_semaphore::Semaphore;
+
+use crate::benching::result::ProfiledResult;
+use crate::benching::{enqueue_profiled, enqueue_profiled_all};
+use crate::kernel_controller::backend::{Candidates, ComputeBackend};
+use crate::kernel_controller::bench::BenchStatistics;
+use crate::kernel_controller::factor::{FACTOR_KERNEL, MAX_FACTORS};
+use crate::kernel_controller::prime_cache::PrimeCache;
+use crate::kernel_controller::primes::PrimeAlgorithm;
+
+/// The kernels that write the numbers flagged by another kernel densely to a buffer
+pub const COMPACTION_KERNELS: [&str; 4] = [
+    "count_flags",
+    "scan_group_counts",
+    "compact_numbers",
+    "compact_odd_numbers",
+];
+
+/// Backend that executes the kernels on an OpenCL device
+pub struct OpenCLBackend {
+    pro_que: ProQue,
+    sem: Semaphore,
+    /// The identifier of the uploaded prime cache and the buffer it was uploaded to
+    prime_cache: Mutex<Option<(u64, Buffer<u64>)>>,
+}
+
+/// The numbers a kernel is executed for
+enum KernelInput {
+    /// Numbers that were uploaded to an input buffer
+    Numbers(Buffer<u64>),
+
+    /// Odd numbers beginning with the offset that are generated by the kernels
+    OddNumbers { offset: u64, count: usize },
+}
+
+impl KernelInput {
+    /// Returns the name of the kernel that executes the algorithm for the input
+    fn kernel_name(&self, algorithm: &PrimeAlgorithm) -> &'static str {
+        match self {
+            KernelInput::Numbers(_) => algorithm.kernel_name(),
+            KernelInput::OddNumbers { .. } => algorithm.odd_kernel_name(),
+        }
+    }
+
+    /// Returns the name of the kernel that counts the primes of the input with the algorithm
+    fn count_kernel_name(&self, algorithm: &PrimeAlgorithm) -> &'static str {
+        match self {
+            KernelInput::Numbers(_) => algorithm.count_kernel_name(),
+            KernelInput::OddNumbers { .. } => algorithm.odd_count_kernel_name(),
+        }
+    }
+
+    /// Adds the input buffer or the offset and count of the odd numbers to the kernel
+    fn add_args<'b>(&'b self, builder: &mut KernelBuilder<'b>) {
+        match self {
+            KernelInput::Numbers(input_buffer) => {
+                builder.arg(input_buffer);
+            }
+            KernelInput::OddNumbers { offset, count } => {
+                builder.arg(*offset).arg(*count as u64);
+            }
+        }
+    }
+}
+
+/// Kernels that compact the flagged numbers on the device so
+/// only the flagged numbers and their count need to be read
+struct Compaction {
+    kernels: [Kernel; 3],
+    group_counts: Buffer<u32>,
+    output: Buffer<u64>,
+}
+
+impl Compaction {
+    /// Runs the kernel that flags the numbers followed by the compaction kernels
+    fn enqueue_after(
+        &self,
+        kernel: &Kernel,
+        pro_que: &ProQue,
+        sem: &Semaphore,
+    ) -> ocl::Result<Duration> {
+        let [count, scan, compact] = &self.kernels;
+
+        enqueue_profiled_all(pro_que, &[kernel, count, scan, compact], sem)
+    }
+
+    /// Reads the number of flagged numbers that is stored after the group offsets
+    /// and only reads as many numbers from the output
+    fn read_numbers(&self, sem: &Semaphore) -> ocl::Result<Vec<u64>> {
+        let mut count = [0u32];
+        {
+            let _guard = sem.access();
+            self.group_counts
+                .read(&mut count[..])
+                .offset(self.group_counts.len() - 1)
+                .enq()?;
+        }
+
+        let mut numbers = vec![0u64; count[0] as usize];
+        if !numbers.is_empty() {
+            let _guard = sem.access();
+            self.output.read(&mut numbers).enq()?;
+        }
+
+        Ok(numbers)
+    }
+}
+
+/// Information that is printed for every device
+const DEVICE_INFO_KEYS: [DeviceInfo; 17] = [
+    DeviceInfo::Type,
+    DeviceInfo::Vendor,
+    DeviceInfo::DriverVersion,
+    DeviceInfo::ExecutionCapabilities,
+    DeviceInfo::MaxComputeUnits,
+    DeviceInfo::MaxWorkItemSizes,
+    DeviceInfo::MaxWorkItemDimensions,
+    DeviceInfo::MaxWorkGroupSize,
+    DeviceInfo::MaxClockFrequency,
+    DeviceInfo::GlobalMemSize,
+    DeviceInfo::LocalMemSize,
+    DeviceInfo::MaxMemAllocSize,
+    DeviceInfo::LocalMemType,
+    DeviceInfo::GlobalMemCacheType,
+    DeviceInfo::GlobalMemCacheSize,
+    DeviceInfo::OpenclCVersion,
+    DeviceInfo::Platform,
+];
+
+impl OpenCLBackend {
+    /// Creates a backend for every selected device.
+    /// Platforms and devices are selected by their index or a part of their name
+    /// and 'all' selects every device of the platform.
+    /// Without a selection the first platform and its first device are used.
+    pub fn open_devices(platform: Option<&str>, devices: &[String]) -> ocl::Result<Vec<Self>> {
+        select_devices(platform, devices)?
+            .into_iter()
+            .map(|(platform, device)| Self::new(platform, device))
+            .collect()
+    }
+
+    /// Creates the backend for a device
+    pub fn new(platform: Platform, device: Device) -> ocl::Result<Self> {
+        let pro_que = ProQue::builder()
+            .platform(platform)
+            .device(device)
+            .src(include_str!("../kernel.cl"))
+            .dims(1) // won't be used as buffer sizes are declared explicitly
+            .queue_properties(CommandQueueProperties::PROFILING_ENABLE)
+            .build()?;
+        println!("Using device {}", pro_que.device().name()?);
+
+        Ok(Self {
+            pro_que,
+            sem: Semaphore::new(1),
+            prime_cache: Mutex::new(None),
+        })
+    }
+
+    /// Builds the kernels that compact the numbers flagged in the flag buffer.
+    /// The length of the flag buffer needs to be divisible by the local size.
+    fn build_compaction(
+        &self,
+        input: &KernelInput,
+        flag_buffer: &Buffer<u8>,
+        local_size: usize,
+    ) -> ocl::Result<Compaction> {
+        let pro_que = &self.pro_que;
+        let sem = &self.sem;
+        let group_count = flag_buffer.len() / local_size;
+
+        let (group_counts, output) = {
+            let _guard = sem.access();
+            log::trace!("Creating compaction buffers");
+            // the total count is stored after the offsets of the work groups
+            let group_counts = pro_que
+                .buffer_builder()
+                .len(group_count + 1)
+                .fill_val(0u32)
+                .build()?;
+            let output = pro_que
+                .buffer_builder::<u64>()
+                .len(flag_buffer.len())
+                .build()?;
+
+            (group_counts, output)
+        };
+
+        log::trace!("Building compaction kernels");
+        let _guard = sem.access();
+        let count = pro_que
+            .kernel_builder("count_flags")
+            .local_work_size(local_size)
+            .arg(flag_buffer)
+            .arg(&group_counts)
+            .arg_local::<u32>(local_size)
+            .global_work_size(flag_buffer.len())
+            .build()?;
+        let scan = pro_que
+            .kernel_builder("scan_group_counts")
+            .local_work_size(local_size)
+            .arg(group_count as u32)
+            .arg(&group_counts)
+            .arg_local::<u32>(local_size)
+            .global_work_size(local_size)
+            .build()?;
+        let mut builder = match input {
+            KernelInput::Numbers(input_buffer) => {
+                let mut builder = pro_que.kernel_builder("compact_numbers");
+                builder.arg(input_buffer);
+                builder
+            }
+            KernelInput::OddNumbers { offset, .. } => {
+                let mut builder = pro_que.kernel_builder("compact_odd_numbers");
+                builder.arg(*offset);
+                builder
+            }
+        };
+        let compact = builder
+            .local_work_size(local_size)
+            .arg(flag_buffer)
+            .arg(&group_counts)
+            .arg(&output)
+            .arg_local::<u32>(local_size)
+            .global_work_size(flag_buffer.len())
+            .build()?;
+
+        Ok(Compaction {
+            kernels: [count, scan, compact],
+            group_counts,
+            output,
+        })
+    }
+
+    /// Uploads the numbers or passes the odd numbers to the kernels.
+    /// Returns the input and the global size that is divisible by the local size.
+    fn kernel_input(
+        &self,
+        candidates: Candidates,
+        local_size: usize,
+    ) -> ocl::Result<(KernelInput, usize)> {
+        match candidates {
+            Candidates::Numbers(mut numbers) => {
+                // the padding with zeros doesn't contain primes
+                let count = numbers.len();
+                numbers.resize(count + (local_size - count % local_size) % local_size, 0);
+
+                let input_buffer = {
+                    let _guard = self.sem.access();
+                    numbers.to_ocl_buffer(&self.pro_que)?
+                };
+
+                Ok((KernelInput::Numbers(input_buffer), numbers.len()))
+            }
+            Candidates::OddNumbers { offset, count } => Ok((
+                KernelInput::OddNumbers { offset, count },
+                count + (local_size - count % local_size) % local_size,
+            )),
+        }
+    }
+
+    /// Returns the number of cached primes and the buffer with the primes if the algorithm
+    /// uses the prime cache. The primes are only uploaded if the cache changed.
+    fn prime_cache_buffer(
+        &self,
+        algorithm: &PrimeAlgorithm,
+        prime_cache: Option<&PrimeCache>,
+    ) -> ocl::Result<Option<(u32, Buffer<u64>)>> {
+        let prime_cache = match (algorithm, prime_cache) {
+            (PrimeAlgorithm::TrialDivision { use_cache: true }, Some(prime_cache)) => prime_cache,
+            (PrimeAlgorithm::TrialDivision { use_cache: true }, None) => {
+                return Err(ocl::Error::from(
+                    "The cached trial division needs a prime cache".to_string(),
+                ))
+            }
+            _ => return Ok(None),
+        };
+        let count = prime_cache.primes().len() as u32;
+        let mut uploaded = self.prime_cache.lock();
+
+        match &*uploaded {
+            Some((id, prime_buffer)) if *id == prime_cache.id() => {
+                Ok(Some((count, prime_buffer.clone())))
+            }
+            _ => {
+                // the previous cache is released before the new one is uploaded
+                *uploaded = None;
+                log::debug!("Uploading {} cached primes", count);
+                let prime_buffer = {
+                    let _guard = self.sem.access();
+                    // empty buffers can't be created so there's always at least one value
+                    if prime_cache.primes().is_empty() {
+                        vec![0u64].to_ocl_buffer(&self.pro_que)?
+                    } else {
+                        self.pro_que
+                            .buffer_builder()
+                            .len(prime_cache.primes().len())
+                            .copy_host_slice(prime_cache.primes())
+                            .build()?
+                    }
+                };
+                *uploaded = Some((prime_cache.id(), prime_buffer.clone()));
+
+                Ok(Some((count, prime_buffer)))
+            }
+        }
+    }
+
+    /// Flags the primes among the candidates with the kernel of the algorithm
+    /// and compacts them on the device
+    fn filter_primes(
+        &self,
+        candidates: Candidates,
+        local_size: usize,
+        algorithm: PrimeAlgorithm,
+        prime_cache: Option<&PrimeCache>,
+    ) -> ocl::Result<ProfiledResult<Vec<u64>>> {
+        let pro_que = &self.pro_que;
+        let sem = &self.sem;
+        let (input, global_size) = self.kernel_input(candidates, local_size)?;
+        let prime_buffer = self.prime_cache_buffer(&algorithm, prime_cache)?;
+
+        let flag_buffer = {
+            let _guard = sem.access();
+            log::trace!("Creating 0u8 flag buffer");
+            pro_que
+                .buffer_builder()
+                .len(global_size)
+                .fill_val(0u8)
+                .build()?
+        };
+
+        let kernel_name = input.kernel_name(&algorithm);
+        log::trace!("Building '{}' kernel", kernel_name);
+        let kernel = {
+            let _guard = sem.access();
+            let mut builder = pro_que.kernel_builder(kernel_name);
+            builder
+                .local_work_size(local_size)
+                .global_work_size(global_size);
+            if let Some((count, prime_buffer)) = &prime_buffer {
+                builder.arg(*count).arg(prime_buffer);
+            }
+            input.add_args(&mut builder);
+            builder.arg(&flag_buffer).build()?
+        };
+        let compaction = self.build_compaction(&input, &flag_buffer, local_size)?;
+
+        let duration = compaction.enqueue_after(&kernel, pro_que, sem)?;
+
+        log::trace!("Reading compacted primes");
+        let primes = compaction.read_numbers(sem)?;
+        log::trace!("Calculated {} primes", primes.len());
+
+        Ok(ProfiledResult::new(duration, primes))
+    }
+
+    #[allow(dead_code)]
+    fn available_memory(&self) -> ocl::Result<u64> {
+        match self.pro_que.device().info(DeviceInfo::GlobalMemSize)? {
+            DeviceInfoResult::GlobalMemSize(size) => Ok(size),
+            _ => Ok(0),
+        }
+    }
+}
+
+impl ComputeBackend for OpenCLBackend {
+    fn device_name(&self) -> String {
+        self.pro_que
+            .device()
+            .name()
+            .unwrap_or_else(|_| "Unknown OpenCL device".to_string())
+    }
+
+    fn driver_version(&self) -> String {
+        self.pro_que
+            .device()
+            .info(DeviceInfo::DriverVersion)
+            .map(|v| v.to_string())
+            .unwrap_or_else(|_| "Unknown".to_string())
+    }
+
+    fn is_cpu(&self) -> bool {
+        match self.pro_que.device().info(DeviceInfo::Type) {
+            Ok(DeviceInfoResult::Type(device_type)) => device_type.contains(DeviceType::CPU),
+            _ => false,
+        }
+    }
+
+    /// Prints information about the gpu capabilities
+    fn print_info(&self) -> ocl::Result<()> {
+        print_device_info(self.pro_que.device())
+    }
+
+    fn max_work_group_size(&self) -> ocl::Result<usize> {
+        self.pro_que.device().max_wg_size()
+    }
+
+    /// Creates the kernel without arguments to query its work group size
+    fn kernel_work_group_size(&self, kernel: &str) -> ocl::Result<usize> {
+        let kernel = ocl::core::create_kernel(self.pro_que.program(), kernel)?;
+
+        match ocl::core::get_kernel_work_group_info(
+            &kernel,
+            self.pro_que.device(),
+            KernelWorkGroupInfo::WorkGroupSize,
+        )? {
+            KernelWorkGroupInfoResult::WorkGroupSize(size) => Ok(size),
+            result => Err(ocl::Error::from(format!(
+                "Unexpected work group info {:?}",
+                result
+            ))),
+        }
+    }
+
+    fn max_mem_alloc_size(&self) -> ocl::Result<u64> {
+        match self.pro_que.device().info(DeviceInfo::MaxMemAllocSize)? {
+            DeviceInfoResult::MaxMemAllocSize(size) => Ok(size),
+            result => Err(ocl::Error::from(format!(
+                "Unexpected device info {:?}",
+                result
+            ))),
+        }
+    }
+
+    fn local_mem_size(&self) -> ocl::Result<u64> {
+        match self.pro_que.device().info(DeviceInfo::LocalMemSize)? {
+            DeviceInfoResult::LocalMemSize(size) => Ok(size),
+            result => Err(ocl::Error::from(format!(
+                "Unexpected device info {:?}",
+                result
+            ))),
+        }
+    }
+
+    /// Flags the primes with the 'check_prime' kernel and compacts them
+    fn check_prime(
+        &self,
+        candidates: Candidates,
+        local_size: usize,
+    ) -> ocl::Result<ProfiledResult<Vec<u64>>> {
+        self.filter_primes(
+            candidates,
+            local_size,
+            PrimeAlgorithm::TrialDivision { use_cache: false },
+            None,
+        )
+    }
+
+    /// Filters primes by using the primes from previous
+    /// calculations for divisibility checks
+    fn check_prime_cached(
+        &self,
+        candidates: Candidates,
+        local_size: usize,
+        prime_cache: &PrimeCache,
+    ) -> ocl::Result<ProfiledResult<Vec<u64>>> {
+        self.filter_primes(
+            candidates,
+            local_size,
+            PrimeAlgorithm::TrialDivision { use_cache: true },
+            Some(prime_cache),
+        )
+    }
+
+    /// Checks the numbers with the 'check_prime_mr' kernel
+    fn check_prime_mr(
+        &self,
+        candidates: Candidates,
+        local_size: usize,
+    ) -> ocl::Result<ProfiledResult<Vec<u64>>> {
+        self.filter_primes(candidates, local_size, PrimeAlgorithm::MillerRabin, None)
+    }
+
+    /// Sieves segments of odd numbers in local memory
+    fn sieve_primes(
+        &self,
+        offset: u64,
+        count: usize,
+        segment_size: usize,
+        local_size: usize,
+        base_primes: &[u32],
+    ) -> ocl::Result<ProfiledResult<Vec<u64>>> {
+        let pro_que = &self.pro_que;
+        let sem = &self.sem;
+        let segments = (count + segment_size - 1) / segment_size;
+
+        let prime_buffer = {
+            let _guard = sem.access();
+            // empty buffers can't be created so there's always at least one value
+            if base_primes.is_empty() {
+                vec![0u32]
+            } else {
+                base_primes.to_vec()
+            }
+            .to_ocl_buffer(pro_que)?
+        };
+
+        let flag_buffer = {
+            let _guard = sem.access();
+            log::trace!("Creating flag buffer");
+            // the flags are padded with non-primes to be divisible by the local size for the compaction
+            pro_que
+                .buffer_builder()
+                .len(count + (local_size - count % local_size) % local_size)
+                .fill_val(0u8)
+                .build()?
+        };
+
+        log::trace!("Building 'sieve_segment' kernel");
+        let kernel = {
+            let _guard = sem.access();
+            pro_que
+                .kernel_builder("sieve_segment")
+                .local_work_size(local_size)
+                .arg(offset)
+                .arg(count as u64)
+                .arg(segment_size as u32)
+                .arg(base_primes.len() as u32)
+                .arg(&prime_buffer)
+                .arg(&flag_buffer)
+                .arg_local::<u8>(segment_size)
+                .global_work_size(segments * local_size)
+                .build()?
+        };
+        let input = KernelInput::OddNumbers { offset, count };
+        let compaction = self.build_compaction(&input, &flag_buffer, local_size)?;
+
+        let duration = compaction.enqueue_after(&kernel, pro_que, sem)?;
+
+        log::trace!("Reading compacted primes");
+        let primes = compaction.read_numbers(sem)?;
+        log::trace!("Calculated {} primes", primes.len());
+
+        Ok(ProfiledResult::new(duration, primes))
+    }
+
+    /// Counts the primes of every work group in local memory
+    /// and only reads the counts
+    fn count_primes(
+        &self,
+        candidates: Candidates,
+        local_size: usize,
+        algorithm: PrimeAlgorithm,
+        prime_cache: Option<&PrimeCache>,
+    ) -> ocl::Result<ProfiledResult<u64>> {
+        let pro_que = &self.pro_que;
+        let sem = &self.sem;
+        if let PrimeAlgorithm::Sieve { .. } = algorithm {
+            return Err(ocl::Error::from(
+                "The sieve can't count the primes of arbitrary numbers".to_string(),
+            ));
+        }
+        let (input, global_size) = self.kernel_input(candidates, local_size)?;
+        let prime_buffer = self.prime_cache_buffer(&algorithm, prime_cache)?;
+
+        let output_buffer = {
+            let _guard = sem.access();
+            log::trace!("Creating output buffer");
+            pro_que
+                .buffer_builder()
+                .len(global_size / local_size)
+                .fill_val(0u32)
+                .build()?
+        };
+
+        let kernel_name = input.count_kernel_name(&algorithm);
+        log::trace!("Building '{}' kernel", kernel_name);
+        let kernel = {
+            let _guard = sem.access();
+            let mut builder = pro_que.kernel_builder(kernel_name);
+            builder
+                .local_work_size(local_size)
+                .global_work_size(global_size);
+            if let Some((count, prime_buffer)) = &prime_buffer {
+                builder.arg(*count).arg(prime_buffer);
+            }
+            input.add_args(&mut builder);
+            builder
+                .arg(&output_buffer)
+                .arg_local::<u32>(local_size)
+                .build()?
+        };
+
+        let duration = enqueue_profiled(pro_que, &kernel, sem)?;
+
+        log::trace!("Reading output");
+        let mut output = vec![0u32; output_buffer.len()];
+        {
+            let _guard = sem.access();
+            output_buffer.read(&mut output).enq()?;
+        }
+
+        let count = output.into_iter().map(u64::from).sum::<u64>();
+        log::trace!("Counted {} primes", count);
+
+        Ok(ProfiledResult::new(duration, count))
+    }
+
+    /// Sieves segments of odd numbers in local memory and counts
+    /// the primes of every segment
+    fn count_sieved_primes(
+        &self,
+        offset: u64,
+        count: usize,
+        segment_size: usize,
+        local_size: usize,
+        base_primes: &[u32],
+    ) -> ocl::Result<ProfiledResult<u64>> {
+        let pro_que = &self.pro_que;
+        let sem = &self.sem;
+        let segments = (count + segment_size - 1) / segment_size;
+
+        let prime_buffer = {
+            let _guard = sem.access();
+            // empty buffers can't be created so there's always at least one value
+            if base_primes.is_empty() {
+                vec![0u32]
+            } else {
+                base_primes.to_vec()
+            }
+            .to_ocl_buffer(pro_que)?
+        };
+
+        let output_buffer = {
+            let _guard = sem.access();
+            log::trace!("Creating output buffer");
+            pro_que
+                .buffer_builder()
+                .len(segments)
+                .fill_val(0u32)
+                .build()?
+        };
+
+        log::trace!("Building 'count_sieve_segment' kernel");
+        let kernel = {
+            let _guard = sem.access();
+            pro_que
+                .kernel_builder("count_sieve_segment")
+                .local_work_size(local_size)
+                .arg(offset)
+                .arg(count as u64)
+                .arg(segment_size as u32)
+                .arg(base_primes.len() as u32)
+                .arg(&prime_buffer)
+                .arg(&output_buffer)
+                .arg_local::<u8>(segment_size)
+                .arg_local::<u32>(local_size)
+                .global_work_size(segments * local_size)
+                .build()?
+        };
+
+        let duration = enqueue_profiled(pro_que, &kernel, sem)?;
+
+        log::trace!("Reading output");
+        let mut output = vec![0u32; output_buffer.len()];
+        {
+            let _guard = sem.access();
+            output_buffer.read(&mut output).enq()?;
+        }
+
+        let primes = output.into_iter().map(u64::from).sum::<u64>();
+        log::trace!("Counted {} primes", primes);
+
+        Ok(ProfiledResult::new(duration, primes))
+    }
+
+    /// Factors the numbers with one work item per number
+    fn factor(
+        &self,
+        numbers: Vec<u64>,
+        local_size: usize,
+        small_primes: &[u32],
+    ) -> ocl::Result<ProfiledResult<Vec<Vec<u64>>>> {
+        let pro_que = &self.pro_que;
+        let sem = &self.sem;
+
+        let (prime_buffer, input_buffer) = {
+            let _guard = sem.access();
+            (
+                small_primes.to_vec().to_ocl_buffer(pro_que)?,
+                numbers.to_ocl_buffer(pro_que)?,
+            )
+        };
+
+        let output_buffer = {
+            let _guard = sem.access();
+            log::trace!("Creating output buffer");
+            pro_que
+                .buffer_builder()
+                .len(numbers.len() * MAX_FACTORS)
+                .fill_val(0u64)
+                .build()?
+        };
+
+        log::trace!("Building 'factor' kernel");
+        let kernel = {
+            let _guard = sem.access();
+            pro_que
+                .kernel_builder(FACTOR_KERNEL)
+                .local_work_size(local_size)
+                .arg(prime_buffer.len() as u32)
+                .arg(&prime_buffer)
+                .arg(&input_buffer)
+                .arg(&output_buffer)
+                .global_work_size(numbers.len())
+                .build()?
+        };
+
+        let duration = enqueue_profiled(pro_que, &kernel, sem)?;
+
+        log::trace!("Reading output");
+        let mut output = vec![0u64; output_buffer.len()];
+        {
+            let _guard = sem.access();
+            output_buffer.read(&mut output).enq()?;
+        }
+
+        log::trace!("Mapping factors");
+        let factors = output
+            .chunks(MAX_FACTORS)
+            .map(|f| f.iter().take_while(|f| **f != 0).cloned().collect())
+            .collect::<Vec<Vec<u64>>>();
+        log::trace!("Factored {} numbers", factors.len());
+
+        Ok(ProfiledResult::new(duration, factors))
+    }
+
+    /// Benches an integer
+    fn bench_int(
+        &self,
+        global_size: usize,
+        local_size: usize,
+        calc_count: u32,
+    ) -> ocl::Result<BenchStatistics> {
+        let pro_que = &self.pro_que;
+        let sem = &self.sem;
+
+        let (input_buffer, write_duration) = {
+            let _guard = sem.access();
+            log::trace!("Writing input buffer");
+            let write_start = Instant::now();
+            let input_buffer = vec![0u32; global_size].to_ocl_buffer(pro_que)?;
+
+            (input_buffer, write_start.elapsed())
+        };
+
+        let kernel = {
+            let _guard = sem.access();
+            log::trace!("Building kernel");
+            pro_que
+                .kernel_builder("bench_int")
+                .local_work_size(local_size)
+                .global_work_size(global_size)
+                .arg(calc_count)
+                .arg(&input_buffer)
+                .build()?
+        };
+
+        let calc_duration = enqueue_profiled(pro_que, &kernel, sem)?;
+
+        let mut output = vec![0u32; global_size];
+        let read_duration = {
+            let _guard = sem.access();
+            log::trace!("Reading output");
+            let read_start = Instant::now();
+            input_buffer.read(&mut output).enq()?;
+
+            read_start.elapsed()
+        };
+
+        Ok(BenchStatistics {
+            global_size,
+            calc_count,
+            local_size,
+            read_duration,
+            calc_duration,
+            write_duration,
+        })
+    }
+}
+
+/// Returns all available platforms
+fn list_platforms() -> ocl::Result<Vec<Platform>> {
+    // Platform::list panics if the platforms can't be retrieved
+    Ok(ocl::core::get_platform_ids()?
+        .into_iter()
+        .map(Platform::new)
+        .collect())
+}
+
+/// Returns every platform and device that match the selections
+fn select_devices(
+    platform: Option<&str>,
+    selections: &[String],
+) -> ocl::Result<Vec<(Platform, Device)>> {
+    let candidates = select_platforms(platform)?;
+    if selections.is_empty() {
+        return Ok(vec![(candidates[0], Device::first(candidates[0])?)]);
+    }
+    let mut selected: Vec<(Platform, Device)> = Vec::new();
+
+    for selection in selections {
+        let devices = if selection.to_lowercase() == "all" {
+            let mut devices = Vec::new();
+            for platform in &candidates {
+                for device in Device::list_all(platform)? {
+                    devices.push((*platform, device));
+                }
+            }
+            devices
+        } else {
+            vec![select_device(&candidates, selection)?]
+        };
+        for (platform, device) in devices {
+            if !selected.iter().any(|(_, d)| *d == device) {
+                selected.push((platform, device));
+            }
+        }
+    }
+
+    Ok(selected)
+}
+
+/// Returns the selected platform or all platforms if there's no selection
+fn select_platforms(platform: Option<&str>) -> ocl::Result<Vec<Platform>> {
+    let platforms = list_platforms()?;
+    let candidates = match platform {
+        Some(selection) => vec![select(
+            &platforms,
+            selection,
+            |p| p.name().ok(),
+            "platform",
+        )?],
+        None => platforms,
+    };
+    if candidates.is_empty() {
+        return Err("No platforms found.".to_string().into());
+    }
+
+    Ok(candidates)
+}
+
+/// Returns the device that matches the selection on one of the platforms
+fn select_device(platforms: &[Platform], selection: &str) -> ocl::Result<(Platform, Device)> {
+    // indices always refer to the devices of the first platform
+    if selection.parse::<usize>().is_ok() {
+        let devices = Device::list_all(platforms[0])?;
+        let device = select(&devices, selection, |d| d.name().ok(), "device")?;
+        return Ok((platforms[0], device));
+    }
+    for platform in platforms {
+        let devices = Device::list_all(platform)?;
+        if let Ok(device) = select(&devices, selection, |d| d.name().ok(), "device") {
+            return Ok((*platform, device));
+        }
+    }
+
+    Err(format!("No device matches '{}'", selection).into())
+}
+
+/// Selects an item by its index or by a case insensitive part of its name
+fn select<T, F>(items: &[T], selection: &str, name: F, kind: &str) -> ocl::Result<T>
+where
+    T: Copy,
+    F: Fn(&T) -> Option<String>,
+{
+    if let Ok(index) = selection.parse::<usize>() {
+        return items
+            .get(index)
+            .cloned()
+            .ok_or_else(|| format!("There's no {} with the index {}", kind, index).into());
+    }
+    let selection = selection.to_lowercase();
+
+    items
+        .iter()
+        .find(|item| {
+            name(item)
+                .map(|n| n.to_lowercase().contains(&selection))
+                .unwrap_or(false)
+        })
+        .cloned()
+        .ok_or_else(|| format!("No {} matches '{}'", kind, selection).into())
+}
+
+/// Prints the capabilities of a device
+fn print_device_info(device: Device) -> ocl::Result<()> {
+    for info in DEVICE_INFO_KEYS.iter() {
+        println!("{:?}: {}", info, device.info(*info)?)
+    }
+    println!();
+
+    Ok(())
+}
+
+/// Prints every platform with all of its devices and their capabilities
+pub fn print_devices() -> ocl::Result<()> {
+    let platforms = list_platforms()?;
+    if platforms.is_empty() {
+        return Err("No platforms found.".to_string().into());
+    }
+
+    for (platform_index, platform) in platforms.iter().enumerate() {
+        println!(
+            "Platform {}: {} ({}, {})",
+            platform_index,
+            platform.name()?,
+            platform.vendor()?,
+            platform.version()?
+        );
+        println!();
+
+        for (device_index, device) in Device::list_all(platform)?.iter().enumerate() {
+            println!("Device {}: {}", device_index, device.name()?);
+            print_device_info(*device)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::*;
+    use crate::kernel_controller::primes::is_prime_mr;
+
+    /// Opens the first OpenCL device or returns None
+    /// so that the tests pass on machines without OpenCL
+    fn first_device() -> Option<Arc<OpenCLBackend>> {
+        match OpenCLBackend::open_devices(None, &[]) {
+            Ok(mut backends) => Some(Arc::new(backends.remove(0))),
+            Err(e) => {
+                eprintln!("Skipping the OpenCL test: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Runs the function on another thread and fails if it doesn't finish
+    /// in time because the device is still locked
+    fn run_with_timeout<T, F>(func: F) -> T
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || sender.send(func()));
+
+        receiver
+            .recv_timeout(Duration::from_secs(60))
+            .expect("The device is still locked")
+    }
+
+    #[test]
+    fn failed_calculation_releases_the_device() {
+        let backend = match first_device() {
+            Some(backend) => backend,
+            None => return,
+        };
+        let too_large = backend.max_work_group_size().unwrap() * 2;
+        assert!(backend
+            .check_prime(Candidates::odd_numbers(1, 1024, true), too_large)
+            .is_err());
+
+        let result = run_with_timeout(move || {
+            backend.check_prime(Candidates::odd_numbers(1, 1024, true), 64)
+        });
+        assert!(result.unwrap().value().contains(&2039));
+    }
+
+    #[test]
+    fn failed_benchmark_releases_the_device() {
+        let backend = match first_device() {
+            Some(backend) => backend,
+            None => return,
+        };
+        let too_large = backend.max_work_group_size().unwrap() * 2;
+        assert!(backend.bench_int(too_large, too_large, 1).is_err());
+
+        let result = run_with_timeout(move || backend.bench_int(1024, 64, 10));
+        assert_eq!(result.unwrap().global_size, 1024);
+    }
+
+    #[test]
+    fn miller_rabin_kernel_matches_cpu() {
+        let backend = match first_device() {
+            Some(backend) => backend,
+            None => return,
+        };
+        let mut numbers = (1..10_000).step_by(2).collect::<Vec<u64>>();
+        numbers.extend((0..10_000).map(|i| u64::MAX - 2 * i));
+        numbers.extend(&[2047, 3215031751, 341550071728321, 3825123056546413051]);
+
+        let primes = backend
+            .check_prime_mr(Candidates::Numbers(numbers.clone()), 64)
+            .unwrap()
+            .into_value();
+        let expected = numbers
+            .into_iter()
+            .filter(|n| is_prime_mr(*n))
+            .collect::<Vec<u64>>();
+        assert_eq!(primes, expected);
+    }
+}
@@ -5,20 +5,16 @@
  */
 
 use std::fmt::{self, Display, Formatter};
-use std::ops::Deref;
+use std::mem::size_of;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::time::{Duration, Instant};
+use std::time::Duration;
 
-use ocl_stream::executor::context::ExecutorContext;
 use ocl_stream::executor::stream::OCLStream;
-use ocl_stream::traits::*;
-use ocl_stream::utils::result::OCLStreamResult;
-use ocl_stream::utils::shared_buffer::SharedBuffer;
+use ocl_stream::utils::result::{OCLStreamError, OCLStreamResult};
 
-use crate::benching::enqueue_profiled;
+use crate::kernel_controller::validation::BENCH_KERNEL;
 use crate::kernel_controller::KernelController;
 use crate::utils::progress::get_progress_bar;
-use std_semaphore::Semaphore;
 
 #[derive(Clone, Debug)]
 pub struct BenchStatistics {
@@ -62,9 +58,8 @@ impl KernelController {
         let pb = get_progress_bar(
             ((global_size_stop - global_size_start) / global_size_step) as u64 * repetitions as u64,
         );
-        let sem = Semaphore::new(1);
 
-        let stream = self.executor.execute_bounded(global_size_stop, move |ctx| {
+        let stream = self.execute_bounded(global_size_stop, move |ctx| {
             loop {
                 if global_size.load(Ordering::SeqCst) > global_size_stop {
                     log::trace!("Stop reached");
@@ -77,8 +72,6 @@ impl KernelController {
                     pb.inc(repetitions as u64);
                     continue;
                 }
-                let input_buffer: SharedBuffer<u32> =
-                    vec![0u32; global_size].to_shared_buffer(ctx.pro_que())?;
 
                 log::trace!(
                     "Benching global size {} with {} repetitions",
@@ -86,9 +79,13 @@ impl KernelController {
                     repetitions
                 );
                 for _ in 0..repetitions {
-                    let stats =
-                        Self::bench_int(&ctx, local_size, calc_count, input_buffer.clone(), &sem)?;
-                    ctx.sender().send(stats)?;
+                    match ctx.backend().bench_int(global_size, local_size, calc_count) {
+                        Ok(stats) => ctx.sender().send(stats)?,
+                        Err(e) => {
+                            ctx.sender().err(bench_error(local_size, global_size, e))?;
+                            break;
+                        }
+                    }
                     pb.inc(1);
                 }
             }
@@ -111,15 +108,19 @@ impl KernelController {
         log::debug!("Benchmarking local size. Global Size: {}, Start: {}, Step: {} ,Stop: {}, Calculations: {}, Repetitions: {}",
                     global_size, local_size_start, local_size_step, local_size_stop, calc_count, repetitions);
 
-        let input_buffer: SharedBuffer<u32> =
-            vec![0u32; global_size].to_shared_buffer(self.executor.pro_que())?;
+        let max_local_size = self.max_local_size(BENCH_KERNEL)?;
+        if local_size_stop > max_local_size {
+            log::warn!(
+                "Skipping local sizes above the maximum work group size {}",
+                max_local_size
+            );
+        }
         let local_size = AtomicUsize::new(local_size_start);
         let pb = get_progress_bar(
             ((local_size_stop - local_size_start) / local_size_step) as u64 * repetitions as u64,
         );
-        let sem = Semaphore::new(1);
 
-        let stream = self.executor.execute_bounded(global_size, move |ctx| {
+        let stream = self.execute_bounded(global_size, move |ctx| {
             loop {
                 if local_size.load(Ordering::SeqCst) > local_size_stop {
                     log::trace!("Stop Reached");
@@ -127,8 +128,8 @@ impl KernelController {
                 }
                 let local_size = local_size.fetch_add(local_size_step, Ordering::SeqCst);
 
-                if local_size > 1024 || global_size % local_size != 0 {
-                    log::trace!("Global size not divisible by local size. Continuing");
+                if local_size > max_local_size || global_size % local_size != 0 {
+                    log::trace!("Local size too large or global size not divisible by local size. Continuing");
                     pb.inc(repetitions as u64);
                     continue;
                 }
@@ -139,9 +140,13 @@ impl KernelController {
                     repetitions
                 );
                 for _ in 0..repetitions {
-                    let stats =
-                        Self::bench_int(&ctx, local_size, calc_count, input_buffer.clone(), &sem)?;
-                    ctx.sender().send(stats)?;
+                    match ctx.backend().bench_int(global_size, local_size, calc_count) {
+                        Ok(stats) => ctx.sender().send(stats)?,
+                        Err(e) => {
+                            ctx.sender().err(bench_error(local_size, global_size, e))?;
+                            break;
+                        }
+                    }
                     pb.inc(1);
                 }
             }
@@ -151,45 +156,81 @@ impl KernelController {
         Ok(stream)
     }
 
-    /// Benches an integer
-    fn bench_int(
-        ctx: &ExecutorContext<BenchStatistics>,
-        local_size: usize,
+    /// Benchmarks every combination of the given local and global sizes.
+    /// Combinations that can't be executed on the device are skipped.
+    pub fn bench_grid(
+        &self,
+        local_sizes: Vec<usize>,
+        global_sizes: Vec<usize>,
         calc_count: u32,
-        input_buffer: SharedBuffer<u32>,
-        sem: &Semaphore,
-    ) -> ocl::Result<BenchStatistics> {
-        let num_tasks = input_buffer.inner().lock().len();
-
-        log::trace!("Building kernel");
-        sem.acquire();
-        let kernel = ctx
-            .pro_que()
-            .kernel_builder("bench_int")
-            .local_work_size(local_size)
-            .global_work_size(num_tasks)
-            .arg(calc_count)
-            .arg(input_buffer.inner().lock().deref())
-            .build()?;
-        sem.release();
-
-        let calc_duration = enqueue_profiled(ctx.pro_que(), &kernel, sem)?;
-
-        log::trace!("Reading output");
-        let mut output = vec![0u32; num_tasks];
-        sem.acquire();
-        let read_start = Instant::now();
-        input_buffer.read(&mut output)?;
-        let read_duration = read_start.elapsed();
-        sem.release();
-
-        Ok(BenchStatistics {
-            global_size: num_tasks,
-            calc_count,
-            local_size,
-            read_duration,
-            calc_duration,
-            write_duration: Duration::from_nanos(0),
-        })
+        repetitions: usize,
+    ) -> OCLStreamResult<OCLStream<BenchStatistics>> {
+        log::debug!(
+            "Benchmarking local sizes {:?} with global sizes {:?}, Calculations: {}, Repetitions: {}",
+            local_sizes, global_sizes, calc_count, repetitions
+        );
+        let max_local_size = self.max_local_size(BENCH_KERNEL)?;
+        let max_global_size =
+            (self.max_mem_alloc_size()? / size_of::<u32>() as u64).min(usize::MAX as u64) as usize;
+        let combinations = local_sizes
+            .iter()
+            .filter(|local_size| **local_size <= max_local_size)
+            .flat_map(|local_size| {
+                global_sizes
+                    .iter()
+                    .filter(move |global_size| {
+                        **global_size <= max_global_size && *global_size % local_size == 0
+                    })
+                    .map(move |global_size| (*local_size, *global_size))
+            })
+            .collect::<Vec<(usize, usize)>>();
+        log::debug!(
+            "Skipping {} of {} combinations",
+            local_sizes.len() * global_sizes.len() - combinations.len(),
+            local_sizes.len() * global_sizes.len()
+        );
+        let index = AtomicUsize::new(0);
+        let pb = get_progress_bar(combinations.len() as u64 * repetitions as u64);
+
+        let size = (combinations.len() * repetitions).max(1);
+
+        let stream = self.execute_bounded(size, move |ctx| {
+            loop {
+                let index = index.fetch_add(1, Ordering::SeqCst);
+                if index >= combinations.len() {
+                    log::trace!("Stop reached");
+                    break;
+                }
+                let (local_size, global_size) = combinations[index];
+
+                log::trace!(
+                    "Benching local size {} and global size {} with {} repetitions",
+                    local_size,
+                    global_size,
+                    repetitions
+                );
+                for _ in 0..repetitions {
+                    match ctx.backend().bench_int(global_size, local_size, calc_count) {
+                        Ok(stats) => ctx.sender().send(stats)?,
+                        Err(e) => {
+                            ctx.sender().err(bench_error(local_size, global_size, e))?;
+                            break;
+                        }
+                    }
+                    pb.inc(1);
+                }
+            }
+            Ok(())
+        });
+
+        Ok(stream)
     }
 }
+
+/// Adds the launch parameters to the error of a failed benchmark
+fn bench_error(local_size: usize, global_size: usize, e: ocl::Error) -> OCLStreamError {
+    OCLStreamError::OCLError(format!(
+        "Benchmark with local size {} and global size {} failed: {}",
+        local_size, global_size, e
+    ))
+}
@@ -0,0 +1,282 @@
+/*
+ * opencl demos with rust
+ * Copyright (C) 2021 trivernis
+ * See LICENSE for more information
+ */
+
+use std::mem::size_of;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crossbeam_channel::Receiver;
+use ocl_stream::utils::result::OCLStreamError;
+use parking_lot::RwLock;
+
+use crate::benching::result::ProfiledResult;
+use crate::kernel_controller::backend::{Candidates, ComputeBackend};
+use crate::kernel_controller::prime_cache::PrimeCache;
+use crate::kernel_controller::primes::{integer_sqrt, PrimeAlgorithm};
+use crate::kernel_controller::KernelController;
+use crate::output::ordered::{Indexed, OrderedStream, ReorderWindow};
+use crate::utils::numbers::NumberBatch;
+
+/// The largest divisor the lower primes are calculated for.
+/// Numbers with a greater square root are checked without the cached primes.
+const MAX_LOWER_PRIME_LIMIT: u64 = 1 << 28;
+
+impl KernelController {
+    /// Checks batches of arbitrary numbers for primality.
+    /// The batches are tagged with their index and the results are emitted in the same order
+    /// with at most `reorder_buffer` batches being buffered.
+    /// Every result contains the numbers of the batch paired with their primality.
+    pub fn check_numbers(
+        &self,
+        batches: Receiver<NumberBatch>,
+        local_size: usize,
+        algorithm: PrimeAlgorithm,
+        reorder_buffer: usize,
+    ) -> OrderedStream<ProfiledResult<Vec<(u64, bool)>>> {
+        log::debug!(
+            "Checking numbers with a local size of {} and algorithm {:?}",
+            local_size,
+            algorithm
+        );
+        let lower_primes = Arc::new(RwLock::new(None));
+        // the lower primes are passed to the devices in a single buffer
+        let max_buffer_numbers = self.max_mem_alloc_size().unwrap_or(0) / size_of::<u64>() as u64;
+        let max_limit = MAX_LOWER_PRIME_LIMIT.min(max_buffer_numbers);
+        let window = ReorderWindow::new(reorder_buffer);
+        let producer_window = Arc::clone(&window);
+
+        let stream = self.execute_bounded(reorder_buffer, move |ctx| {
+            let backend = ctx.backend();
+
+            while let Ok((index, numbers)) = batches.recv() {
+                let count = numbers.len();
+                log::trace!("Checking batch {} with {} numbers", index, count);
+
+                let mut result = match algorithm {
+                    PrimeAlgorithm::TrialDivision { use_cache: true } => check_with_lower_primes(
+                        backend,
+                        &numbers,
+                        local_size,
+                        &lower_primes,
+                        max_limit,
+                    ),
+                    _ => check_primality(backend, &numbers, local_size, algorithm, None),
+                }
+                .map_err(|e| {
+                    OCLStreamError::OCLError(format!(
+                        "Checking the batch {} with {} numbers on device {} failed: {}",
+                        index,
+                        count,
+                        ctx.device_id(),
+                        e
+                    ))
+                })?;
+                result.set_device(ctx.device_id());
+                producer_window.wait_for(index);
+                ctx.sender().send(Indexed::new(index, result))?;
+            }
+
+            Ok(())
+        });
+
+        OrderedStream::new(stream, window)
+    }
+}
+
+/// Checks the numbers with the cached lower primes.
+/// Numbers whose square root exceeds the maximum limit are checked separately without
+/// the cached primes so that they don't disable the cache for the whole batch.
+fn check_with_lower_primes(
+    backend: &dyn ComputeBackend,
+    numbers: &[u64],
+    local_size: usize,
+    lower_primes: &RwLock<Option<Arc<PrimeCache>>>,
+    max_limit: u64,
+) -> ocl::Result<ProfiledResult<Vec<(u64, bool)>>> {
+    let algorithm = PrimeAlgorithm::TrialDivision { use_cache: true };
+    let is_cacheable = |n: &u64| integer_sqrt(*n) < max_limit;
+    let (small, large): (Vec<u64>, Vec<u64>) = numbers.iter().partition(|n| is_cacheable(n));
+    if !large.is_empty() {
+        log::debug!(
+            "Checking {} of {} numbers without the cached primes because their square root exceeds {}",
+            large.len(),
+            numbers.len(),
+            max_limit
+        );
+    }
+    let limit = small.iter().max().map_or(0, |n| integer_sqrt(*n) + 1);
+    let prime_cache = if small.is_empty() {
+        None
+    } else {
+        update_lower_primes(lower_primes, limit, max_limit)
+    };
+
+    let small = check_primality(
+        backend,
+        &small,
+        local_size,
+        algorithm,
+        prime_cache.as_deref(),
+    )?;
+    let large = check_primality(backend, &large, local_size, algorithm, None)?;
+    let duration = *small.duration() + *large.duration();
+    let mut small = small.into_value().into_iter();
+    let mut large = large.into_value().into_iter();
+    let paired = numbers
+        .iter()
+        .filter_map(|n| {
+            if is_cacheable(n) {
+                small.next()
+            } else {
+                large.next()
+            }
+        })
+        .collect();
+
+    Ok(ProfiledResult::new(duration, paired))
+}
+
+/// Checks the numbers on the backend and pairs them with their primality
+fn check_primality(
+    backend: &dyn ComputeBackend,
+    numbers: &[u64],
+    local_size: usize,
+    algorithm: PrimeAlgorithm,
+    prime_cache: Option<&PrimeCache>,
+) -> ocl::Result<ProfiledResult<Vec<(u64, bool)>>> {
+    if numbers.is_empty() {
+        return Ok(ProfiledResult::new(Duration::default(), Vec::new()));
+    }
+    let count = numbers.len();
+    let mut padded = numbers.to_vec();
+    // the kernels need the global size to be divisible by the local size
+    padded.resize(count + (local_size - count % local_size) % local_size, 0);
+
+    let result = match algorithm {
+        PrimeAlgorithm::TrialDivision { .. } if prime_cache.is_some() => {
+            log::trace!("Using optimized function with cached primes");
+            backend.check_prime_cached(
+                Candidates::Numbers(padded),
+                local_size,
+                prime_cache.unwrap(),
+            )
+        }
+        PrimeAlgorithm::TrialDivision { .. } => {
+            log::trace!("Using normal prime calculation function");
+            backend.check_prime(Candidates::Numbers(padded), local_size)
+        }
+        PrimeAlgorithm::MillerRabin => {
+            log::trace!("Using Miller-Rabin test");
+            backend.check_prime_mr(Candidates::Numbers(padded), local_size)
+        }
+        PrimeAlgorithm::Sieve { .. } => Err(ocl::Error::from(
+            "The sieve can't check arbitrary numbers".to_string(),
+        )),
+    }?;
+
+    Ok(ProfiledResult::new(
+        *result.duration(),
+        pair_primality(numbers, result.value()),
+    ))
+}
+
+/// Returns a prime cache that contains every prime below the limit.
+/// Returns None if the limit is too large to cache the primes.
+fn update_lower_primes(
+    lower_primes: &RwLock<Option<Arc<PrimeCache>>>,
+    limit: u64,
+    max_limit: u64,
+) -> Option<Arc<PrimeCache>> {
+    if let Some(cache) = &*lower_primes.read() {
+        if cache.limit() >= limit {
+            return Some(Arc::clone(cache));
+        }
+    }
+    if limit > max_limit {
+        return None;
+    }
+    let mut lower_primes = lower_primes.write();
+    let current_limit = lower_primes.as_ref().map_or(0, |cache| cache.limit());
+
+    if current_limit < limit {
+        // grow in larger steps to avoid recalculations for slowly increasing numbers
+        let limit = limit.max(current_limit * 2).min(max_limit);
+        log::debug!(
+            "Calculating the primes below {} for the divisibility check",
+            limit
+        );
+        *lower_primes = Some(Arc::new(PrimeCache::calculate(limit)));
+    }
+
+    lower_primes.as_ref().map(Arc::clone)
+}
+
+/// Pairs the numbers with their primality. The primes need to be
+/// in the same order as they appear in the numbers.
+fn pair_primality(numbers: &[u64], primes: &[u64]) -> Vec<(u64, bool)> {
+    let mut primes = primes.iter().peekable();
+
+    numbers
+        .iter()
+        .map(|n| {
+            let is_prime = primes.peek() == Some(&n);
+            if is_prime {
+                primes.next();
+            }
+            (*n, is_prime)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel_controller::backend::cpu::CpuBackend;
+    use crate::kernel_controller::primes::is_prime_mr;
+
+    #[test]
+    fn mixed_batch_uses_the_cache_for_small_numbers() {
+        let backend = CpuBackend::new();
+        let lower_primes = RwLock::new(None);
+        // numbers above 10^6 have a square root that exceeds the limit
+        let max_limit = 1000;
+        let numbers = (990_001..990_101)
+            .chain(1_000_001..1_000_101)
+            .chain(vec![2, 3, 4, 1_000_003, 999_983, 999_999_937, u64::MAX])
+            .rev()
+            .collect::<Vec<u64>>();
+
+        let result =
+            check_with_lower_primes(&backend, &numbers, 64, &lower_primes, max_limit).unwrap();
+        let expected = numbers
+            .iter()
+            .map(|n| (*n, is_prime_mr(*n)))
+            .collect::<Vec<(u64, bool)>>();
+        assert_eq!(result.value(), &expected);
+
+        // the large numbers don't prevent the small ones from using the cached primes
+        let cache = lower_primes
+            .read()
+            .clone()
+            .expect("The primes weren't cached");
+        assert!(cache.limit() > integer_sqrt(999_983));
+        assert!(cache.limit() <= max_limit);
+    }
+
+    #[test]
+    fn large_batch_is_checked_without_the_cache() {
+        let backend = CpuBackend::new();
+        let lower_primes = RwLock::new(None);
+        let numbers = vec![1_000_003, 1_000_005, 999_999_937];
+
+        let result = check_with_lower_primes(&backend, &numbers, 64, &lower_primes, 1000).unwrap();
+        assert_eq!(
+            result.value(),
+            &vec![(1_000_003, true), (1_000_005, false), (999_999_937, true)]
+        );
+        assert!(lower_primes.read().is_none());
+    }
+}
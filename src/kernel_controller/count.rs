@@ -0,0 +1,204 @@
+/*
+ * opencl demos with rust
+ * Copyright (C) 2021 trivernis
+ * See LICENSE for more information
+ */
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use ocl_stream::utils::result::OCLStreamError;
+
+use crate::benching::result::ProfiledResult;
+use crate::kernel_controller::backend::Candidates;
+use crate::kernel_controller::primes::{get_base_primes, integer_sqrt, PrimeAlgorithm};
+use crate::kernel_controller::KernelController;
+use crate::output::ordered::{Indexed, OrderedStream, ReorderWindow};
+use crate::utils::progress::get_progress_bar;
+
+impl KernelController {
+    /// Counts the primes from start up to the exclusive stop on the compute backends
+    /// without reading the primes from the devices.
+    /// Every step is counted with one kernel launch and the counts are emitted in
+    /// ascending order with at most `reorder_buffer` steps being buffered.
+    /// Only odd numbers are checked so the prime 2 is added to the count of the first step.
+    pub fn count_primes(
+        &self,
+        start: u64,
+        stop: u64,
+        step: usize,
+        local_size: usize,
+        mut algorithm: PrimeAlgorithm,
+        reorder_buffer: usize,
+    ) -> OrderedStream<ProfiledResult<u64>> {
+        let includes_two = start <= 2 && stop > 2;
+        // the range needs to contain a step for the prime 2 even if it ends at 3
+        let start = if includes_two { 1 } else { start | 1 };
+        log::debug!(
+            "Counting primes between {} and {} with {} number per step, a local size of {} and algorithm {:?}",
+            start,
+            stop,
+            step,
+            local_size,
+            algorithm,
+        );
+        let root = integer_sqrt(stop) + 1;
+        let mut prime_cache = None;
+        let mut base_primes = Vec::new();
+
+        match algorithm {
+            PrimeAlgorithm::TrialDivision { use_cache: true } => {
+                prime_cache = self.prime_cache(stop);
+                if prime_cache.is_none() {
+                    log::warn!("Counting without cached primes.");
+                    algorithm = PrimeAlgorithm::TrialDivision { use_cache: false };
+                }
+            }
+            PrimeAlgorithm::Sieve { .. } => base_primes = get_base_primes(root),
+            _ => {}
+        }
+        let base_primes = Arc::new(base_primes);
+        let next_index = Arc::new(AtomicUsize::new(0));
+        let upload_numbers = self.upload_numbers;
+        let step_len = step as u64 * 2;
+        let steps = stop.saturating_sub(start);
+        let pb = get_progress_bar(steps / step_len + (steps % step_len != 0) as u64);
+        let window = ReorderWindow::new(reorder_buffer);
+        let producer_window = Arc::clone(&window);
+
+        let stream = self.execute_bounded(reorder_buffer, move |ctx| {
+            let backend = ctx.backend();
+
+            loop {
+                let index = next_index.fetch_add(1, Ordering::SeqCst);
+                let offset = match (index as u64)
+                    .checked_mul(step as u64 * 2)
+                    .and_then(|o| o.checked_add(start))
+                    .filter(|o| *o < stop)
+                {
+                    Some(offset) => offset,
+                    None => break,
+                };
+                // the last step ends before the stop
+                let count = ((stop - offset + 1) / 2).min(step as u64) as usize;
+                log::trace!("Counting primes of {} numbers beginning from {}", count, offset);
+
+                let result = match algorithm {
+                    PrimeAlgorithm::Sieve { segment_size } => backend.count_sieved_primes(
+                        offset,
+                        count,
+                        segment_size,
+                        local_size,
+                        &base_primes,
+                    ),
+                    _ => backend.count_primes(
+                        Candidates::odd_numbers(offset, count, upload_numbers),
+                        local_size,
+                        algorithm,
+                        prime_cache.as_deref(),
+                    ),
+                }
+                .map_err(|e| {
+                    OCLStreamError::OCLError(format!(
+                        "Counting the primes of {} numbers beginning from {} on device {} failed: {}",
+                        count,
+                        offset,
+                        ctx.device_id(),
+                        e
+                    ))
+                })?;
+
+                let duration = *result.duration();
+                let mut primes = result.into_value();
+                if index == 0 && includes_two {
+                    primes += 1;
+                }
+                let mut result = ProfiledResult::new(duration, primes);
+                result.set_device(ctx.device_id());
+                producer_window.wait_for(index);
+                ctx.sender().send(Indexed::new(index, result))?;
+                pb.inc(1);
+            }
+
+            Ok(())
+        });
+
+        OrderedStream::new(stream, window)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::args::Backend;
+
+    fn count(start: u64, stop: u64, step: usize, algorithm: PrimeAlgorithm) -> u64 {
+        let controller = KernelController::new(Backend::Cpu, None, &[]).unwrap();
+        let mut stream = controller.count_primes(start, stop, step, 64, algorithm, 4);
+        let mut total = 0;
+        loop {
+            match stream.next() {
+                Ok(r) => total += r.value(),
+                Err(OCLStreamError::RecvError(_)) => return total,
+                Err(e) => panic!("{}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn stop_is_exclusive() {
+        let algorithms = [
+            PrimeAlgorithm::TrialDivision { use_cache: false },
+            PrimeAlgorithm::Sieve { segment_size: 64 },
+            PrimeAlgorithm::MillerRabin,
+        ];
+        for algorithm in algorithms.iter() {
+            assert_eq!(count(0, 2, 64, *algorithm), 0);
+            assert_eq!(count(0, 3, 64, *algorithm), 1);
+            assert_eq!(count(2, 3, 64, *algorithm), 1);
+            assert_eq!(count(0, 7, 64, *algorithm), 3);
+            assert_eq!(count(0, 8, 64, *algorithm), 4);
+            assert_eq!(count(7, 7, 64, *algorithm), 0);
+            assert_eq!(count(7, 8, 64, *algorithm), 1);
+            // the stop falls on the first number of the next step
+            assert_eq!(count(0, 129, 64, *algorithm), 31);
+            assert_eq!(count(0, 131, 64, *algorithm), 31);
+            assert_eq!(count(0, 132, 64, *algorithm), 32);
+        }
+    }
+    #[test]
+    fn count_matches_the_calculated_primes() {
+        let algorithms = [
+            PrimeAlgorithm::TrialDivision { use_cache: true },
+            PrimeAlgorithm::Sieve { segment_size: 64 },
+            PrimeAlgorithm::MillerRabin,
+        ];
+        let ranges = [
+            (0, 2),
+            (0, 3),
+            (2, 3),
+            (1, 100),
+            (3, 1000),
+            (128, 129),
+            (1000, 5000),
+        ];
+        for algorithm in algorithms.iter() {
+            for (start, stop) in ranges.iter() {
+                let mut controller = KernelController::new(Backend::Cpu, None, &[]).unwrap();
+                let mut stream = controller.calculate_primes(*start, *stop, 64, 64, *algorithm, 4);
+                let mut calculated = 0;
+                while let Ok(r) = stream.next() {
+                    calculated += r.value().len() as u64;
+                }
+                assert_eq!(
+                    count(*start, *stop, 64, *algorithm),
+                    calculated,
+                    "{}..{} {:?}",
+                    start,
+                    stop,
+                    algorithm
+                );
+            }
+        }
+    }
+}
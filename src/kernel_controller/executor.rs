@@ -0,0 +1,102 @@
+/*
+ * opencl demos with rust
+ * Copyright (C) 2021 trivernis
+ * See LICENSE for more information
+ */
+
+use std::sync::Arc;
+use std::thread;
+
+use ocl_stream::stream::{self, OCLStream, OCLStreamSender};
+use ocl_stream::utils::result::OCLStreamResult;
+
+use crate::kernel_controller::backend::ComputeBackend;
+
+/// Context passed to the executing closure
+/// to provide access to the backend and the sender
+pub struct BackendContext<T>
+where
+    T: Send + Sync,
+{
+    backend: Arc<dyn ComputeBackend>,
+    sender: OCLStreamSender<T>,
+    task_id: usize,
+    device_id: usize,
+}
+
+impl<T> BackendContext<T>
+where
+    T: Send + Sync,
+{
+    /// Returns the backend the calculations are executed on
+    pub fn backend(&self) -> &dyn ComputeBackend {
+        &*self.backend
+    }
+
+    /// Returns the Sender
+    pub fn sender(&self) -> &OCLStreamSender<T> {
+        &self.sender
+    }
+
+    /// Returns the unique task id of the scheduled task
+    #[allow(dead_code)]
+    pub fn task_id(&self) -> usize {
+        self.task_id
+    }
+
+    /// Returns the index of the device the task is executed on
+    pub fn device_id(&self) -> usize {
+        self.device_id
+    }
+}
+
+/// Executes a closure on `concurrency` threads per backend with a bounded channel.
+/// Errors returned by the closure are forwarded into the stream.
+pub fn execute_bounded<F, T>(
+    backends: Vec<Arc<dyn ComputeBackend>>,
+    concurrency: usize,
+    size: usize,
+    func: F,
+) -> OCLStream<T>
+where
+    F: Fn(BackendContext<T>) -> OCLStreamResult<()> + Send + Sync + 'static,
+    T: Send + Sync + 'static,
+{
+    let (stream, sender) = stream::bounded(size);
+    let func = Arc::new(func);
+
+    log::debug!(
+        "Spawning {} executor threads per device for {} devices",
+        concurrency,
+        backends.len()
+    );
+
+    for task_id in 0..concurrency * backends.len() {
+        let func = Arc::clone(&func);
+        let device_id = task_id % backends.len();
+        let context = BackendContext {
+            backend: Arc::clone(&backends[device_id]),
+            sender: sender.clone(),
+            task_id,
+            device_id,
+        };
+
+        thread::Builder::new()
+            .name(format!("executor-{}", task_id))
+            .spawn(move || {
+                let sender = context.sender().clone();
+
+                log::debug!("Running function in thread {}", task_id);
+                if let Err(e) = func(context) {
+                    log::error!("Execution of function failed: {}", e);
+                    // the receiver is gone if the stream was dropped early
+                    if let Err(e) = sender.err(e) {
+                        log::debug!("Failed to forward error to receiver: {}", e);
+                    }
+                }
+            })
+            .expect("Failed to spawn executor thread");
+    }
+
+    stream
+}
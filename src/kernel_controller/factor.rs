@@ -0,0 +1,268 @@
+/*
+ * opencl demos with rust
+ * Copyright (C) 2021 trivernis
+ * See LICENSE for more information
+ */
+
+use std::sync::Arc;
+
+use crossbeam_channel::Receiver;
+use ocl_stream::utils::result::OCLStreamError;
+
+use crate::benching::result::ProfiledResult;
+use crate::kernel_controller::primes::{get_base_primes, integer_sqrt, is_prime_mr, Montgomery};
+use crate::kernel_controller::KernelController;
+use crate::output::ordered::{Indexed, OrderedStream, ReorderWindow};
+use crate::utils::numbers::NumberBatch;
+
+/// The kernel that factors numbers
+pub const FACTOR_KERNEL: &str = "factor";
+/// The maximum number of prime factors of a 64 bit number
+pub(crate) const MAX_FACTORS: usize = 64;
+/// The primes up to this limit are used for the trial division before pollard's rho is used
+const SMALL_PRIME_LIMIT: u64 = 1 << 16;
+/// How often pollard's rho is restarted with a different polynomial
+const RHO_ATTEMPTS: u64 = 8;
+/// The maximum cycle length that is searched before a polynomial is given up
+const RHO_MAX_CYCLE: u64 = 1 << 24;
+/// Number of differences that are multiplied before the gcd is calculated
+const RHO_BATCH: u64 = 128;
+
+/// A number paired with its sorted prime factors
+pub type Factorization = (u64, Vec<u64>);
+
+impl KernelController {
+    /// Factors batches of arbitrary numbers.
+    /// The batches are tagged with their index and the results are emitted in the same order
+    /// with at most `reorder_buffer` batches being buffered.
+    /// Every result contains the numbers of the batch paired with their sorted prime factors.
+    pub fn factor_numbers(
+        &self,
+        batches: Receiver<NumberBatch>,
+        local_size: usize,
+        reorder_buffer: usize,
+    ) -> OrderedStream<ProfiledResult<Vec<Factorization>>> {
+        log::debug!("Factoring numbers with a local size of {}", local_size);
+        let small_primes = Arc::new(get_small_primes());
+        let window = ReorderWindow::new(reorder_buffer);
+        let producer_window = Arc::clone(&window);
+
+        let stream = self.execute_bounded(reorder_buffer, move |ctx| {
+            let backend = ctx.backend();
+
+            while let Ok((index, mut numbers)) = batches.recv() {
+                let count = numbers.len();
+                log::trace!("Factoring batch {} with {} numbers", index, count);
+                let input = numbers.clone();
+                // the kernel needs the global size to be divisible by the local size
+                numbers.resize(count + (local_size - count % local_size) % local_size, 0);
+
+                let result = backend
+                    .factor(numbers, local_size, &small_primes)
+                    .map_err(|e| {
+                        OCLStreamError::OCLError(format!(
+                            "Factoring the batch {} with {} numbers on device {} failed: {}",
+                            index,
+                            count,
+                            ctx.device_id(),
+                            e
+                        ))
+                    })?;
+                let duration = *result.duration();
+                let factors = input
+                    .into_iter()
+                    .zip(result.into_value())
+                    .map(|(number, mut factors)| {
+                        // the device gives up on factors that are hard to split
+                        if factors.iter().any(|f| !is_prime_mr(*f)) {
+                            log::debug!("Factoring {} on the cpu", number);
+                            factors = factorize(number, &small_primes);
+                        }
+                        factors.sort_unstable();
+                        (number, factors)
+                    })
+                    .collect::<Vec<Factorization>>();
+
+                let mut result = ProfiledResult::new(duration, factors);
+                result.set_device(ctx.device_id());
+                producer_window.wait_for(index);
+                ctx.sender().send(Indexed::new(index, result))?;
+            }
+
+            Ok(())
+        });
+
+        OrderedStream::new(stream, window)
+    }
+}
+
+/// Returns the primes that are used for the trial division
+/// before the remaining factors are split with pollard's rho
+pub(crate) fn get_small_primes() -> Vec<u32> {
+    let mut primes = vec![2];
+    primes.append(&mut get_base_primes(SMALL_PRIME_LIMIT));
+
+    primes
+}
+
+/// Returns the sorted prime factors of a number like the 'factor' kernel.
+/// Factors that pollard's rho can't split within the limits of the kernel
+/// are split by trial division.
+pub(crate) fn factorize(mut num: u64, small_primes: &[u32]) -> Vec<u64> {
+    let mut factors = Vec::new();
+    if num < 2 {
+        return factors;
+    }
+    for prime in small_primes.iter().map(|p| *p as u64) {
+        if prime * prime > num {
+            break;
+        }
+        while num % prime == 0 {
+            factors.push(prime);
+            num /= prime;
+        }
+    }
+
+    let mut composites = Vec::new();
+    if num > 1 {
+        composites.push(num);
+    }
+    while let Some(num) = composites.pop() {
+        if is_prime_mr(num) {
+            factors.push(num);
+        } else {
+            let divisor = pollard_brent(num).unwrap_or_else(|| {
+                log::debug!("Splitting {} with trial division", num);
+                smallest_factor(num)
+            });
+            composites.push(divisor);
+            composites.push(num / divisor);
+        }
+    }
+    factors.sort_unstable();
+
+    factors
+}
+
+/// Pollard's rho with Brent's cycle detection for odd composite numbers.
+/// Returns a non-trivial factor of the number or None if none was found.
+fn pollard_brent(num: u64) -> Option<u64> {
+    let montgomery = Montgomery::new(num);
+    let distance = |a: u64, b: u64| if a > b { a - b } else { b - a };
+
+    for c in 1..=RHO_ATTEMPTS.min(num - 1) {
+        // f(y) = y^2 + c with y in montgomery form
+        let step = |y: u64| {
+            let square = montgomery.mul(y, y);
+            if square >= num - c {
+                square - (num - c)
+            } else {
+                square + c
+            }
+        };
+        let mut y = 2;
+        let mut q = montgomery.one();
+        let mut x = y;
+        let mut ys = y;
+        let mut g = 1;
+        let mut r = 1;
+
+        while g == 1 && r <= RHO_MAX_CYCLE {
+            x = y;
+            for _ in 0..r {
+                y = step(y);
+            }
+            let mut k = 0;
+            while k < r && g == 1 {
+                ys = y;
+                for _ in 0..RHO_BATCH.min(r - k) {
+                    y = step(y);
+                    q = montgomery.mul(q, distance(x, y));
+                }
+                g = gcd(q, num);
+                k += RHO_BATCH;
+            }
+            r *= 2;
+        }
+        if g == num {
+            // the batch contained the factor so the steps are repeated one by one
+            loop {
+                ys = step(ys);
+                g = gcd(distance(x, ys), num);
+                if g != 1 {
+                    break;
+                }
+            }
+        }
+        if g != 1 && g != num {
+            return Some(g);
+        }
+    }
+
+    None
+}
+
+/// Returns the smallest prime factor of an odd composite number
+fn smallest_factor(num: u64) -> u64 {
+    (3..=integer_sqrt(num))
+        .step_by(2)
+        .find(|divisor| num % divisor == 0)
+        .unwrap_or(num)
+}
+
+fn gcd(mut a: u64, mut b: u64) -> u64 {
+    while b != 0 {
+        let remainder = a % b;
+        a = b;
+        b = remainder;
+    }
+
+    a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_factors(num: u64, factors: &[u64]) {
+        assert_eq!(factorize(num, &get_small_primes()), factors, "{}", num);
+    }
+
+    #[test]
+    fn small_numbers() {
+        assert_factors(0, &[]);
+        assert_factors(1, &[]);
+        assert_factors(2, &[2]);
+        assert_factors(60, &[2, 2, 3, 5]);
+        assert_factors(65537, &[65537]);
+    }
+
+    #[test]
+    fn powers_of_two() {
+        for exponent in 1..64 {
+            assert_factors(1 << exponent, &vec![2; exponent]);
+        }
+    }
+
+    #[test]
+    fn semiprimes_near_the_limit() {
+        // the two largest primes below the square root of 2^63
+        assert_factors(9223371873002223329, &[3037000453, 3037000493]);
+        assert_factors(9223372036854349817, &[65537, 140735340904441]);
+        assert_factors(u64::MAX, &[3, 5, 17, 257, 641, 65537, 6700417]);
+    }
+
+    #[test]
+    fn prime_powers() {
+        assert_factors(9223371994482243049, &[3037000493, 3037000493]);
+        assert_factors(9223253290108583207, &[2097143, 2097143, 2097143]);
+        assert_factors(65537 * 65537, &[65537, 65537]);
+    }
+
+    #[test]
+    fn trial_division_finds_the_smallest_factor() {
+        assert_eq!(smallest_factor(1000003 * 1000033), 1000003);
+        assert_eq!(smallest_factor(65537 * 65537), 65537);
+        assert_eq!(smallest_factor(15), 3);
+    }
+}
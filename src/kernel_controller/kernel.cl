@@ -5,59 +5,203 @@
  */
 
 
-__kernel void check_prime_cached(const uint LOWER_PRIME_COUNT, __global const ulong *LOWER_PRIMES, __global const ulong *IN, __global bool *OUT) {
-    uint id = get_global_id(0);
-    ulong num = IN[id];
+inline bool is_prime_cached(ulong num, uint lower_prime_count, __global const ulong *lower_primes) {
     ulong limit = (ulong) native_sqrt((double) num) + 1;
 
     if (num == 2 || num == 3) {
-        OUT[id] = true;
-        return;
-    } else if (num == 1) {
-        return;
+        return true;
+    } else if (num <= 1) {
+        return false;
     } else {
-        for (uint i = 0; i < LOWER_PRIME_COUNT; i++) {
-            if (LOWER_PRIMES[i] >= limit) {
+        for (uint i = 0; i < lower_prime_count; i++) {
+            if (lower_primes[i] >= limit) {
                 break;
             }
-            if (num % LOWER_PRIMES[i] == 0) {
-                return;
+            if (num % lower_primes[i] == 0) {
+                return false;
             }
         }
     }
 
-    OUT[id] = true;
+    return true;
 }
 
-__kernel void check_prime(__global const ulong *IN, __global bool *OUT) {
-    uint id = get_global_id(0);
-    ulong num = IN[id];
+inline bool is_prime_trial(ulong num) {
     ulong limit = (ulong) native_sqrt((double) num) + 1;
 
     if (num == 2 || num == 3) {
-        OUT[id] = true;
-        return;
+        return true;
     } else if (num == 1 || num % 2 == 0) {
-        return;
+        return false;
     }
     if (limit < 9) {
         for (ulong i = 3; i <= limit; i++) {
             if (num % i == 0) {
-                return;
+                return false;
             }
         }
     } else {
         if (num > 3 && num % 3 == 0) {
-            return;
+            return false;
         }
         for (ulong i = 9; i <= (limit + 6); i += 6) {
             if (num % (i - 2) == 0 || num % (i - 4) == 0) {
-                return;
+                return false;
             }
         }
     }
 
-    OUT[id] = true;
+    return true;
+}
+
+// Sums the values of all work items of the work group in local memory.
+// The sum is stored in COUNTS[0].
+inline void reduce_work_group(uint value, __local uint *COUNTS) {
+    uint lid = get_local_id(0);
+    uint local_size = get_local_size(0);
+    uint stride = 1;
+
+    COUNTS[lid] = value;
+    barrier(CLK_LOCAL_MEM_FENCE);
+
+    while (stride < local_size) {
+        stride <<= 1;
+    }
+    for (stride >>= 1; stride > 0; stride >>= 1) {
+        if (lid < stride && lid + stride < local_size) {
+            COUNTS[lid] += COUNTS[lid + stride];
+        }
+        barrier(CLK_LOCAL_MEM_FENCE);
+    }
+}
+
+// Calculates the exclusive prefix sum of the values of all work items of the work group
+// in local memory. SCAN[local_size - 1] contains the sum of all values afterwards.
+inline uint scan_work_group(uint value, __local uint *SCAN) {
+    uint lid = get_local_id(0);
+    uint local_size = get_local_size(0);
+
+    SCAN[lid] = value;
+    barrier(CLK_LOCAL_MEM_FENCE);
+
+    for (uint stride = 1; stride < local_size; stride <<= 1) {
+        uint previous = lid >= stride ? SCAN[lid - stride] : 0;
+        barrier(CLK_LOCAL_MEM_FENCE);
+        SCAN[lid] += previous;
+        barrier(CLK_LOCAL_MEM_FENCE);
+    }
+
+    return SCAN[lid] - value;
+}
+
+__kernel void check_prime_cached(const uint LOWER_PRIME_COUNT, __global const ulong *LOWER_PRIMES, __global const ulong *IN, __global bool *OUT) {
+    uint id = get_global_id(0);
+    OUT[id] = is_prime_cached(IN[id], LOWER_PRIME_COUNT, LOWER_PRIMES);
+}
+
+__kernel void check_prime(__global const ulong *IN, __global bool *OUT) {
+    uint id = get_global_id(0);
+    OUT[id] = is_prime_trial(IN[id]);
+}
+
+// Returns the odd number of the work item beginning with the offset.
+// The work items after the last number get 0 which isn't a prime.
+inline ulong odd_number(const ulong OFFSET, const ulong COUNT) {
+    ulong id = get_global_id(0);
+    return id < COUNT ? OFFSET + 2 * id : 0;
+}
+
+// the odd kernels generate the odd numbers instead of reading them from an input buffer
+__kernel void check_prime_cached_odd(const uint LOWER_PRIME_COUNT, __global const ulong *LOWER_PRIMES, const ulong OFFSET, const ulong COUNT, __global bool *OUT) {
+    OUT[get_global_id(0)] = is_prime_cached(odd_number(OFFSET, COUNT), LOWER_PRIME_COUNT, LOWER_PRIMES);
+}
+
+__kernel void check_prime_odd(const ulong OFFSET, const ulong COUNT, __global bool *OUT) {
+    OUT[get_global_id(0)] = is_prime_trial(odd_number(OFFSET, COUNT));
+}
+
+// the count kernels store the number of primes of every work group
+__kernel void count_prime_cached(const uint LOWER_PRIME_COUNT, __global const ulong *LOWER_PRIMES, __global const ulong *IN, __global uint *OUT, __local uint *COUNTS) {
+    reduce_work_group(is_prime_cached(IN[get_global_id(0)], LOWER_PRIME_COUNT, LOWER_PRIMES), COUNTS);
+    if (get_local_id(0) == 0) {
+        OUT[get_group_id(0)] = COUNTS[0];
+    }
+}
+
+__kernel void count_prime(__global const ulong *IN, __global uint *OUT, __local uint *COUNTS) {
+    reduce_work_group(is_prime_trial(IN[get_global_id(0)]), COUNTS);
+    if (get_local_id(0) == 0) {
+        OUT[get_group_id(0)] = COUNTS[0];
+    }
+}
+
+__kernel void count_prime_cached_odd(const uint LOWER_PRIME_COUNT, __global const ulong *LOWER_PRIMES, const ulong OFFSET, const ulong COUNT, __global uint *OUT, __local uint *COUNTS) {
+    reduce_work_group(is_prime_cached(odd_number(OFFSET, COUNT), LOWER_PRIME_COUNT, LOWER_PRIMES), COUNTS);
+    if (get_local_id(0) == 0) {
+        OUT[get_group_id(0)] = COUNTS[0];
+    }
+}
+
+__kernel void count_prime_odd(const ulong OFFSET, const ulong COUNT, __global uint *OUT, __local uint *COUNTS) {
+    reduce_work_group(is_prime_trial(odd_number(OFFSET, COUNT)), COUNTS);
+    if (get_local_id(0) == 0) {
+        OUT[get_group_id(0)] = COUNTS[0];
+    }
+}
+
+// the compaction kernels write the flagged numbers densely to the output in three passes.
+// The first pass stores the number of flagged numbers of every work group.
+__kernel void count_flags(__global const bool *FLAGS, __global uint *GROUP_COUNTS, __local uint *COUNTS) {
+    reduce_work_group(FLAGS[get_global_id(0)], COUNTS);
+    if (get_local_id(0) == 0) {
+        GROUP_COUNTS[get_group_id(0)] = COUNTS[0];
+    }
+}
+
+// The second pass replaces the counts with the output offsets of the work groups in a single work group.
+// The total count is stored after the offset of the last work group.
+__kernel void scan_group_counts(const uint GROUP_COUNT, __global uint *GROUP_COUNTS, __local uint *SCAN) {
+    uint lid = get_local_id(0);
+    uint local_size = get_local_size(0);
+    uint total = 0;
+
+    for (uint start = 0; start < GROUP_COUNT; start += local_size) {
+        uint index = start + lid;
+        uint count = index < GROUP_COUNT ? GROUP_COUNTS[index] : 0;
+        uint offset = scan_work_group(count, SCAN);
+
+        if (index < GROUP_COUNT) {
+            GROUP_COUNTS[index] = total + offset;
+        }
+        total += SCAN[local_size - 1];
+        barrier(CLK_LOCAL_MEM_FENCE);
+    }
+    if (lid == 0) {
+        GROUP_COUNTS[GROUP_COUNT] = total;
+    }
+}
+
+// The last pass writes every flagged number to the offset of its work group
+// plus the number of flagged numbers before it in the work group.
+__kernel void compact_numbers(__global const ulong *IN, __global const bool *FLAGS, __global const uint *GROUP_OFFSETS, __global ulong *OUT, __local uint *SCAN) {
+    uint id = get_global_id(0);
+    bool flag = FLAGS[id];
+    uint index = GROUP_OFFSETS[get_group_id(0)] + scan_work_group(flag, SCAN);
+
+    if (flag) {
+        OUT[index] = IN[id];
+    }
+}
+
+// Compacts the odd numbers beginning with the offset like 'compact_numbers'
+__kernel void compact_odd_numbers(const ulong OFFSET, __global const bool *FLAGS, __global const uint *GROUP_OFFSETS, __global ulong *OUT, __local uint *SCAN) {
+    uint id = get_global_id(0);
+    bool flag = FLAGS[id];
+    uint index = GROUP_OFFSETS[get_group_id(0)] + scan_work_group(flag, SCAN);
+
+    if (flag) {
+        OUT[index] = OFFSET + 2 * (ulong) id;
+    }
 }
 
 __kernel void bench_int(const uint limit, __global int *NUMBERS) {
@@ -67,4 +211,302 @@ __kernel void bench_int(const uint limit, __global int *NUMBERS) {
         num += i;
     }
     NUMBERS[id] = num;
-}
\ No newline at end of file
+}
+
+// Sieves a segment of odd numbers in local memory with the base primes.
+// Returns the number of numbers in the segment.
+inline uint sieve_local_segment(ulong segment_start, ulong segment_index, const ulong COUNT, const uint SEGMENT_SIZE, const uint BASE_PRIME_COUNT, __global const uint *BASE_PRIMES, __local uchar *SIEVE) {
+    uint lid = get_local_id(0);
+    uint local_size = get_local_size(0);
+    uint segment_len = min((ulong) SEGMENT_SIZE, COUNT - segment_index);
+    ulong segment_end = segment_start + 2 * (segment_len - 1);
+
+    for (uint i = lid; i < segment_len; i += local_size) {
+        SIEVE[i] = 1;
+    }
+    barrier(CLK_LOCAL_MEM_FENCE);
+
+    for (uint j = lid; j < BASE_PRIME_COUNT; j += local_size) {
+        ulong prime = BASE_PRIMES[j];
+        ulong square = prime * prime;
+
+        if (square > segment_end) {
+            break;
+        }
+        ulong start = max(segment_start, square);
+        ulong remainder = start % prime;
+        ulong multiple = start;
+
+        if (remainder != 0) {
+            if (prime - remainder > ULONG_MAX - start) {
+                continue;
+            }
+            multiple = start + (prime - remainder);
+        }
+        if (multiple % 2 == 0) {
+            if (prime > ULONG_MAX - multiple) {
+                continue;
+            }
+            multiple += prime;
+        }
+        for (ulong k = (multiple - segment_start) / 2; k < segment_len; k += prime) {
+            SIEVE[k] = 0;
+        }
+    }
+    barrier(CLK_LOCAL_MEM_FENCE);
+
+    return segment_len;
+}
+
+__kernel void sieve_segment(const ulong OFFSET, const ulong COUNT, const uint SEGMENT_SIZE, const uint BASE_PRIME_COUNT, __global const uint *BASE_PRIMES, __global bool *OUT, __local uchar *SIEVE) {
+    uint lid = get_local_id(0);
+    uint local_size = get_local_size(0);
+    ulong segment_index = (ulong) get_group_id(0) * SEGMENT_SIZE;
+
+    if (segment_index >= COUNT) {
+        return;
+    }
+    ulong segment_start = OFFSET + 2 * segment_index;
+    uint segment_len = sieve_local_segment(segment_start, segment_index, COUNT, SEGMENT_SIZE, BASE_PRIME_COUNT, BASE_PRIMES, SIEVE);
+
+    for (uint i = lid; i < segment_len; i += local_size) {
+        OUT[segment_index + i] = SIEVE[i] == 1 && (segment_start + 2 * i) != 1;
+    }
+}
+
+__kernel void count_sieve_segment(const ulong OFFSET, const ulong COUNT, const uint SEGMENT_SIZE, const uint BASE_PRIME_COUNT, __global const uint *BASE_PRIMES, __global uint *OUT, __local uchar *SIEVE, __local uint *COUNTS) {
+    uint lid = get_local_id(0);
+    uint local_size = get_local_size(0);
+    ulong segment_index = (ulong) get_group_id(0) * SEGMENT_SIZE;
+
+    if (segment_index >= COUNT) {
+        return;
+    }
+    ulong segment_start = OFFSET + 2 * segment_index;
+    uint segment_len = sieve_local_segment(segment_start, segment_index, COUNT, SEGMENT_SIZE, BASE_PRIME_COUNT, BASE_PRIMES, SIEVE);
+    uint count = 0;
+
+    for (uint i = lid; i < segment_len; i += local_size) {
+        count += SIEVE[i] == 1 && (segment_start + 2 * i) != 1;
+    }
+    reduce_work_group(count, COUNTS);
+    if (lid == 0) {
+        OUT[get_group_id(0)] = COUNTS[0];
+    }
+}
+
+// deterministic Miller-Rabin bases for all 64 bit numbers
+__constant ulong MR_BASES[7] = {2, 325, 9375, 28178, 450775, 9780504, 1795265022};
+
+// Montgomery multiplication (a * b * 2^-64 mod n) with the inverse of n mod 2^64
+inline ulong mont_mul(ulong a, ulong b, ulong n, ulong n_inv) {
+    ulong lo = a * b;
+    ulong hi = mul_hi(a, b);
+    ulong m = lo * n_inv;
+    ulong mn_hi = mul_hi(m, n);
+    ulong result = hi - mn_hi;
+
+    if (hi < mn_hi) {
+        result += n;
+    }
+    return result;
+}
+
+// inverse of an odd number mod 2^64 with newton's method
+inline ulong mont_inverse(ulong num) {
+    ulong n_inv = num;
+    for (int i = 0; i < 5; i++) {
+        n_inv *= 2 - num * n_inv;
+    }
+    return n_inv;
+}
+
+inline bool is_prime_mr(ulong num) {
+    if (num < 2) {
+        return false;
+    }
+    if (num % 2 == 0 || num % 3 == 0 || num % 5 == 0 || num % 7 == 0) {
+        return num == 2 || num == 3 || num == 5 || num == 7;
+    }
+
+    ulong n_inv = mont_inverse(num);
+    // 2^64 mod num is the montgomery form of one
+    ulong one = (0 - num) % num;
+    ulong minus_one = num - one;
+    // 2^128 mod num by doubling 2^64 mod num another 64 times
+    ulong r2 = one;
+    for (int i = 0; i < 64; i++) {
+        r2 = r2 >= num - r2 ? r2 - (num - r2) : r2 + r2;
+    }
+
+    ulong d = num - 1;
+    uint s = 0;
+    while (d % 2 == 0) {
+        d /= 2;
+        s++;
+    }
+
+    for (int b = 0; b < 7; b++) {
+        ulong base = MR_BASES[b] % num;
+        if (base == 0) {
+            continue;
+        }
+        ulong x = one;
+        ulong power = mont_mul(base, r2, num, n_inv);
+        for (ulong e = d; e > 0; e >>= 1) {
+            if (e & 1) {
+                x = mont_mul(x, power, num, n_inv);
+            }
+            power = mont_mul(power, power, num, n_inv);
+        }
+        if (x == one || x == minus_one) {
+            continue;
+        }
+        bool composite = true;
+        for (uint r = 1; r < s; r++) {
+            x = mont_mul(x, x, num, n_inv);
+            if (x == minus_one) {
+                composite = false;
+                break;
+            }
+        }
+        if (composite) {
+            return false;
+        }
+    }
+
+    return true;
+}
+
+__kernel void check_prime_mr(__global const ulong *IN, __global bool *OUT) {
+    uint id = get_global_id(0);
+    OUT[id] = is_prime_mr(IN[id]);
+}
+
+__kernel void count_prime_mr(__global const ulong *IN, __global uint *OUT, __local uint *COUNTS) {
+    reduce_work_group(is_prime_mr(IN[get_global_id(0)]), COUNTS);
+    if (get_local_id(0) == 0) {
+        OUT[get_group_id(0)] = COUNTS[0];
+    }
+}
+
+__kernel void check_prime_mr_odd(const ulong OFFSET, const ulong COUNT, __global bool *OUT) {
+    OUT[get_global_id(0)] = is_prime_mr(odd_number(OFFSET, COUNT));
+}
+
+__kernel void count_prime_mr_odd(const ulong OFFSET, const ulong COUNT, __global uint *OUT, __local uint *COUNTS) {
+    reduce_work_group(is_prime_mr(odd_number(OFFSET, COUNT)), COUNTS);
+    if (get_local_id(0) == 0) {
+        OUT[get_group_id(0)] = COUNTS[0];
+    }
+}
+
+// a 64 bit number has at most 64 prime factors
+#define MAX_FACTORS 64
+// how often pollard's rho is restarted with a different polynomial
+#define RHO_ATTEMPTS 8
+// the maximum cycle length that is searched before a polynomial is given up
+#define RHO_MAX_CYCLE (1ul << 24)
+// number of differences that are multiplied before the gcd is calculated
+#define RHO_BATCH 128
+
+inline ulong gcd(ulong a, ulong b) {
+    while (b != 0) {
+        ulong remainder = a % b;
+        a = b;
+        b = remainder;
+    }
+    return a;
+}
+
+// f(y) = y^2 + c with y in montgomery form
+inline ulong rho_step(ulong y, ulong c, ulong num, ulong n_inv) {
+    ulong square = mont_mul(y, y, num, n_inv);
+    return square >= num - c ? square - (num - c) : square + c;
+}
+
+// Pollard's rho with Brent's cycle detection for odd composite numbers.
+// Returns a non-trivial factor or 0 if none was found.
+inline ulong pollard_brent(ulong num) {
+    ulong n_inv = mont_inverse(num);
+
+    for (ulong c = 1; c <= RHO_ATTEMPTS; c++) {
+        ulong y = 2;
+        ulong q = (0 - num) % num;
+        ulong x = y;
+        ulong ys = y;
+        ulong g = 1;
+
+        for (ulong r = 1; g == 1 && r <= RHO_MAX_CYCLE; r *= 2) {
+            x = y;
+            for (ulong i = 0; i < r; i++) {
+                y = rho_step(y, c, num, n_inv);
+            }
+            for (ulong k = 0; k < r && g == 1; k += RHO_BATCH) {
+                ys = y;
+                ulong steps = min((ulong) RHO_BATCH, r - k);
+                for (ulong i = 0; i < steps; i++) {
+                    y = rho_step(y, c, num, n_inv);
+                    q = mont_mul(q, x > y ? x - y : y - x, num, n_inv);
+                }
+                g = gcd(q, num);
+            }
+        }
+        if (g == num) {
+            // the batch contained the factor so the steps are repeated one by one
+            do {
+                ys = rho_step(ys, c, num, n_inv);
+                g = gcd(x > ys ? x - ys : ys - x, num);
+            } while (g == 1);
+        }
+        if (g != 1 && g != num) {
+            return g;
+        }
+    }
+
+    return 0;
+}
+
+// Factors every number by dividing it by the small primes and splitting the rest with pollard's rho.
+// The factors of a number are stored unsorted in MAX_FACTORS values that are terminated by a zero.
+// Composite factors that couldn't be split are stored as they are.
+__kernel void factor(const uint SMALL_PRIME_COUNT, __global const uint *SMALL_PRIMES, __global const ulong *IN, __global ulong *OUT) {
+    uint id = get_global_id(0);
+    ulong num = IN[id];
+    __global ulong *factors = OUT + (ulong) id * MAX_FACTORS;
+    uint count = 0;
+
+    if (num < 2) {
+        return;
+    }
+    for (uint i = 0; i < SMALL_PRIME_COUNT; i++) {
+        ulong prime = SMALL_PRIMES[i];
+        if (prime * prime > num) {
+            break;
+        }
+        while (num % prime == 0) {
+            factors[count++] = prime;
+            num /= prime;
+        }
+    }
+
+    ulong composites[MAX_FACTORS];
+    uint composite_count = 0;
+    if (num > 1) {
+        composites[composite_count++] = num;
+    }
+    while (composite_count > 0) {
+        num = composites[--composite_count];
+        if (is_prime_mr(num)) {
+            factors[count++] = num;
+            continue;
+        }
+        ulong divisor = pollard_brent(num);
+        if (divisor == 0) {
+            factors[count++] = num;
+            continue;
+        }
+        composites[composite_count++] = divisor;
+        composites[composite_count++] = num / divisor;
+    }
+}
@@ -4,75 +4,182 @@
  * See LICENSE for more information
  */
 
-use ocl::core::DeviceInfo;
-use ocl::enums::DeviceInfoResult;
-use ocl::{CommandQueueProperties, ProQue};
-use ocl_stream::OCLStreamExecutor;
+use std::path::PathBuf;
+use std::sync::Arc;
 
+use ocl_stream::stream::OCLStream;
+use ocl_stream::utils::result::OCLStreamResult;
+
+use crate::kernel_controller::backend::cpu::CpuBackend;
+use crate::kernel_controller::backend::{create_backends, ComputeBackend};
+use crate::kernel_controller::executor::BackendContext;
+use crate::utils::args::Backend;
+
+pub mod backend;
 pub mod bench;
+pub mod check;
+pub mod count;
+pub mod executor;
+pub mod factor;
+pub mod prime_cache;
 pub mod primes;
+pub mod validation;
+
+/// Metadata of a device the calculations are executed on.
+/// Capabilities that can't be queried are None.
+#[derive(Clone, Debug)]
+pub struct DeviceInfo {
+    pub index: usize,
+    pub name: String,
+    pub driver_version: String,
+    pub cpu: bool,
+    pub max_work_group_size: Option<usize>,
+    pub max_mem_alloc_size: Option<u64>,
+    pub local_mem_size: Option<u64>,
+}
 
 #[derive(Clone)]
 pub struct KernelController {
-    pro_que: ProQue,
-    executor: OCLStreamExecutor,
+    backends: Vec<Arc<dyn ComputeBackend>>,
+    concurrency: usize,
+    prime_cache_file: Option<PathBuf>,
+    upload_numbers: bool,
 }
 
 impl KernelController {
-    pub fn new() -> ocl::Result<Self> {
-        let pro_que = ProQue::builder()
-            .src(include_str!("kernel.cl"))
-            .dims(1) // won't be used as buffer sizes are declared explicitly
-            .queue_properties(CommandQueueProperties::PROFILING_ENABLE)
-            .build()?;
-        let mut executor = OCLStreamExecutor::new(pro_que.clone());
-        executor.set_concurrency(3);
-        println!("Using device {}", pro_que.device().name()?);
-
-        Ok(Self { pro_que, executor })
+    pub fn new(backend: Backend, platform: Option<&str>, devices: &[String]) -> ocl::Result<Self> {
+        let backends = create_backends(backend, platform, devices)?;
+        if backends.len() > 1 {
+            for (index, backend) in backends.iter().enumerate() {
+                log::info!("Device {}: {}", index, backend.device_name());
+            }
+        }
+
+        Ok(Self {
+            backends,
+            concurrency: 3,
+            prime_cache_file: None,
+            upload_numbers: false,
+        })
     }
 
-    /// Sets the amount of executor threads that are used
-    pub fn set_concurrency(&mut self, concurrency: usize) {
-        self.executor.set_concurrency(concurrency)
+    /// Sets the amount of executor threads that are used per device.
+    /// A value of 0 means that the number of cpu cores should be used.
+    pub fn set_concurrency(&mut self, mut concurrency: usize) {
+        if concurrency == 0 {
+            concurrency = num_cpus::get();
+        }
+        self.concurrency = concurrency;
     }
 
-    /// Prints information about the gpu capabilities
+    /// Adds the cpu as an additional device so that the calculations
+    /// are shared between the cpu and the OpenCL devices.
+    /// Returns false if a selected device already runs on the cpu.
+    pub fn add_cpu_backend(&mut self) -> bool {
+        if self.backends.iter().any(|b| b.is_cpu()) {
+            return false;
+        }
+        self.backends.push(Arc::new(CpuBackend::new()));
+        for (index, backend) in self.backends.iter().enumerate() {
+            log::info!("Device {}: {}", index, backend.device_name());
+        }
+
+        true
+    }
+
+    /// Sets if the numbers that are checked for primality are uploaded to the devices
+    /// instead of being generated by the kernels
+    pub fn set_upload_numbers(&mut self, upload_numbers: bool) {
+        self.upload_numbers = upload_numbers;
+    }
+
+    /// Returns the number of devices the calculations are distributed on
+    pub fn device_count(&self) -> usize {
+        self.backends.len()
+    }
+
+    /// Returns the names of the devices the calculations are executed on
+    pub fn device_name(&self) -> String {
+        self.backends
+            .iter()
+            .map(|b| b.device_name())
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+
+    /// Returns the driver versions of the devices
+    pub fn driver_version(&self) -> String {
+        self.backends
+            .iter()
+            .map(|b| b.driver_version())
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+
+    /// Returns the metadata of every device in the order of the device indices
+    pub fn device_infos(&self) -> Vec<DeviceInfo> {
+        self.backends
+            .iter()
+            .enumerate()
+            .map(|(index, backend)| DeviceInfo {
+                index,
+                name: backend.device_name(),
+                driver_version: backend.driver_version(),
+                cpu: backend.is_cpu(),
+                max_work_group_size: backend.max_work_group_size().ok(),
+                max_mem_alloc_size: backend.max_mem_alloc_size().ok(),
+                local_mem_size: backend.local_mem_size().ok(),
+            })
+            .collect()
+    }
+
+    /// Prints information about the device capabilities
     pub fn print_info(&self) -> ocl::Result<()> {
-        let device = self.pro_que.device();
-        let info_keys = vec![
-            DeviceInfo::Type,
-            DeviceInfo::Vendor,
-            DeviceInfo::DriverVersion,
-            DeviceInfo::ExecutionCapabilities,
-            DeviceInfo::MaxComputeUnits,
-            DeviceInfo::MaxWorkItemSizes,
-            DeviceInfo::MaxWorkItemDimensions,
-            DeviceInfo::MaxWorkGroupSize,
-            DeviceInfo::MaxClockFrequency,
-            DeviceInfo::GlobalMemSize,
-            DeviceInfo::LocalMemSize,
-            DeviceInfo::MaxMemAllocSize,
-            DeviceInfo::LocalMemType,
-            DeviceInfo::GlobalMemCacheType,
-            DeviceInfo::GlobalMemCacheSize,
-            DeviceInfo::OpenclCVersion,
-            DeviceInfo::Platform,
-        ];
-
-        for info in info_keys {
-            println!("{:?}: {}", info, device.info(info)?)
+        for backend in &self.backends {
+            backend.print_info()?;
         }
-        println!();
 
         Ok(())
     }
 
-    #[allow(dead_code)]
-    fn available_memory(&self) -> ocl::Result<u64> {
-        match self.pro_que.device().info(DeviceInfo::GlobalMemSize)? {
-            DeviceInfoResult::GlobalMemSize(size) => Ok(size),
-            _ => Ok(0),
+    /// Returns the maximum local size of the kernel that every device supports
+    pub fn max_local_size(&self, kernel: &str) -> ocl::Result<usize> {
+        let mut max_local_size = usize::MAX;
+        for backend in &self.backends {
+            max_local_size = max_local_size
+                .min(backend.max_work_group_size()?)
+                .min(backend.kernel_work_group_size(kernel)?);
+        }
+
+        Ok(max_local_size)
+    }
+
+    /// Returns the maximum size of a buffer in bytes that every device supports
+    pub fn max_mem_alloc_size(&self) -> ocl::Result<u64> {
+        let mut max_mem_alloc_size = u64::MAX;
+        for backend in &self.backends {
+            max_mem_alloc_size = max_mem_alloc_size.min(backend.max_mem_alloc_size()?);
+        }
+
+        Ok(max_mem_alloc_size)
+    }
+
+    /// Returns the local memory size in bytes that every device supports
+    pub fn local_mem_size(&self) -> ocl::Result<u64> {
+        let mut local_mem_size = u64::MAX;
+        for backend in &self.backends {
+            local_mem_size = local_mem_size.min(backend.local_mem_size()?);
         }
+
+        Ok(local_mem_size)
+    }
+
+    /// Executes a closure on the executor threads with a bounded channel
+    fn execute_bounded<F, T>(&self, size: usize, func: F) -> OCLStream<T>
+    where
+        F: Fn(BackendContext<T>) -> OCLStreamResult<()> + Send + Sync + 'static,
+        T: Send + Sync + 'static,
+    {
+        executor::execute_bounded(self.backends.clone(), self.concurrency, size, func)
     }
 }
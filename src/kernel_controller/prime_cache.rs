@@ -0,0 +1,228 @@
+/*
+ * opencl demos with rust
+ * Copyright (C) 2021 trivernis
+ * See LICENSE for more information
+ */
+
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, ErrorKind, Read, Write};
+use std::mem::size_of;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::kernel_controller::primes::{get_base_primes, integer_sqrt, PRIME_CACHE_LIMIT};
+use crate::kernel_controller::KernelController;
+
+/// Distinguishes the prime caches so that the devices know when to upload a new one
+static NEXT_CACHE_ID: AtomicU64 = AtomicU64::new(0);
+
+/// The primes up to a limit that are used for the divisibility check of the trial division.
+/// The devices upload the primes once and reuse them until a different cache is used.
+#[derive(Debug)]
+pub struct PrimeCache {
+    id: u64,
+    limit: u64,
+    primes: Vec<u64>,
+}
+
+impl PrimeCache {
+    /// Calculates the primes up to the limit on the cpu.
+    /// The 2 is included so that even numbers are rejected as well.
+    pub fn calculate(limit: u64) -> Self {
+        let primes = Some(2)
+            .into_iter()
+            .filter(|_| limit >= 2)
+            .chain(get_base_primes(limit).into_iter().map(u64::from))
+            .collect();
+
+        Self::new(limit, primes)
+    }
+
+    fn new(limit: u64, primes: Vec<u64>) -> Self {
+        Self {
+            id: NEXT_CACHE_ID.fetch_add(1, Ordering::SeqCst),
+            limit,
+            primes,
+        }
+    }
+
+    /// Reads a prime cache file that starts with the limit followed by the primes.
+    /// All numbers are little endian 64 bit integers.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let mut bytes = Vec::new();
+        File::open(path)?.read_to_end(&mut bytes)?;
+        if bytes.len() < size_of::<u64>() || bytes.len() % size_of::<u64>() != 0 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "The prime cache has an invalid length of {} bytes",
+                    bytes.len()
+                ),
+            ));
+        }
+        let mut numbers = bytes
+            .chunks_exact(size_of::<u64>())
+            .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()));
+        let limit = numbers.next().unwrap();
+
+        Ok(Self::new(limit, numbers.collect()))
+    }
+
+    /// Writes the cache into a temporary file and moves it to the given path
+    /// so that other runs never read a partially written cache
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let tmp_path = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        writer.write_all(&self.limit.to_le_bytes())?;
+        for prime in &self.primes {
+            writer.write_all(&prime.to_le_bytes())?;
+        }
+        writer.flush()?;
+
+        fs::rename(tmp_path, path)
+    }
+
+    /// Returns the identifier of the cache
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Returns the number up to which all primes are contained
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    /// Returns the ascending primes of the cache
+    pub fn primes(&self) -> &[u64] {
+        &self.primes
+    }
+}
+
+impl KernelController {
+    /// Sets the file the prime cache is loaded from and stored in to reuse it across runs
+    pub fn set_prime_cache_file(&mut self, path: PathBuf) {
+        self.prime_cache_file = Some(path);
+    }
+
+    /// Returns the prime cache with the primes up to the square root of the max number.
+    /// The cache is loaded from the prime cache file if it contains enough primes and
+    /// stored in the file after it was calculated otherwise.
+    /// Returns None if the primes don't fit into the memory of the devices.
+    pub fn prime_cache(&self, max_number: u64) -> Option<Arc<PrimeCache>> {
+        let limit = integer_sqrt(max_number);
+        let max_buffer_numbers = self.max_mem_alloc_size().unwrap_or(0) / size_of::<u64>() as u64;
+        let max_primes = PRIME_CACHE_LIMIT.min(max_buffer_numbers);
+        // the number of primes up to the limit is always less than the limit
+        if limit > max_primes {
+            log::warn!(
+                "The primes up to {} don't fit into the prime cache of {} primes",
+                limit,
+                max_primes
+            );
+            return None;
+        }
+
+        if let Some(path) = &self.prime_cache_file {
+            match PrimeCache::load(path) {
+                Ok(cache) if cache.limit >= limit && cache.primes.len() as u64 <= max_primes => {
+                    log::info!(
+                        "Loaded {} primes up to {} from the prime cache",
+                        cache.primes.len(),
+                        cache.limit
+                    );
+                    return Some(Arc::new(cache));
+                }
+                Ok(cache) => log::debug!(
+                    "The prime cache contains the primes up to {} but {} are needed",
+                    cache.limit,
+                    limit
+                ),
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => log::warn!("Failed to load the prime cache {:?}: {}", path, e),
+            }
+        }
+        let cache = PrimeCache::calculate(limit);
+        log::debug!(
+            "Calculated {} primes up to {} for the prime cache",
+            cache.primes.len(),
+            limit
+        );
+        if let Some(path) = &self.prime_cache_file {
+            if let Err(e) = cache.save(path) {
+                log::warn!("Failed to store the prime cache {:?}: {}", path, e);
+            }
+        }
+
+        Some(Arc::new(cache))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::args::Backend;
+    use std::process;
+
+    fn cache_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("{}-{}.cache", name, process::id()))
+    }
+
+    fn controller(path: &Path) -> KernelController {
+        let mut controller = KernelController::new(Backend::Cpu, None, &[]).unwrap();
+        controller.set_prime_cache_file(path.to_path_buf());
+
+        controller
+    }
+
+    #[test]
+    fn save_and_load() {
+        let path = cache_path("prime-cache-round-trip");
+        let cache = PrimeCache::calculate(1000);
+        cache.save(&path).unwrap();
+        let loaded = PrimeCache::load(&path).unwrap();
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(loaded.limit(), 1000);
+        assert_eq!(loaded.primes(), cache.primes());
+        assert_eq!(loaded.primes().len(), 168);
+        assert_ne!(loaded.id(), cache.id());
+    }
+
+    #[test]
+    fn corrupted_files_are_replaced() {
+        let path = cache_path("prime-cache-corrupted");
+        for content in &[&[][..], &[1, 2, 3][..], &[0; 12][..]] {
+            fs::write(&path, content).unwrap();
+            assert_eq!(
+                PrimeCache::load(&path).unwrap_err().kind(),
+                ErrorKind::InvalidData
+            );
+
+            let cache = controller(&path).prime_cache(10_000).unwrap();
+            assert_eq!(cache.limit(), 100);
+            assert_eq!(PrimeCache::load(&path).unwrap().primes(), cache.primes());
+        }
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn caches_for_smaller_limits_are_recalculated() {
+        let path = cache_path("prime-cache-limits");
+        PrimeCache::calculate(100).save(&path).unwrap();
+        let controller = controller(&path);
+
+        // a cache up to 100 is enough for numbers up to 10000
+        assert_eq!(controller.prime_cache(10_000).unwrap().limit(), 100);
+        assert_eq!(controller.prime_cache(100).unwrap().limit(), 100);
+
+        let cache = controller.prime_cache(1_000_000).unwrap();
+        assert_eq!(cache.limit(), 1000);
+        assert_eq!(cache.primes().len(), 168);
+        let stored = PrimeCache::load(&path).unwrap();
+        fs::remove_file(path).unwrap();
+        assert_eq!(stored.limit(), 1000);
+        assert_eq!(stored.primes(), cache.primes());
+    }
+}
@@ -4,253 +4,376 @@
  * See LICENSE for more information
  */
 
-use crate::benching::enqueue_profiled;
 use crate::benching::result::ProfiledResult;
+use crate::kernel_controller::backend::Candidates;
 use crate::kernel_controller::KernelController;
+use crate::output::ordered::{Indexed, OrderedStream, ReorderWindow};
 use crate::utils::progress::get_progress_bar;
-use ocl::ProQue;
-use ocl_stream::stream::OCLStream;
-use ocl_stream::traits::ToOclBuffer;
+use ocl_stream::utils::result::OCLStreamError;
 use parking_lot::Mutex;
+use rayon::prelude::*;
 use std::mem::size_of;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::Instant;
-use std_semaphore::Semaphore;
+use std::time::{Duration, Instant};
 
 const MEMORY_LIMIT: u64 = 4 * 1024 * 1024 * 1024;
+/// The maximum number of primes stored in the prime cache
+pub(crate) const PRIME_CACHE_LIMIT: u64 = MEMORY_LIMIT / 4 / size_of::<u64>() as u64;
+const BASE_PRIME_SEGMENT_SIZE: usize = 1024 * 1024;
+/// The maximum number of odd numbers a device calculates at once when it takes several steps
+const MAX_CHUNK_SIZE: usize = 128 * 1024 * 1024;
+/// Weight of the latest measurement in the throughput of a device
+const THROUGHPUT_SMOOTHING: f64 = 0.3;
+
+/// Bases for which the Miller-Rabin test is deterministic for all 64 bit numbers
+const MR_BASES: [u64; 7] = [2, 325, 9375, 28178, 450775, 9780504, 1795265022];
+
+/// The algorithm that is used to find primes
+#[derive(Clone, Copy, Debug)]
+pub enum PrimeAlgorithm {
+    /// Checks every number by dividing it by odd numbers or
+    /// previously calculated primes if the cache is used
+    TrialDivision { use_cache: bool },
+
+    /// Sieves segments of odd numbers with the primes up to sqrt(end).
+    /// The segment size is the amount of numbers sieved by one work group.
+    Sieve { segment_size: usize },
+
+    /// Checks every number with the deterministic Miller-Rabin test
+    MillerRabin,
+}
 
-impl KernelController {
-    /// Calculates prime number on the cpu
-    pub fn calculate_primes_cpu(
-        &mut self,
-        mut start: u64,
-        stop: u64,
-        step: usize,
-    ) -> OCLStream<ProfiledResult<Vec<u64>>> {
-        if start % 2 == 0 {
-            start += 1;
+impl PrimeAlgorithm {
+    /// Returns the name of the kernel that executes the algorithm
+    pub fn kernel_name(&self) -> &'static str {
+        match self {
+            PrimeAlgorithm::TrialDivision { use_cache: true } => "check_prime_cached",
+            PrimeAlgorithm::TrialDivision { use_cache: false } => "check_prime",
+            PrimeAlgorithm::Sieve { .. } => "sieve_segment",
+            PrimeAlgorithm::MillerRabin => "check_prime_mr",
         }
-        log::debug!(
-            "Calculating primes between {} and {} with {} number per step on the cpu",
-            start,
-            stop,
-            step,
-        );
-        let offset = Arc::new(AtomicU64::new(start));
-        let pb = get_progress_bar((stop - start) / (step * 2) as u64);
+    }
 
-        self.executor.execute_bounded(step * 10, move |ctx| {
-            loop {
-                if offset.load(Ordering::SeqCst) >= stop {
-                    log::trace!("Stop reached.");
-                    break;
-                }
-                let offset = offset.fetch_add(step as u64 * 2, Ordering::SeqCst);
-                log::trace!("Calculating {} primes beginning from {}", step, offset);
-                let start = Instant::now();
-
-                let primes = (offset..(step as u64 * 2 + offset))
-                    .step_by(2)
-                    .filter(|n| is_prime(*n))
-                    .collect::<Vec<u64>>();
-
-                ctx.sender()
-                    .send(ProfiledResult::new(start.elapsed(), primes))?;
-                pb.inc(1);
-            }
+    /// Returns the name of the kernel that executes the algorithm
+    /// for odd numbers that are generated on the device
+    pub fn odd_kernel_name(&self) -> &'static str {
+        match self {
+            PrimeAlgorithm::TrialDivision { use_cache: true } => "check_prime_cached_odd",
+            PrimeAlgorithm::TrialDivision { use_cache: false } => "check_prime_odd",
+            PrimeAlgorithm::Sieve { .. } => "sieve_segment",
+            PrimeAlgorithm::MillerRabin => "check_prime_mr_odd",
+        }
+    }
 
-            Ok(())
-        })
+    /// Returns the name of the kernel that counts the primes with the algorithm
+    pub fn count_kernel_name(&self) -> &'static str {
+        match self {
+            PrimeAlgorithm::TrialDivision { use_cache: true } => "count_prime_cached",
+            PrimeAlgorithm::TrialDivision { use_cache: false } => "count_prime",
+            PrimeAlgorithm::Sieve { .. } => "count_sieve_segment",
+            PrimeAlgorithm::MillerRabin => "count_prime_mr",
+        }
+    }
+
+    /// Returns the name of the kernel that counts the primes with the algorithm
+    /// for odd numbers that are generated on the device
+    pub fn odd_count_kernel_name(&self) -> &'static str {
+        match self {
+            PrimeAlgorithm::TrialDivision { use_cache: true } => "count_prime_cached_odd",
+            PrimeAlgorithm::TrialDivision { use_cache: false } => "count_prime_odd",
+            PrimeAlgorithm::Sieve { .. } => "count_sieve_segment",
+            PrimeAlgorithm::MillerRabin => "count_prime_mr_odd",
+        }
     }
+}
 
-    /// Calculates prime numbers on the gpu
+impl KernelController {
+    /// Calculates the prime numbers from start up to the exclusive stop on the compute backends.
+    /// Faster devices take several steps at once depending on their measured throughput.
+    /// The results are emitted per step in ascending order with at most `reorder_buffer`
+    /// steps being buffered.
     pub fn calculate_primes(
-        &self,
+        &mut self,
         mut start: u64,
         stop: u64,
         step: usize,
         local_size: usize,
-        use_cache: bool,
-    ) -> OCLStream<ProfiledResult<Vec<u64>>> {
-        if start % 2 == 0 {
+        mut algorithm: PrimeAlgorithm,
+        reorder_buffer: usize,
+    ) -> OrderedStream<ProfiledResult<Vec<u64>>> {
+        // only odd numbers are checked so the prime 2 is added to the first step
+        let includes_two = start <= 2 && stop > 2;
+        if includes_two {
+            // the range needs to contain a step for the prime 2 even if it ends at 3
+            start = 1;
+        } else if start % 2 == 0 {
             start += 1;
         }
         log::debug!(
-            "Calculating primes between {} and {} with {} number per step and a local size of {}",
+            "Calculating primes between {} and {} with {} number per step, a local size of {} and algorithm {:?}",
             start,
             stop,
             step,
-            local_size
+            local_size,
+            algorithm,
         );
         let offset = Arc::new(AtomicU64::new(start));
-        let prime_cache = Arc::new(Mutex::new(Vec::new()));
-
-        if use_cache {
-            prime_cache
-                .lock()
-                .append(&mut get_primes(start + step as u64));
+        // the last chunk may end after the stop
+        let max_number = stop.saturating_add(step as u64 * 2);
+        let mut prime_cache = None;
+        let mut base_primes = Vec::new();
+
+        match algorithm {
+            PrimeAlgorithm::TrialDivision { use_cache: true } => {
+                prime_cache = self.prime_cache(max_number);
+                if prime_cache.is_none() {
+                    log::warn!("Calculating without cached primes.");
+                    algorithm = PrimeAlgorithm::TrialDivision { use_cache: false };
+                }
+            }
+            PrimeAlgorithm::Sieve { .. } => {
+                base_primes = get_base_primes(integer_sqrt(max_number));
+            }
+            _ => {}
         }
-
-        let pb = get_progress_bar((stop - start) / (step * 2) as u64);
-        let sem = Semaphore::new(1);
-
-        self.executor.execute_bounded(step * 10, move |ctx| {
+        let base_primes = Arc::new(base_primes);
+        // the buffers of combined steps need to fit into the devices.
+        // Steps aren't combined if the limit can't be queried
+        let max_buffer_numbers = self.max_mem_alloc_size().unwrap_or(0) / size_of::<u64>() as u64;
+        let max_chunk_size = (MAX_CHUNK_SIZE as u64).min(max_buffer_numbers) as usize;
+        let max_steps = (max_chunk_size / step).clamp(1, reorder_buffer.max(1));
+        let balancer = LoadBalancer::new(self.device_count(), max_steps);
+        let upload_numbers = self.upload_numbers;
+
+        let step_len = step as u64 * 2;
+        let steps = stop.saturating_sub(start);
+        let pb = get_progress_bar(steps / step_len + (steps % step_len != 0) as u64);
+        let window = ReorderWindow::new(reorder_buffer);
+        let producer_window = Arc::clone(&window);
+
+        let stream = self.execute_bounded(reorder_buffer, move |ctx| {
             loop {
-                let pro_que = ctx.pro_que();
+                let backend = ctx.backend();
                 let sender = ctx.sender();
                 if offset.load(Ordering::SeqCst) >= stop {
                     log::trace!("Stop reached.");
                     break;
                 }
-                let offset = offset.fetch_add(step as u64 * 2, Ordering::SeqCst);
-                log::trace!("Calculating {} primes beginning from {}", step, offset);
-
-                let numbers = (offset..(step as u64 * 2 + offset))
-                    .step_by(2)
-                    .collect::<Vec<u64>>();
-                let result = if use_cache {
-                    let prime_cache = Arc::clone(&prime_cache);
-                    log::trace!("Using optimized function with cached primes");
-                    Self::filter_primes_cached(pro_que, numbers, local_size, prime_cache, &sem)?
-                } else {
-                    log::trace!("Using normal prime calculation function");
-                    Self::filter_primes(pro_que, numbers, local_size, &sem)?
-                };
-                sender.send(result)?;
-                pb.inc(1);
+                let (offset, steps) =
+                    match next_chunk(&offset, step, balancer.steps(ctx.device_id()), stop) {
+                        Some(chunk) => chunk,
+                        None => break,
+                    };
+                let count = step * steps;
+                log::trace!("Calculating {} primes beginning from {}", count, offset);
+                let calc_start = Instant::now();
+
+                let result = match algorithm {
+                    PrimeAlgorithm::TrialDivision { use_cache } => {
+                        let candidates = Candidates::odd_numbers(offset, count, upload_numbers);
+                        match &prime_cache {
+                            Some(prime_cache) if use_cache => {
+                                log::trace!("Using optimized function with cached primes");
+                                backend.check_prime_cached(candidates, local_size, prime_cache)
+                            }
+                            _ => {
+                                log::trace!("Using normal prime calculation function");
+                                backend.check_prime(candidates, local_size)
+                            }
+                        }
+                    }
+                    PrimeAlgorithm::Sieve { segment_size } => {
+                        log::trace!("Using segmented sieve");
+                        let chunk_end = offset.saturating_add(count as u64 * 2);
+                        let prime_count =
+                            base_primes.partition_point(|p| (*p as u64) * (*p as u64) <= chunk_end);
+                        backend.sieve_primes(
+                            offset,
+                            odd_numbers(offset, count).len(),
+                            segment_size,
+                            local_size,
+                            &base_primes[..prime_count],
+                        )
+                    }
+                    PrimeAlgorithm::MillerRabin => {
+                        log::trace!("Using Miller-Rabin test");
+                        let candidates = Candidates::odd_numbers(offset, count, upload_numbers);
+                        backend.check_prime_mr(candidates, local_size)
+                    }
+                }
+                .map_err(|e| {
+                    OCLStreamError::OCLError(format!(
+                        "Calculation of {} numbers beginning from {} on device {} failed: {}",
+                        count,
+                        offset,
+                        ctx.device_id(),
+                        e
+                    ))
+                })?;
+                balancer.record(ctx.device_id(), count, calc_start.elapsed());
+
+                let first_index = step_index(start, offset, step);
+                let duration = *result.duration() / steps as u32;
+                let mut primes = result.into_value();
+                // the last step is cut off at the stop
+                primes.truncate(primes.partition_point(|p| *p < stop));
+                for (index, primes) in split_steps(offset, step, steps, primes)
+                    .into_iter()
+                    .enumerate()
+                {
+                    let mut primes = primes;
+                    if first_index + index == 0 && includes_two {
+                        primes.insert(0, 2);
+                    }
+                    let mut result = ProfiledResult::new(duration, primes);
+                    result.set_device(ctx.device_id());
+                    producer_window.wait_for(first_index + index);
+                    sender.send(Indexed::new(first_index + index, result))?;
+                }
+                pb.inc(steps as u64);
             }
 
             Ok(())
+        });
+
+        OrderedStream::new(stream, window)
+    }
+}
+
+/// Returns the offset and the number of steps of the next chunk and advances the offset.
+/// The chunk is shortened to the steps that begin before the stop.
+fn next_chunk(offset: &AtomicU64, step: usize, steps: usize, stop: u64) -> Option<(u64, usize)> {
+    let step_len = step as u64 * 2;
+    let chunk_steps = |o: u64| {
+        let remaining = stop - o;
+        (remaining / step_len + (remaining % step_len != 0) as u64).min(steps as u64)
+    };
+    let offset = offset
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |o| {
+            if o >= stop {
+                None
+            } else {
+                Some(o.saturating_add(chunk_steps(o).saturating_mul(step_len)))
+            }
         })
+        .ok()?;
+
+    Some((offset, chunk_steps(offset) as usize))
+}
+
+/// Splits the sorted primes of a chunk into the primes of its steps
+fn split_steps(offset: u64, step: usize, steps: usize, mut primes: Vec<u64>) -> Vec<Vec<u64>> {
+    let mut step_primes = Vec::with_capacity(steps);
+
+    for index in (1..steps).rev() {
+        let step_start = offset.saturating_add(index as u64 * step as u64 * 2);
+        let split = primes.partition_point(|p| *p < step_start);
+        step_primes.push(primes.split_off(split));
     }
+    step_primes.push(primes);
+    step_primes.reverse();
 
-    /// Creates the prime filter kernel and executes it
-    fn filter_primes(
-        pro_que: &ProQue,
-        numbers: Vec<u64>,
-        local_size: usize,
-        sem: &Semaphore,
-    ) -> ocl::Result<ProfiledResult<Vec<u64>>> {
-        sem.acquire();
-        log::trace!("Creating 0u8 output buffer");
-        let output_buffer = pro_que
-            .buffer_builder()
-            .len(numbers.len())
-            .fill_val(0u8)
-            .build()?;
-        sem.release();
-
-        sem.acquire();
-        let input_buffer = numbers.to_ocl_buffer(pro_que)?;
-        sem.release();
-
-        sem.acquire();
-        log::trace!("Building 'check_prime' kernel");
-        let kernel = pro_que
-            .kernel_builder("check_prime")
-            .local_work_size(local_size)
-            .arg(&input_buffer)
-            .arg(&output_buffer)
-            .global_work_size(numbers.len())
-            .build()?;
-        sem.release();
-        let duration = enqueue_profiled(pro_que, &kernel, &sem)?;
-
-        log::trace!("Reading output");
-        let mut output = vec![0u8; output_buffer.len()];
-        sem.acquire();
-        output_buffer.read(&mut output).enq()?;
-        sem.release();
-
-        log::trace!("Filtering primes");
-        let primes = map_gpu_prime_result(numbers, output);
-        log::trace!("Calculated {} primes", primes.len());
-
-        Ok(ProfiledResult::new(duration, primes))
-    }
-
-    /// Filters primes by using the primes from previous
-    /// calculations for divisibility checks
-    pub fn filter_primes_cached(
-        pro_que: &ProQue,
-        numbers: Vec<u64>,
-        local_size: usize,
-        prime_cache: Arc<Mutex<Vec<u64>>>,
-        sem: &Semaphore,
-    ) -> ocl::Result<ProfiledResult<Vec<u64>>> {
-        sem.acquire();
-        let prime_buffer = prime_cache.lock().to_ocl_buffer(pro_que)?;
-        sem.release();
-
-        sem.acquire();
-        let input_buffer = numbers.to_ocl_buffer(pro_que)?;
-        sem.release();
-
-        sem.acquire();
-        log::trace!("Creating output buffer");
-        let output_buffer = pro_que
-            .buffer_builder()
-            .len(numbers.len())
-            .fill_val(0u8)
-            .build()?;
-        sem.release();
-
-        log::trace!("Building 'check_prime_cached' kernel");
-        sem.acquire();
-        let kernel = pro_que
-            .kernel_builder("check_prime_cached")
-            .local_work_size(local_size)
-            .arg(prime_buffer.len() as u32)
-            .arg(&prime_buffer)
-            .arg(&input_buffer)
-            .arg(&output_buffer)
-            .global_work_size(numbers.len())
-            .build()?;
-        sem.release();
-
-        let duration = enqueue_profiled(pro_que, &kernel, sem)?;
-
-        log::trace!("Reading output");
-        let mut output = vec![0u8; output_buffer.len()];
-        sem.acquire();
-        output_buffer.read(&mut output).enq()?;
-        sem.release();
-
-        log::trace!("Mapping prime result");
-        let primes = map_gpu_prime_result(numbers, output);
-        log::trace!("Calculated {} primes", primes.len());
-
-        let mut prime_cache = prime_cache.lock();
-
-        log::trace!("Updating prime cache");
-        if (prime_cache.len() + primes.len()) * size_of::<i64>() < MEMORY_LIMIT as usize / 4 {
-            prime_cache.append(&mut primes.clone());
-            prime_cache.sort();
-            prime_cache.dedup();
+    step_primes
+}
+
+/// Keeps track of the throughput of every device to determine
+/// how many steps a device calculates at once.
+/// The slowest device calculates one step at a time.
+struct LoadBalancer {
+    throughputs: Mutex<Vec<f64>>,
+    max_steps: usize,
+}
+
+impl LoadBalancer {
+    fn new(devices: usize, max_steps: usize) -> Self {
+        Self {
+            throughputs: Mutex::new(vec![0f64; devices]),
+            max_steps,
+        }
+    }
+
+    /// Returns the number of steps the device should calculate next
+    fn steps(&self, device: usize) -> usize {
+        let throughputs = self.throughputs.lock();
+        let slowest = throughputs
+            .iter()
+            .cloned()
+            .filter(|t| *t > 0f64)
+            .fold(f64::INFINITY, f64::min);
+
+        if throughputs[device] > 0f64 && slowest.is_finite() {
+            ((throughputs[device] / slowest).round() as usize).clamp(1, self.max_steps)
+        } else {
+            1
         }
+    }
+
+    /// Records the numbers per second of a calculation on the device
+    fn record(&self, device: usize, numbers: usize, duration: Duration) {
+        let throughput = numbers as f64 / duration.as_secs_f64().max(f64::EPSILON);
+        let mut throughputs = self.throughputs.lock();
 
-        Ok(ProfiledResult::new(duration, primes))
+        throughputs[device] = if throughputs[device] > 0f64 {
+            (1f64 - THROUGHPUT_SMOOTHING) * throughputs[device] + THROUGHPUT_SMOOTHING * throughput
+        } else {
+            throughput
+        };
+        log::trace!(
+            "Throughput of device {}: {} numbers/s",
+            device,
+            throughputs[device]
+        );
     }
 }
 
-/// Returns a list of prime numbers that can be used to speed up the divisibility check
-fn get_primes(max_number: u64) -> Vec<u64> {
-    log::trace!("Calculating primes until {} on the cpu", max_number);
-    let start = Instant::now();
-    let mut primes = Vec::with_capacity((max_number as f64).sqrt() as usize);
-    let mut num = 1;
+/// Returns the index of the step that begins with the given offset
+fn step_index(start: u64, offset: u64, step: usize) -> usize {
+    ((offset - start) / (step as u64 * 2)) as usize
+}
 
-    while num < max_number {
-        let is_prime = is_prime(num);
+/// Returns up to `step` odd numbers beginning with the odd offset.
+/// The numbers end early if the maximum u64 is reached.
+pub(crate) fn odd_numbers(offset: u64, step: usize) -> impl ExactSizeIterator<Item = u64> {
+    let count = ((u64::MAX - offset) / 2 + 1).min(step as u64) as usize;
 
-        if is_prime {
-            primes.push(num)
+    (0..count).map(move |i| offset + 2 * i as u64)
+}
+
+/// Returns the odd primes up to the given limit that are used
+/// to sieve numbers up to limit^2
+pub(crate) fn get_base_primes(limit: u64) -> Vec<u32> {
+    log::trace!("Calculating base primes until {} on the cpu", limit);
+    let start = Instant::now();
+    let limit = limit.min(u32::MAX as u64);
+    let small_limit = integer_sqrt(limit) as usize;
+    let mut small_sieve = vec![true; small_limit + 1];
+    let mut small_primes = Vec::new();
+
+    for num in (3..=small_limit).step_by(2) {
+        if small_sieve[num] {
+            small_primes.push(num as u32);
+            for multiple in (num * num..=small_limit).step_by(num * 2) {
+                small_sieve[multiple] = false;
+            }
         }
-        num += 2;
     }
+    let count = if limit >= 3 { (limit - 3) / 2 + 1 } else { 0 };
+    let segments = (count as usize + BASE_PRIME_SEGMENT_SIZE - 1) / BASE_PRIME_SEGMENT_SIZE;
+    let primes = (0..segments)
+        .into_par_iter()
+        .map(|segment| {
+            let index = (segment * BASE_PRIME_SEGMENT_SIZE) as u64;
+            let len = (count - index).min(BASE_PRIME_SEGMENT_SIZE as u64) as usize;
+            sieve_segment(3 + index * 2, len, &small_primes)
+        })
+        .collect::<Vec<Vec<u64>>>()
+        .concat()
+        .into_iter()
+        .map(|p| p as u32)
+        .collect::<Vec<u32>>();
     log::trace!(
-        "Generated {} primes on the cpu in {} ms",
+        "Generated {} base primes on the cpu in {} ms",
         primes.len(),
         start.elapsed().as_secs_f64() * 1000f64,
     );
@@ -258,40 +381,395 @@ fn get_primes(max_number: u64) -> Vec<u64> {
     primes
 }
 
+/// Sieves `count` odd numbers beginning with `start` and returns the primes.
+/// The base primes need to contain all odd primes up to the square root of the last number.
+pub(crate) fn sieve_segment(start: u64, count: usize, base_primes: &[u32]) -> Vec<u64> {
+    if count == 0 {
+        return Vec::new();
+    }
+    let mut sieve = vec![true; count];
+    let end = start + 2 * (count as u64 - 1);
+
+    for prime in base_primes.iter().map(|p| *p as u64) {
+        let square = prime * prime;
+        if square > end {
+            break;
+        }
+        let first = start.max(square);
+        let multiple = first
+            .checked_add((prime - first % prime) % prime)
+            .and_then(|m| if m % 2 == 0 { m.checked_add(prime) } else { Some(m) });
+
+        if let Some(multiple) = multiple {
+            for index in ((((multiple - start) / 2) as usize)..count).step_by(prime as usize) {
+                sieve[index] = false;
+            }
+        }
+    }
+
+    sieve
+        .into_iter()
+        .enumerate()
+        .filter(|(_, is_prime)| *is_prime)
+        .map(|(index, _)| start + 2 * index as u64)
+        .filter(|n| *n != 1)
+        .collect()
+}
+
 /// Checks if a given number is a prime number
 pub(crate) fn is_prime(num: u64) -> bool {
-    let mut is_prime = true;
-
     if num == 2 || num == 3 {
-        is_prime = true;
-    } else if num == 1 || num % 2 == 0 {
-        is_prime = false;
-    } else {
-        let check_stop = (num as f64).sqrt().ceil() as u64;
-
-        if check_stop <= 9 {
-            for i in (3..check_stop).step_by(2) {
-                if num % i == 0 {
-                    is_prime = false;
-                }
+        return true;
+    } else if num <= 1 || num % 2 == 0 || num % 3 == 0 {
+        return false;
+    }
+    let limit = integer_sqrt(num);
+
+    (5..=limit)
+        .step_by(6)
+        .all(|i| num % i != 0 && num % (i + 2) != 0)
+}
+
+/// Checks if a given number is a prime number with the deterministic
+/// Miller-Rabin test. Multiplications are done in montgomery form
+/// so that no overflow can happen for any 64 bit number.
+pub(crate) fn is_prime_mr(num: u64) -> bool {
+    if num < 2 {
+        return false;
+    }
+    if num % 2 == 0 || num % 3 == 0 || num % 5 == 0 || num % 7 == 0 {
+        return num == 2 || num == 3 || num == 5 || num == 7;
+    }
+    let montgomery = Montgomery::new(num);
+    let one = montgomery.one;
+    let minus_one = num - one;
+
+    let mut d = num - 1;
+    let mut s = 0;
+    while d % 2 == 0 {
+        d /= 2;
+        s += 1;
+    }
+
+    'bases: for base in MR_BASES.iter().map(|b| b % num).filter(|b| *b != 0) {
+        let mut x = montgomery.pow(montgomery.to_montgomery(base), d);
+
+        if x == one || x == minus_one {
+            continue;
+        }
+        for _ in 1..s {
+            x = montgomery.mul(x, x);
+            if x == minus_one {
+                continue 'bases;
             }
+        }
+        return false;
+    }
+
+    true
+}
+
+/// Montgomery arithmetic modulo an odd number with R = 2^64
+pub(crate) struct Montgomery {
+    modulus: u64,
+    inverse: u64,
+    one: u64,
+    r2: u64,
+}
+
+impl Montgomery {
+    pub(crate) fn new(modulus: u64) -> Self {
+        // inverse of the modulus mod 2^64 with newton's method
+        let mut inverse = modulus;
+        for _ in 0..5 {
+            inverse = inverse.wrapping_mul(2u64.wrapping_sub(modulus.wrapping_mul(inverse)));
+        }
+        let one = modulus.wrapping_neg() % modulus;
+        let r2 = ((one as u128 * one as u128) % modulus as u128) as u64;
+
+        Self {
+            modulus,
+            inverse,
+            one,
+            r2,
+        }
+    }
+
+    /// Converts the number into montgomery form
+    fn to_montgomery(&self, num: u64) -> u64 {
+        self.mul(num, self.r2)
+    }
+
+    /// Returns the montgomery form of one
+    pub(crate) fn one(&self) -> u64 {
+        self.one
+    }
+
+    /// Multiplies two numbers in montgomery form
+    pub(crate) fn mul(&self, a: u64, b: u64) -> u64 {
+        let product = a as u128 * b as u128;
+        let low = product as u64;
+        let high = (product >> 64) as u64;
+        let m = low.wrapping_mul(self.inverse);
+        let mn_high = ((m as u128 * self.modulus as u128) >> 64) as u64;
+
+        if high < mn_high {
+            high.wrapping_sub(mn_high).wrapping_add(self.modulus)
         } else {
-            for i in (9..(check_stop + 6)).step_by(6) {
-                if num % (i - 2) == 0 || num % (i - 4) == 0 {
-                    is_prime = false;
-                }
+            high - mn_high
+        }
+    }
+
+    /// Raises a number in montgomery form to the given power
+    fn pow(&self, mut base: u64, mut exponent: u64) -> u64 {
+        let mut result = self.one;
+
+        while exponent > 0 {
+            if exponent & 1 == 1 {
+                result = self.mul(result, base);
             }
+            base = self.mul(base, base);
+            exponent >>= 1;
         }
+
+        result
     }
-    is_prime
 }
 
-#[inline]
-pub fn map_gpu_prime_result(input: Vec<u64>, output: Vec<u8>) -> Vec<u64> {
-    input
-        .into_iter()
-        .enumerate()
-        .filter(|(index, _)| output[*index] == 1)
-        .map(|(_, v)| v)
-        .collect::<Vec<u64>>()
+/// Returns the largest integer whose square is less or equal to the given number
+pub(crate) fn integer_sqrt(num: u64) -> u64 {
+    let mut root = (num as f64).sqrt() as u64;
+
+    while root.checked_mul(root).map_or(true, |sq| sq > num) {
+        root -= 1;
+    }
+    while (root + 1).checked_mul(root + 1).map_or(false, |sq| sq <= num) {
+        root += 1;
+    }
+
+    root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::args::Backend;
+
+    /// Strong pseudoprimes to several of the smallest prime bases
+    const STRONG_PSEUDOPRIMES: [u64; 5] = [
+        2047,
+        1373653,
+        3215031751,
+        341550071728321,
+        3825123056546413051,
+    ];
+
+    fn calculate(start: u64, stop: u64, step: usize, algorithm: PrimeAlgorithm) -> Vec<u64> {
+        let mut controller = KernelController::new(Backend::Cpu, None, &[]).unwrap();
+        let mut stream = controller.calculate_primes(start, stop, step, 64, algorithm, 4);
+        let mut primes = Vec::new();
+        loop {
+            match stream.next() {
+                Ok(r) => primes.extend(r.value()),
+                Err(OCLStreamError::RecvError(_)) => return primes,
+                Err(e) => panic!("{}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn stop_is_exclusive() {
+        let algorithms = [
+            PrimeAlgorithm::TrialDivision { use_cache: false },
+            PrimeAlgorithm::Sieve { segment_size: 64 },
+            PrimeAlgorithm::MillerRabin,
+        ];
+        for algorithm in algorithms.iter() {
+            assert_eq!(calculate(0, 7, 64, *algorithm), vec![2, 3, 5]);
+            assert_eq!(calculate(0, 8, 64, *algorithm), vec![2, 3, 5, 7]);
+            assert_eq!(calculate(0, 2, 64, *algorithm), Vec::<u64>::new());
+            assert_eq!(calculate(2, 3, 64, *algorithm), vec![2]);
+            assert_eq!(calculate(3, 4, 64, *algorithm), vec![3]);
+            assert_eq!(calculate(7, 7, 64, *algorithm), Vec::<u64>::new());
+            assert_eq!(calculate(7, 8, 64, *algorithm), vec![7]);
+            // the last step is cut off even though it is calculated completely
+            let primes = calculate(1000, 1300, 64, *algorithm);
+            assert_eq!(primes.last(), Some(&1297));
+            assert_eq!(primes.len(), 43);
+        }
+    }
+
+    #[test]
+    fn chunks_end_at_the_stop() {
+        let offset = AtomicU64::new(1);
+        assert_eq!(next_chunk(&offset, 8, 4, 40), Some((1, 3)));
+        assert_eq!(next_chunk(&offset, 8, 4, 40), None);
+
+        let offset = AtomicU64::new(1);
+        assert_eq!(next_chunk(&offset, 8, 2, 65), Some((1, 2)));
+        assert_eq!(next_chunk(&offset, 8, 2, 65), Some((33, 2)));
+        assert_eq!(next_chunk(&offset, 8, 2, 65), None);
+    }
+
+    #[test]
+    fn chunks_near_u64_max() {
+        let offset = AtomicU64::new(u64::MAX - 10);
+        assert_eq!(
+            next_chunk(&offset, 4, 4, u64::MAX),
+            Some((u64::MAX - 10, 2))
+        );
+        assert_eq!(next_chunk(&offset, 4, 4, u64::MAX), None);
+
+        let offset = AtomicU64::new(u64::MAX - 2);
+        assert_eq!(
+            next_chunk(&offset, 1 << 20, 64, u64::MAX),
+            Some((u64::MAX - 2, 1))
+        );
+        assert_eq!(offset.load(Ordering::SeqCst), u64::MAX);
+
+        let offset = AtomicU64::new(1);
+        assert_eq!(
+            next_chunk(&offset, 1 << 30, usize::MAX, u64::MAX),
+            Some((1, 1 << 33))
+        );
+    }
+
+    #[test]
+    fn fast_devices_take_more_steps() {
+        let balancer = LoadBalancer::new(2, 16);
+        assert_eq!(balancer.steps(0), 1);
+        assert_eq!(balancer.steps(1), 1);
+
+        balancer.record(0, 1000, Duration::from_secs(1));
+        balancer.record(1, 1000, Duration::from_secs(1));
+        assert_eq!(balancer.steps(0), 1);
+        // the smoothed throughput converges to the measurements of the fast device
+        let steps = (0..20)
+            .map(|_| {
+                balancer.record(1, 8000, Duration::from_secs(1));
+                balancer.steps(1)
+            })
+            .collect::<Vec<usize>>();
+        assert!(steps.windows(2).all(|w| w[0] <= w[1]), "{:?}", steps);
+        assert_eq!(steps.last(), Some(&8));
+        assert_eq!(balancer.steps(0), 1);
+    }
+
+    #[test]
+    fn steps_are_limited() {
+        let balancer = LoadBalancer::new(3, 4);
+        balancer.record(0, 1, Duration::from_secs(1));
+        balancer.record(1, 1_000_000, Duration::from_secs(1));
+        // the device without measurements takes single steps
+        assert_eq!(balancer.steps(0), 1);
+        assert_eq!(balancer.steps(1), 4);
+        assert_eq!(balancer.steps(2), 1);
+
+        // a zero duration doesn't divide by zero
+        balancer.record(2, 1000, Duration::default());
+        assert_eq!(balancer.steps(2), 4);
+        assert_eq!(balancer.steps(0), 1);
+    }
+
+    #[test]
+    fn balanced_chunks_do_not_overshoot_the_stop() {
+        let balancer = LoadBalancer::new(2, 8);
+        balancer.record(0, 1000, Duration::from_secs(1));
+        balancer.record(1, 5000, Duration::from_secs(1));
+        let offset = AtomicU64::new(1);
+        let (step, stop) = (8, 201);
+        let mut steps = 0;
+        let mut device = 0;
+        while let Some((chunk_offset, chunk_steps)) =
+            next_chunk(&offset, step, balancer.steps(device), stop)
+        {
+            assert_eq!(chunk_offset, 1 + steps as u64 * 16);
+            // every step of the chunk begins before the stop
+            assert!(chunk_offset + (chunk_steps as u64 - 1) * 16 < stop);
+            steps += chunk_steps;
+            device = (device + 1) % 2;
+        }
+        assert_eq!(steps, 13);
+    }
+
+    #[test]
+    fn miller_rabin_matches_trial_division() {
+        for num in 0..100_000 {
+            assert_eq!(is_prime_mr(num), is_prime(num), "{}", num);
+        }
+    }
+
+    #[test]
+    fn miller_rabin_matches_sieve() {
+        let start = 1_000_000_000_001;
+        let count = 100_000;
+        let base_primes = get_base_primes(integer_sqrt(start + 2 * count as u64));
+        let sieved = sieve_segment(start, count, &base_primes);
+        let tested = odd_numbers(start, count)
+            .filter(|n| is_prime_mr(*n))
+            .collect::<Vec<u64>>();
+
+        assert_eq!(tested, sieved);
+    }
+
+    #[test]
+    fn miller_rabin_rejects_strong_pseudoprimes() {
+        for num in STRONG_PSEUDOPRIMES.iter() {
+            assert!(!is_prime_mr(*num), "{}", num);
+        }
+        assert!(!is_prime(3215031751));
+    }
+
+    #[test]
+    fn miller_rabin_near_u64_max() {
+        let primes = [59, 83, 95, 179, 189, 257, 279, 323, 353, 363];
+
+        for distance in (1..400).step_by(2) {
+            let num = u64::MAX - distance + 1;
+            assert_eq!(
+                is_prime_mr(num),
+                primes.contains(&distance),
+                "2^64 - {}",
+                distance
+            );
+        }
+        assert!(is_prime_mr(18446744073709551557));
+        // the product of the two largest primes below 2^32
+        assert!(!is_prime_mr(4294967291 * 4294967279));
+    }
+
+    #[test]
+    fn montgomery_arithmetic() {
+        // a linear congruential generator for reproducible operands
+        let mut state = 0x2545f4914f6cdd1du64;
+        let mut next = || {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            state
+        };
+
+        for modulus in [3, 97, 4294967291, 18446744073709551557, u64::MAX].iter() {
+            let montgomery = Montgomery::new(*modulus);
+            assert_eq!(montgomery.mul(montgomery.one(), 1), 1 % modulus);
+
+            for _ in 0..1000 {
+                let a = next() % modulus;
+                let b = next() % modulus;
+                let product =
+                    montgomery.mul(montgomery.to_montgomery(a), montgomery.to_montgomery(b));
+                let expected = (a as u128 * b as u128 % *modulus as u128) as u64;
+
+                assert_eq!(montgomery.mul(product, 1), expected, "{} * {}", a, b);
+            }
+        }
+        // fermat's little theorem holds for every base of a prime modulus
+        let montgomery = Montgomery::new(18446744073709551557);
+        for _ in 0..100 {
+            let base = next() % 18446744073709551557;
+            let power = montgomery.pow(montgomery.to_montgomery(base), 18446744073709551556);
+
+            assert_eq!(power == montgomery.one(), base != 0, "{}", base);
+        }
+    }
 }
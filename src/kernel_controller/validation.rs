@@ -0,0 +1,303 @@
+/*
+ * opencl demos with rust
+ * Copyright (C) 2021 trivernis
+ * See LICENSE for more information
+ */
+
+use std::mem::size_of;
+
+use crate::kernel_controller::backend::opencl::COMPACTION_KERNELS;
+use crate::kernel_controller::factor::{FACTOR_KERNEL, MAX_FACTORS};
+use crate::kernel_controller::primes::PrimeAlgorithm;
+use crate::kernel_controller::KernelController;
+use crate::utils::error::{DemoError, DemoResult};
+
+/// The kernel that is used for benchmarks
+pub const BENCH_KERNEL: &str = "bench_int";
+
+impl KernelController {
+    /// Checks the launch parameters of a prime calculation against the limits of the devices.
+    /// Parameters that may be adjusted are reduced until they fit the devices.
+    /// Returns the local size and the numbers per step that should be used.
+    pub fn validate_prime_launch(
+        &self,
+        algorithm: &PrimeAlgorithm,
+        local_size: usize,
+        mut numbers_per_step: usize,
+        adjust_local_size: bool,
+        adjust_numbers_per_step: bool,
+    ) -> DemoResult<(usize, usize)> {
+        if local_size == 0 || numbers_per_step == 0 {
+            return Err(DemoError::Argument(
+                "The local size and the numbers per step must be greater than 0".to_string(),
+            ));
+        }
+        // the primes are compacted on the devices with the same local size
+        let mut kernel = if self.upload_numbers {
+            algorithm.kernel_name()
+        } else {
+            algorithm.odd_kernel_name()
+        };
+        let mut max_local_size = self.max_local_size(kernel)?;
+        for compaction_kernel in COMPACTION_KERNELS.iter() {
+            let compaction_local_size = self.max_local_size(compaction_kernel)?;
+            if compaction_local_size < max_local_size {
+                kernel = compaction_kernel;
+                max_local_size = compaction_local_size;
+            }
+        }
+
+        let local_size = fit_local_size(local_size, max_local_size, adjust_local_size, kernel)?;
+
+        // every number may be a prime so the buffer of the compacted primes has the same length
+        let bytes_per_number = size_of::<u64>() as u64;
+        let max_mem_alloc_size = self.max_mem_alloc_size()?;
+        let max_numbers = (max_mem_alloc_size / bytes_per_number).min(usize::MAX as u64) as usize;
+
+        if numbers_per_step > max_numbers {
+            let adjusted = max_numbers - max_numbers % local_size;
+            if !adjust_numbers_per_step || adjusted == 0 {
+                return Err(DemoError::Argument(format!(
+                    "{} numbers per step need buffers of {} bytes but the devices only support {} bytes per buffer",
+                    numbers_per_step,
+                    numbers_per_step as u64 * bytes_per_number,
+                    max_mem_alloc_size
+                )));
+            }
+            log::warn!(
+                "Reducing the numbers per step from {} to {} to fit the maximum buffer size of {} bytes",
+                numbers_per_step,
+                adjusted,
+                max_mem_alloc_size
+            );
+            numbers_per_step = adjusted;
+        }
+
+        match algorithm {
+            PrimeAlgorithm::TrialDivision { .. } if numbers_per_step % local_size != 0 => {
+                let adjusted = numbers_per_step - numbers_per_step % local_size;
+                if !adjust_numbers_per_step || adjusted == 0 {
+                    return Err(DemoError::Argument(format!(
+                        "The numbers per step {} must be divisible by the local size {}",
+                        numbers_per_step, local_size
+                    )));
+                }
+                log::warn!(
+                    "Reducing the numbers per step from {} to {} to be divisible by the local size {}",
+                    numbers_per_step,
+                    adjusted,
+                    local_size
+                );
+                numbers_per_step = adjusted;
+            }
+            PrimeAlgorithm::Sieve { segment_size: 0 } => {
+                return Err(DemoError::Argument(
+                    "The segment size must be greater than 0".to_string(),
+                ));
+            }
+            PrimeAlgorithm::Sieve { segment_size } => {
+                let local_mem_size = self.local_mem_size()?;
+                if *segment_size as u64 > local_mem_size {
+                    return Err(DemoError::Argument(format!(
+                        "The segment size {} exceeds the local memory size of {} bytes",
+                        segment_size, local_mem_size
+                    )));
+                }
+            }
+            _ => {}
+        }
+
+        Ok((local_size, numbers_per_step))
+    }
+
+    /// Checks the launch parameters of the factorization against the limits of the devices.
+    /// The local size is reduced until it fits the devices if it may be adjusted.
+    /// Returns the local size that should be used.
+    pub fn validate_factor_launch(
+        &self,
+        local_size: usize,
+        batch_size: usize,
+        adjust_local_size: bool,
+    ) -> DemoResult<usize> {
+        if local_size == 0 || batch_size == 0 {
+            return Err(DemoError::Argument(
+                "The local size and the batch size must be greater than 0".to_string(),
+            ));
+        }
+        let max_local_size = self.max_local_size(FACTOR_KERNEL)?;
+        let local_size =
+            fit_local_size(local_size, max_local_size, adjust_local_size, FACTOR_KERNEL)?;
+        let max_mem_alloc_size = self.max_mem_alloc_size()?;
+        // the batch is padded to be divisible by the local size
+        let padded_size = (batch_size + local_size - 1) / local_size * local_size;
+        let buffer_size =
+            (padded_size as u64).saturating_mul((MAX_FACTORS * size_of::<u64>()) as u64);
+
+        if buffer_size > max_mem_alloc_size {
+            return Err(DemoError::Argument(format!(
+                "The batch size {} needs a buffer of {} bytes for the factors but the devices only support {} bytes per buffer",
+                batch_size, buffer_size, max_mem_alloc_size
+            )));
+        }
+
+        Ok(local_size)
+    }
+
+    /// Checks that the benchmark kernel can be executed with the local size
+    /// and global sizes up to the given maximum
+    pub fn validate_bench_launch(
+        &self,
+        local_size: Option<usize>,
+        max_global_size: usize,
+    ) -> DemoResult<()> {
+        if let Some(local_size) = local_size {
+            let max_local_size = self.max_local_size(BENCH_KERNEL)?;
+            if local_size == 0 || local_size > max_local_size {
+                return Err(DemoError::Argument(format!(
+                    "The local size {} must be between 1 and the maximum work group size {} of the kernel '{}'",
+                    local_size, max_local_size, BENCH_KERNEL
+                )));
+            }
+        }
+        if max_global_size == 0 {
+            return Err(DemoError::Argument(
+                "The global size must be greater than 0".to_string(),
+            ));
+        }
+        let max_mem_alloc_size = self.max_mem_alloc_size()?;
+        let buffer_size = max_global_size as u64 * size_of::<u32>() as u64;
+
+        if buffer_size > max_mem_alloc_size {
+            return Err(DemoError::Argument(format!(
+                "The global size {} needs a buffer of {} bytes but the devices only support {} bytes per buffer",
+                max_global_size, buffer_size, max_mem_alloc_size
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks that a range of sizes is ascending and increases with every step
+pub fn validate_range(name: &str, start: usize, step: usize, stop: usize) -> DemoResult<()> {
+    if start == 0 || step == 0 {
+        Err(DemoError::Argument(format!(
+            "The {} start and step must be greater than 0",
+            name
+        )))
+    } else if start > stop {
+        Err(DemoError::Argument(format!(
+            "The {} start {} is greater than the stop {}",
+            name, start, stop
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+/// Returns the local size if it fits into the maximum work group size of the kernel.
+/// A local size that may be adjusted is reduced to the largest power of two that fits.
+fn fit_local_size(
+    local_size: usize,
+    max_local_size: usize,
+    adjust_local_size: bool,
+    kernel: &str,
+) -> DemoResult<usize> {
+    if local_size <= max_local_size {
+        return Ok(local_size);
+    }
+    if !adjust_local_size {
+        return Err(DemoError::Argument(format!(
+            "The local size {} exceeds the maximum work group size {} of the kernel '{}'",
+            local_size, max_local_size, kernel
+        )));
+    }
+    let adjusted = previous_power_of_two(max_local_size);
+    log::warn!(
+        "Reducing the local size from {} to {} to fit the maximum work group size of the kernel '{}'",
+        local_size,
+        adjusted,
+        kernel
+    );
+
+    Ok(adjusted)
+}
+
+/// Returns the largest power of two that is less or equal to the given number
+fn previous_power_of_two(num: usize) -> usize {
+    if num == 0 {
+        0
+    } else {
+        1 << (usize::BITS - 1 - num.leading_zeros())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::args::Backend;
+
+    #[test]
+    fn previous_powers_of_two() {
+        assert_eq!(previous_power_of_two(0), 0);
+        assert_eq!(previous_power_of_two(1), 1);
+        assert_eq!(previous_power_of_two(2), 2);
+        assert_eq!(previous_power_of_two(3), 2);
+        assert_eq!(previous_power_of_two(256), 256);
+        assert_eq!(previous_power_of_two(1000), 512);
+        assert_eq!(previous_power_of_two(usize::MAX), 1 << (usize::BITS - 1));
+    }
+
+    #[test]
+    fn local_sizes_that_fit_are_kept() {
+        assert_eq!(fit_local_size(128, 256, true, "kernel").unwrap(), 128);
+        assert_eq!(fit_local_size(256, 256, false, "kernel").unwrap(), 256);
+        // explicitly given sizes don't need to be powers of two
+        assert_eq!(fit_local_size(96, 1000, false, "kernel").unwrap(), 96);
+    }
+
+    #[test]
+    fn local_sizes_are_adjusted_to_powers_of_two() {
+        assert_eq!(fit_local_size(1024, 256, true, "kernel").unwrap(), 256);
+        // the maximum of the device isn't a power of two
+        assert_eq!(fit_local_size(1024, 1000, true, "kernel").unwrap(), 512);
+        assert_eq!(fit_local_size(128, 96, true, "kernel").unwrap(), 64);
+    }
+
+    #[test]
+    fn explicit_local_sizes_that_do_not_fit_are_rejected() {
+        match fit_local_size(1024, 1000, false, "check_prime") {
+            Err(DemoError::Argument(message)) => {
+                assert!(message.contains("1024"));
+                assert!(message.contains("1000"));
+                assert!(message.contains("'check_prime'"));
+            }
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn cpu_launches_are_not_limited() {
+        let controller = KernelController::new(Backend::Cpu, None, &[]).unwrap();
+        let algorithm = PrimeAlgorithm::TrialDivision { use_cache: true };
+
+        assert_eq!(
+            controller
+                .validate_prime_launch(&algorithm, 96, 960, false, false)
+                .unwrap(),
+            (96, 960)
+        );
+        assert!(controller
+            .validate_prime_launch(&algorithm, 96, 1000, false, false)
+            .is_err());
+        assert_eq!(
+            controller
+                .validate_prime_launch(&algorithm, 96, 1000, false, true)
+                .unwrap(),
+            (96, 960)
+        );
+        assert!(controller
+            .validate_prime_launch(&algorithm, 0, 960, true, true)
+            .is_err());
+    }
+}
@@ -12,150 +12,1012 @@ mod kernel_controller;
 mod output;
 mod utils;
 
-use std::fs::{File, OpenOptions};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
 use std::io;
-use std::io::BufWriter;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
 use std::path::PathBuf;
-use std::time::Duration;
+use std::process;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
 use ocl_stream::stream::OCLStream;
-use ocl_stream::utils::result::{OCLStreamError, OCLStreamResult};
+use ocl_stream::utils::result::OCLStreamError;
+use parking_lot::Mutex;
 use rayon::prelude::*;
 
 use structopt::StructOpt;
-use utils::args::{BenchGlobalSize, BenchLocalSize, CalculatePrimes, Opts};
+use utils::args::{
+    BenchGlobalSize, BenchGrid, BenchLocalSize, BenchOptions, CalculatePrimes, Command,
+    CountPrimes, Factor, IsPrime, NumberInputOptions, Opts, ReadPrimes, Tune,
+};
 
+use crate::benching::statistics::{AggregatedBenchStatistics, BenchAggregator};
+use crate::kernel_controller::backend::opencl::print_devices;
 use crate::kernel_controller::bench::BenchStatistics;
-use crate::kernel_controller::primes::is_prime;
-use crate::kernel_controller::KernelController;
-use crate::output::csv::ThreadedCSVWriter;
-use crate::output::threaded::ThreadedWriter;
-use crate::utils::args::UseColors;
+use crate::kernel_controller::factor::Factorization;
+use crate::kernel_controller::primes::{integer_sqrt, is_prime, PrimeAlgorithm};
+use crate::kernel_controller::validation::validate_range;
+use crate::kernel_controller::{DeviceInfo, KernelController};
+use crate::output::binary::{finish_prime_file, BinaryFormat, PrimeEncoder};
+use crate::output::csv::{record_columns, CsvReader, CsvRecord, ThreadedCSVWriter, Timestamped};
+use crate::output::json::{JsonObject, JsonRecord, JsonSummaryWriter, ThreadedJsonWriter};
+use crate::output::reader::PrimeFileReader;
+use crate::output::records::{CountTiming, PrimeTiming, RecordWriter};
+use crate::output::text::{format_numbers, push_u64};
+use crate::output::threaded::{FlushPolicy, ThreadedWriter, WriterOptions};
+use crate::utils::args::{
+    Algorithm, Backend, InputFormat, OutputFormat, RecordFormat, Stepping, UseColors,
+};
+use crate::utils::checkpoint::{Checkpoint, CheckpointParameters};
+use crate::utils::error::{DemoError, DemoResult};
 use crate::utils::logging::init_logger;
+use crate::utils::numbers::{NumberBatch, NumberFormat, NumberReader};
+use crate::utils::profile::{DeviceProfile, DeviceProfiles};
 use chrono::Local;
+use crossbeam_channel::Receiver;
 use log::LevelFilter;
 
-fn main() -> OCLStreamResult<()> {
+const DEFAULT_LOCAL_SIZE: usize = 128;
+const DEFAULT_NUMBERS_PER_STEP: usize = 33554432;
+const DEFAULT_THREADS: usize = 2;
+const DEFAULT_REORDER_BUFFER: usize = 16;
+
+/// Writes the JSON document with the summary of a run into a file
+type SummaryWriter = JsonSummaryWriter<BufWriter<File>>;
+
+fn main() {
     let opts: Opts = Opts::from_args();
-    let controller = KernelController::new()?;
     init_logger();
 
-    match opts {
-        Opts::Info => controller.print_info().map_err(OCLStreamError::from),
-        Opts::CalculatePrimes(prime_opts) => calculate_primes(prime_opts, controller),
-        Opts::BenchGlobalSize(bench_opts) => bench_global_size(bench_opts, controller),
-        Opts::BenchLocalSize(bench_opts) => bench_local_size(bench_opts, controller),
+    if let Err(e) = run(opts) {
+        log::error!("{}", e);
+        process::exit(e.exit_code());
+    }
+}
+
+/// Executes the given command
+fn run(opts: Opts) -> DemoResult<()> {
+    if let Command::ReadPrimes(read_opts) = &opts.command {
+        // prime files are read without a device
+        return read_primes(read_opts);
+    }
+    let backend = match &opts.command {
+        Command::CalculatePrimes(prime_opts) if prime_opts.use_cpu => Backend::Cpu,
+        _ => opts.backend.clone(),
+    };
+    let controller = KernelController::new(backend, opts.platform.as_deref(), &opts.devices)?;
+    if controller.device_count() > 1
+        && !matches!(
+            opts.command,
+            Command::CalculatePrimes(_)
+                | Command::CountPrimes(_)
+                | Command::IsPrime(_)
+                | Command::Factor(_)
+                | Command::Info
+        )
+    {
+        return Err(DemoError::Argument(
+            "Multiple devices are only supported by calculate-primes, count-primes, is-prime and factor"
+                .to_string(),
+        ));
+    }
+
+    match opts.command {
+        Command::Info => print_info(controller),
+        Command::CalculatePrimes(prime_opts) => calculate_primes(prime_opts, controller),
+        Command::CountPrimes(count_opts) => count_primes(count_opts, controller),
+        Command::BenchGlobalSize(bench_opts) => bench_global_size(bench_opts, controller),
+        Command::BenchLocalSize(bench_opts) => bench_local_size(bench_opts, controller),
+        Command::BenchGrid(bench_opts) => bench_grid(bench_opts, controller),
+        Command::Tune(tune_opts) => tune(tune_opts, controller),
+        Command::IsPrime(is_prime_opts) => check_numbers(is_prime_opts, controller),
+        Command::Factor(factor_opts) => factor_numbers(factor_opts, controller),
+        Command::ReadPrimes(_) => unreachable!(),
+    }
+}
+
+/// Prints all OpenCL devices followed by the selected device
+fn print_info(controller: KernelController) -> DemoResult<()> {
+    if let Err(e) = print_devices() {
+        log::warn!("Failed to list OpenCL devices: {}", e);
+        controller.print_info()?;
+    }
+    println!("Selected device: {}", controller.device_name());
+
+    Ok(())
+}
+
+/// Verifies a binary prime file and answers the queries of the options with its primes
+fn read_primes(read_opts: &ReadPrimes) -> DemoResult<()> {
+    let mut reader = PrimeFileReader::open(&read_opts.input_file)?;
+    if !reader.verify()? {
+        return Err(DemoError::Validation(format!(
+            "The checksum of {:?} doesn't match its content",
+            read_opts.input_file
+        )));
+    }
+    let header = reader.header().clone();
+    let algorithm = Algorithm::variants()
+        .get(header.algorithm as usize)
+        .copied()
+        .unwrap_or("Unknown");
+    log::info!(
+        "{:?}: {} primes in the range {}..{} calculated with {} ({:?} format)",
+        read_opts.input_file,
+        header.count,
+        header.start,
+        header.end,
+        algorithm,
+        header.format
+    );
+
+    for &number in &read_opts.numbers {
+        println!("{} {}", number, reader.is_prime(number)?);
     }
+    let range = read_opts.start.unwrap_or(header.start)..read_opts.end.unwrap_or(header.end);
+    if read_opts.count {
+        println!("{}", reader.count(range.clone())?);
+    }
+    if read_opts.print {
+        let stdout = io::stdout();
+        let mut output = BufWriter::new(stdout.lock());
+        let mut line = Vec::new();
+
+        for prime in reader.primes()? {
+            let prime = prime?;
+            if prime >= range.end {
+                break;
+            }
+            if prime >= range.start {
+                line.clear();
+                push_u64(&mut line, prime);
+                line.push(b'\n');
+                output.write_all(&line)?;
+            }
+        }
+        output.flush()?;
+    }
+
+    Ok(())
 }
 
-/// Calculates primes on the GPU
+/// Calculates primes on the selected backend
 fn calculate_primes(
     prime_opts: CalculatePrimes,
     mut controller: KernelController,
-) -> OCLStreamResult<()> {
+) -> DemoResult<()> {
     let use_stdout = prime_opts.general_options.stdout;
     if use_stdout {
         log::set_max_level(LevelFilter::Warn);
     }
     set_output_colored(prime_opts.general_options.color);
-    controller.set_concurrency(prime_opts.general_options.threads);
 
-    let csv_file = open_write_buffered(&prime_opts.timings_file);
-    let mut csv_writer =
-        ThreadedCSVWriter::new(csv_file, &["timestamp", "first", "count", "duration"]);
-
-    let output_writer = if use_stdout {
-        ThreadedWriter::new(io::stdout(), |v: Vec<u64>| {
-            v.iter()
-                .map(|v| v.to_string())
-                .fold("".to_string(), |a, b| format!("{}\n{}", a, b))
-                .into_bytes()
-        })
+    let profiles = DeviceProfiles::load(&prime_opts.profiles_file)?;
+    let profile = if prime_opts.use_cpu {
+        None
     } else {
-        let output_file = open_write_buffered(&prime_opts.output_file);
-
-        ThreadedWriter::new(output_file, |v: Vec<u64>| {
-            v.iter()
-                .map(|v| v.to_string())
-                .fold("".to_string(), |a, b| format!("{}\n{}", a, b))
-                .into_bytes()
-        })
+        profiles.get(
+            &controller.device_name(),
+            &controller.driver_version(),
+            &prime_opts.algorithm.to_string(),
+        )
     };
+    if let Some(profile) = profile {
+        log::info!(
+            "Found tuned launch parameters: local size {}, {} numbers per step, {} threads",
+            profile.local_size,
+            profile.numbers_per_step,
+            profile.threads
+        );
+    }
+    let local_size = prime_opts
+        .local_size
+        .or_else(|| profile.map(|p| p.local_size))
+        .unwrap_or(DEFAULT_LOCAL_SIZE);
+    let numbers_per_step = prime_opts
+        .numbers_per_step
+        .or_else(|| profile.map(|p| p.numbers_per_step))
+        .unwrap_or(DEFAULT_NUMBERS_PER_STEP);
+    let threads = prime_opts
+        .general_options
+        .threads
+        .or_else(|| profile.map(|p| p.threads))
+        .unwrap_or(DEFAULT_THREADS);
+    controller.set_concurrency(threads);
+    controller.set_upload_numbers(prime_opts.upload_numbers);
+    if let Some(path) = prime_opts.prime_cache_file.clone() {
+        controller.set_prime_cache_file(path);
+    }
+    if prime_opts.hybrid && !controller.add_cpu_backend() {
+        log::warn!("The selected device already runs on the cpu. Ignoring --hybrid.");
+    }
+    if prime_opts.start_offset > prime_opts.max_number {
+        return Err(DemoError::Argument(format!(
+            "The start {} is greater than the end {}",
+            prime_opts.start_offset, prime_opts.max_number
+        )));
+    }
+    let algorithm = prime_algorithm(
+        &prime_opts.algorithm,
+        !prime_opts.no_cache,
+        prime_opts.segment_size,
+    );
+    let (local_size, numbers_per_step) = controller.validate_prime_launch(
+        &algorithm,
+        local_size,
+        numbers_per_step,
+        prime_opts.local_size.is_none(),
+        prime_opts.numbers_per_step.is_none(),
+    )?;
 
-    let mut stream = if prime_opts.use_cpu {
-        controller.calculate_primes_cpu(
-            prime_opts.start_offset,
-            prime_opts.max_number,
-            prime_opts.numbers_per_step,
+    let checkpoint_parameters = CheckpointParameters {
+        end: prime_opts.max_number,
+        algorithm: prime_opts.algorithm.to_string(),
+        numbers_per_step,
+        output_format: prime_opts.output_format.to_string(),
+        timings_format: prime_opts.timings_format.to_string(),
+        // the cache covers the divisors of the last step that may end after the end
+        cache_limit: match algorithm {
+            PrimeAlgorithm::TrialDivision { use_cache: true } => integer_sqrt(
+                prime_opts
+                    .max_number
+                    .saturating_add(numbers_per_step as u64 * 2),
+            ),
+            _ => 0,
+        },
+    };
+    let checkpoint = if prime_opts.resume {
+        let checkpoint = Checkpoint::load(&prime_opts.checkpoint_file)?;
+        checkpoint.verify(&checkpoint_parameters)?;
+        log::info!("Resuming from offset {}", checkpoint.offset);
+        checkpoint
+    } else {
+        Checkpoint {
+            offset: prime_opts.start_offset,
+            ..Default::default()
+        }
+    };
+    let start = checkpoint.offset;
+    let binary_format = match prime_opts.output_format {
+        OutputFormat::Text => None,
+        OutputFormat::Bitmap => Some(BinaryFormat::Bitmap),
+        OutputFormat::DeltaVarint => Some(BinaryFormat::Varint),
+    };
+    if binary_format.is_some() && (use_stdout || prime_opts.resume) {
+        return Err(DemoError::Argument(
+            "The binary output formats can't be written to stdout or resumed".to_string(),
+        ));
+    }
+    let encoder =
+        binary_format.map(|format| Arc::new(Mutex::new(PrimeEncoder::new(format, start))));
+    let record_errors = prime_opts.record_errors;
+    let parameters = JsonObject::new()
+        .field("command", "calculate-primes")
+        .field("start", start)
+        .field("end", prime_opts.max_number)
+        .field("numbers_per_step", numbers_per_step)
+        .field("local_size", local_size)
+        .field("algorithm", prime_opts.algorithm.to_string())
+        .field("use_cache", !prime_opts.no_cache)
+        .field("segment_size", prime_opts.segment_size)
+        .field("upload_numbers", prime_opts.upload_numbers)
+        .field("threads", threads)
+        .field("use_cpu", prime_opts.use_cpu)
+        .field("hybrid", prime_opts.hybrid)
+        .field("output_format", prime_opts.output_format.to_string())
+        .field("reorder_buffer", prime_opts.reorder_buffer)
+        .field("resume", prime_opts.resume);
+    let summary_writer = match &prime_opts.summary_file {
+        Some(path) => Some(summary_writer(
+            path,
+            parameters.clone(),
+            &controller.device_infos(),
+        )?),
+        None => None,
+    };
+    let options = writer_options(
+        prime_opts.flush,
+        prime_opts.write_queue,
+        prime_opts.write_memory,
+    );
+    let mut timings_writer = if prime_opts.resume {
+        if let RecordFormat::Csv = prime_opts.timings_format {
+            check_csv_columns(
+                &prime_opts.timings_file,
+                &record_columns::<Timestamped<PrimeTiming>>(record_errors),
+            )?;
+        }
+        record_writer::<Timestamped<PrimeTiming>, _>(
+            open_append_buffered(&prime_opts.timings_file, checkpoint.timings_len)?,
+            &prime_opts.timings_format,
+            true,
+            record_errors,
+            parameters,
+            &controller.device_infos(),
+            options,
         )
     } else {
-        controller.calculate_primes(
-            prime_opts.start_offset,
-            prime_opts.max_number,
-            prime_opts.numbers_per_step,
-            prime_opts.local_size.unwrap_or(128),
-            !prime_opts.no_cache,
+        record_writer::<Timestamped<PrimeTiming>, _>(
+            open_write_buffered(&prime_opts.timings_file)?,
+            &prime_opts.timings_format,
+            false,
+            record_errors,
+            parameters,
+            &controller.device_infos(),
+            options,
         )
     };
-    while let Ok(r) = stream.next() {
-        let primes = r.value();
+
+    let output_writer = if use_stdout {
+        ThreadedWriter::with_options(io::stdout(), options, format_numbers)
+    } else {
+        let output_file = if prime_opts.resume {
+            open_append_buffered(&prime_opts.output_file, checkpoint.output_len)?
+        } else {
+            open_write_buffered(&prime_opts.output_file)?
+        };
+
+        if let Some(encoder) = &encoder {
+            let encoder = Arc::clone(encoder);
+            ThreadedWriter::with_options(output_file, options, move |v: Vec<u64>, output| {
+                output.append(&mut encoder.lock().encode(&v))
+            })
+        } else {
+            ThreadedWriter::with_options(output_file, options, format_numbers)
+        }
+    };
+
+    let mut stream = controller.calculate_primes(
+        start,
+        prime_opts.max_number,
+        numbers_per_step,
+        local_size,
+        algorithm,
+        prime_opts.reorder_buffer,
+    );
+    let mut device_steps: BTreeMap<usize, (usize, Duration)> = BTreeMap::new();
+    let mut offset = start;
+    let mut prime_count = 0u64;
+    let calc_start = Instant::now();
+    let mut failure = None;
+    loop {
+        let r = match stream.next() {
+            Ok(r) => r,
+            Err(OCLStreamError::RecvError(_)) => break,
+            Err(e) => {
+                log::error!("Stopping the calculation at the offset {}: {}", offset, e);
+                if record_errors {
+                    timings_writer.add_error(&e)?;
+                }
+                failure = Some(e);
+                break;
+            }
+        };
+        let duration = *r.duration();
+        let device = r.device();
+        let primes = r.into_value();
         if prime_opts.cpu_validate {
-            validate_primes_on_cpu(primes);
+            validate_primes_on_cpu(&primes)?;
         }
-        let first = *primes.first().unwrap(); // if there's none, rip
         log::debug!(
-            "Calculated {} primes in {:?}, offset: {}",
+            "Calculated {} primes in {:?}, offset: {}, queued steps: {}",
             primes.len(),
+            duration,
+            offset,
+            output_writer.metrics().queue_depth
+        );
+        timings_writer.add_record(&Timestamped::now(PrimeTiming {
+            first: primes.first().copied(),
+            count: primes.len(),
+            duration,
+            device,
+        }))?;
+        prime_count += primes.len() as u64;
+        output_writer.write(primes)?;
+        let device_stats = device_steps
+            .entry(device)
+            .or_insert((0, Duration::default()));
+        device_stats.0 += 1;
+        device_stats.1 += duration;
+
+        // the last step ends with the end of the range
+        offset = offset
+            .saturating_add(numbers_per_step as u64 * 2)
+            .min(prime_opts.max_number);
+        if !use_stdout && encoder.is_none() {
+            output_writer.flush()?;
+            timings_writer.flush()?;
+            Checkpoint {
+                offset,
+                output_len: file_len(&prime_opts.output_file),
+                timings_len: file_len(&prime_opts.timings_file),
+                parameters: checkpoint_parameters.clone(),
+            }
+            .save(&prime_opts.checkpoint_file)?;
+        }
+    }
+    let elapsed = calc_start.elapsed();
+    if let Some(summary_writer) = summary_writer {
+        summary_writer.write(
+            JsonObject::new()
+                .field("offset", offset)
+                .field(
+                    "steps",
+                    device_steps.values().map(|(steps, _)| steps).sum::<usize>(),
+                )
+                .field("primes", prime_count)
+                .field("duration", elapsed.as_secs_f64() * 1000f64)
+                .field(
+                    "device_steps",
+                    device_steps
+                        .iter()
+                        .map(|(device, (steps, duration))| {
+                            JsonObject::new()
+                                .field("device", *device)
+                                .field("steps", *steps)
+                                .field("duration", duration.as_secs_f64() * 1000f64)
+                        })
+                        .collect::<Vec<JsonObject>>(),
+                )
+                .field("error", failure.as_ref().map(|e| e.to_string())),
+        )?;
+    }
+    timings_writer.close()?;
+    output_writer.close()?;
+    if controller.device_count() > 1 {
+        let mut total_steps = 0;
+        for (device, (steps, duration)) in device_steps {
+            log::info!(
+                "Device {} calculated {} steps in {} ms ({:.0} numbers/s)",
+                device,
+                steps,
+                duration.as_secs_f64() * 1000f64,
+                (steps * numbers_per_step * 2) as f64 / duration.as_secs_f64()
+            );
+            total_steps += steps;
+        }
+        log::info!(
+            "Calculated {} steps in {} ms ({:.0} numbers/s combined)",
+            total_steps,
+            elapsed.as_secs_f64() * 1000f64,
+            (total_steps * numbers_per_step * 2) as f64 / elapsed.as_secs_f64()
+        );
+    }
+
+    if let Some(encoder) = encoder {
+        let header = finish_prime_file(
+            &prime_opts.output_file,
+            &mut encoder.lock(),
+            offset,
+            prime_opts.algorithm as u8,
+        )?;
+        log::info!(
+            "Wrote {} primes in the range {}..{}",
+            header.count,
+            header.start,
+            header.end
+        );
+    }
+
+    match failure {
+        Some(e) => Err(e.into()),
+        None => Ok(()),
+    }
+}
+
+/// Counts the primes in the range and writes the count of every step into the timings file
+fn count_primes(opts: CountPrimes, mut controller: KernelController) -> DemoResult<()> {
+    let use_stdout = opts.general_options.stdout;
+    if use_stdout {
+        log::set_max_level(LevelFilter::Warn);
+    }
+    set_output_colored(opts.general_options.color);
+    controller.set_concurrency(opts.general_options.threads.unwrap_or(DEFAULT_THREADS));
+    controller.set_upload_numbers(opts.upload_numbers);
+    if let Some(path) = opts.prime_cache_file.clone() {
+        controller.set_prime_cache_file(path);
+    }
+
+    if opts.start_offset > opts.max_number {
+        return Err(DemoError::Argument(format!(
+            "The start {} is greater than the end {}",
+            opts.start_offset, opts.max_number
+        )));
+    }
+    let algorithm = prime_algorithm(&opts.algorithm, !opts.no_cache, opts.segment_size);
+    let (local_size, numbers_per_step) = controller.validate_prime_launch(
+        &algorithm,
+        opts.local_size.unwrap_or(DEFAULT_LOCAL_SIZE),
+        opts.numbers_per_step.unwrap_or(DEFAULT_NUMBERS_PER_STEP),
+        opts.local_size.is_none(),
+        opts.numbers_per_step.is_none(),
+    )?;
+
+    let record_errors = opts.record_errors;
+    let csv_columns = record_columns::<Timestamped<CountTiming>>(record_errors);
+    let mut csv_writer = if use_stdout {
+        ThreadedCSVWriter::new(io::stdout(), &csv_columns, WriterOptions::default())
+    } else {
+        ThreadedCSVWriter::new(
+            open_write_buffered(&opts.timings_file)?,
+            &csv_columns,
+            WriterOptions::default(),
+        )
+    };
+    let mut stream = controller.count_primes(
+        opts.start_offset,
+        opts.max_number,
+        numbers_per_step,
+        local_size,
+        algorithm,
+        opts.reorder_buffer,
+    );
+    let mut offset = opts.start_offset | 1;
+    let mut total = 0u64;
+    let calc_start = Instant::now();
+    let mut failure = None;
+    loop {
+        let r = match stream.next() {
+            Ok(r) => r,
+            Err(OCLStreamError::RecvError(_)) => break,
+            Err(e) => {
+                log::error!("Stopping the count at the offset {}: {}", offset, e);
+                if record_errors {
+                    csv_writer.add_error(&e)?;
+                }
+                failure = Some(e);
+                break;
+            }
+        };
+        total += *r.value();
+        log::debug!(
+            "Counted {} primes in {:?}, offset: {}",
+            r.value(),
             r.duration(),
-            first
+            offset
+        );
+        csv_writer.add_record(&Timestamped::now(CountTiming {
+            offset,
+            count: *r.value(),
+            total,
+            duration: *r.duration(),
+            device: r.device(),
+        }))?;
+        offset = offset.saturating_add(numbers_per_step as u64 * 2);
+    }
+    csv_writer.close()?;
+
+    match failure {
+        Some(e) => Err(e.into()),
+        None => {
+            log::info!(
+                "Counted {} primes between {} and {} in {} ms",
+                total,
+                opts.start_offset,
+                opts.max_number,
+                calc_start.elapsed().as_secs_f64() * 1000f64
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Returns the prime algorithm with its parameters
+fn prime_algorithm(algorithm: &Algorithm, use_cache: bool, segment_size: usize) -> PrimeAlgorithm {
+    match algorithm {
+        Algorithm::TrialDivision => PrimeAlgorithm::TrialDivision { use_cache },
+        Algorithm::Sieve => PrimeAlgorithm::Sieve { segment_size },
+        Algorithm::MillerRabin => PrimeAlgorithm::MillerRabin,
+    }
+}
+
+/// Checks the numbers of the input for primality and writes
+/// the results in the order of the input
+fn check_numbers(opts: IsPrime, mut controller: KernelController) -> DemoResult<()> {
+    let use_stdout = opts.general_options.stdout;
+    if use_stdout {
+        log::set_max_level(LevelFilter::Warn);
+    }
+    set_output_colored(opts.general_options.color);
+    controller.set_concurrency(opts.general_options.threads.unwrap_or(DEFAULT_THREADS));
+    // arbitrary numbers can't be generated by the kernels
+    controller.set_upload_numbers(true);
+
+    if let Algorithm::Sieve = opts.algorithm {
+        return Err(DemoError::Argument(
+            "The sieve can't be used to check arbitrary numbers".to_string(),
+        ));
+    }
+    let input_opts = &opts.input_options;
+    let algorithm = prime_algorithm(&opts.algorithm, !opts.no_cache, 0);
+    let (local_size, batch_size) = controller.validate_prime_launch(
+        &algorithm,
+        input_opts.local_size.unwrap_or(DEFAULT_LOCAL_SIZE),
+        input_opts.batch_size,
+        input_opts.local_size.is_none(),
+        false,
+    )?;
+    let (batch_rx, reader_handle) =
+        read_number_batches(input_opts, batch_size, controller.device_count())?;
+
+    let options = input_writer_options(input_opts);
+    let output_writer = if use_stdout {
+        ThreadedWriter::with_options(io::stdout(), options, format_primality)
+    } else {
+        ThreadedWriter::with_options(
+            open_write_buffered(&opts.output_file)?,
+            options,
+            format_primality,
+        )
+    };
+    let mut stream =
+        controller.check_numbers(batch_rx, local_size, algorithm, input_opts.reorder_buffer);
+    let start = Instant::now();
+    let mut prime_count = 0;
+    let mut failure = None;
+    loop {
+        let r = match stream.next() {
+            Ok(r) => r,
+            Err(OCLStreamError::RecvError(_)) => break,
+            Err(e) => {
+                failure = Some(e);
+                break;
+            }
+        };
+        let primes = r.value().iter().filter(|(_, is_prime)| *is_prime).count();
+        log::debug!(
+            "Checked {} numbers with {} primes in {:?}",
+            r.value().len(),
+            primes,
+            r.duration()
         );
-        csv_writer.add_row(vec![
-            Local::now().format("%Y-%m-%dT%H:%M:%S.%f").to_string(),
-            first.to_string(),
-            primes.len().to_string(),
-            duration_to_ms_string(r.duration()),
-        ]);
-        output_writer.write(primes.clone());
+        prime_count += primes;
+        output_writer.write(r.into_value())?;
     }
-    csv_writer.close();
-    output_writer.close();
+    output_writer.close()?;
+    // the reader might still wait for the executors when the calculation failed
+    if let Some(e) = failure {
+        return Err(e.into());
+    }
+    let count = join_number_reader(reader_handle)?;
+    log::info!(
+        "Found {} primes in {} numbers in {} ms",
+        prime_count,
+        count,
+        start.elapsed().as_secs_f64() * 1000f64
+    );
 
     Ok(())
 }
 
+/// Factors the numbers of the input and writes
+/// the results in the order of the input
+fn factor_numbers(opts: Factor, mut controller: KernelController) -> DemoResult<()> {
+    let use_stdout = opts.general_options.stdout;
+    if use_stdout {
+        log::set_max_level(LevelFilter::Warn);
+    }
+    set_output_colored(opts.general_options.color);
+    controller.set_concurrency(opts.general_options.threads.unwrap_or(DEFAULT_THREADS));
+
+    let input_opts = &opts.input_options;
+    let local_size = controller.validate_factor_launch(
+        input_opts.local_size.unwrap_or(DEFAULT_LOCAL_SIZE),
+        input_opts.batch_size,
+        input_opts.local_size.is_none(),
+    )?;
+    let (batch_rx, reader_handle) =
+        read_number_batches(input_opts, input_opts.batch_size, controller.device_count())?;
+
+    let options = input_writer_options(input_opts);
+    let output_writer = if use_stdout {
+        ThreadedWriter::with_options(io::stdout(), options, format_factors)
+    } else {
+        ThreadedWriter::with_options(
+            open_write_buffered(&opts.output_file)?,
+            options,
+            format_factors,
+        )
+    };
+    let mut stream = controller.factor_numbers(batch_rx, local_size, input_opts.reorder_buffer);
+    let start = Instant::now();
+    let mut failure = None;
+    loop {
+        let r = match stream.next() {
+            Ok(r) => r,
+            Err(OCLStreamError::RecvError(_)) => break,
+            Err(e) => {
+                failure = Some(e);
+                break;
+            }
+        };
+        log::debug!("Factored {} numbers in {:?}", r.value().len(), r.duration());
+        output_writer.write(r.into_value())?;
+    }
+    output_writer.close()?;
+    // the reader might still wait for the executors when the calculation failed
+    if let Some(e) = failure {
+        return Err(e.into());
+    }
+    let count = join_number_reader(reader_handle)?;
+    log::info!(
+        "Factored {} numbers in {} ms",
+        count,
+        start.elapsed().as_secs_f64() * 1000f64
+    );
+
+    Ok(())
+}
+
+/// Reads the numbers of the input in batches on a separate thread.
+/// The batches are tagged with their index and the thread returns the number of read numbers.
+fn read_number_batches(
+    opts: &NumberInputOptions,
+    batch_size: usize,
+    capacity: usize,
+) -> io::Result<(Receiver<NumberBatch>, JoinHandle<io::Result<usize>>)> {
+    let input: Box<dyn Read + Send> = match &opts.input_file {
+        Some(path) => Box::new(File::open(path)?),
+        None => Box::new(io::stdin()),
+    };
+    let format = match opts.input_format {
+        InputFormat::Text => NumberFormat::Text,
+        InputFormat::Binary => NumberFormat::Binary,
+    };
+    let mut reader = NumberReader::new(input, format);
+    let (batch_tx, batch_rx) = crossbeam_channel::bounded(capacity);
+    let handle = thread::Builder::new()
+        .name("number-reader".to_string())
+        .spawn(move || {
+            let mut count = 0;
+            for index in 0.. {
+                let batch = reader.read_batch(batch_size)?;
+                if batch.is_empty() {
+                    break;
+                }
+                count += batch.len();
+                if batch_tx.send((index, batch)).is_err() {
+                    break;
+                }
+            }
+
+            Ok(count)
+        })?;
+
+    Ok((batch_rx, handle))
+}
+
+/// Waits for the reader thread and returns the number of read numbers
+fn join_number_reader(handle: JoinHandle<io::Result<usize>>) -> io::Result<usize> {
+    handle
+        .join()
+        .map_err(|_| io::Error::new(io::ErrorKind::Other, "The reader thread panicked"))?
+}
+
+/// Returns the options of a writer with the memory budget given in MiB
+fn writer_options(
+    flush_policy: FlushPolicy,
+    capacity: usize,
+    memory_budget: Option<usize>,
+) -> WriterOptions {
+    WriterOptions {
+        flush_policy,
+        capacity,
+        memory_budget: memory_budget.map(|mib| mib.saturating_mul(1024 * 1024)),
+    }
+}
+
+/// Returns the options of the writer for the results of the numbers that are read
+fn input_writer_options(input_opts: &NumberInputOptions) -> WriterOptions {
+    writer_options(
+        input_opts.flush,
+        input_opts.write_queue,
+        input_opts.write_memory,
+    )
+}
+
+/// Serializes numbers with their primality as comma separated lines
+fn format_primality(values: Vec<(u64, bool)>, output: &mut Vec<u8>) {
+    output.reserve(values.len() * 24);
+    for (number, is_prime) in values {
+        push_u64(output, number);
+        output.extend_from_slice(if is_prime { b",true\n" } else { b",false\n" });
+    }
+}
+
+/// Serializes numbers with their prime factors separated by spaces as comma separated lines
+fn format_factors(values: Vec<Factorization>, output: &mut Vec<u8>) {
+    output.reserve(values.len() * 48);
+    for (number, factors) in values {
+        push_u64(output, number);
+        output.push(b',');
+        for (index, factor) in factors.iter().enumerate() {
+            if index > 0 {
+                output.push(b' ');
+            }
+            push_u64(output, *factor);
+        }
+        output.push(b'\n');
+    }
+}
+
+/// Measures the prime calculation throughput for every combination of launch parameters
+/// and stores the best one in the device profiles
+fn tune(opts: Tune, controller: KernelController) -> DemoResult<()> {
+    let algorithm = prime_algorithm(&opts.algorithm, true, opts.segment_size);
+    let mut best: Option<(f64, DeviceProfile)> = None;
+
+    for threads in &opts.threads {
+        for numbers_per_step in &opts.numbers_per_step {
+            for local_size in &opts.local_sizes {
+                match controller.validate_prime_launch(
+                    &algorithm,
+                    *local_size,
+                    *numbers_per_step,
+                    false,
+                    false,
+                ) {
+                    Ok(_) => {}
+                    Err(DemoError::Argument(e)) => {
+                        log::debug!(
+                            "Skipping local size {} with {} numbers per step: {}",
+                            local_size,
+                            numbers_per_step,
+                            e
+                        );
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                }
+                let throughput = match tune_configuration(
+                    &controller,
+                    opts.start_offset,
+                    opts.steps,
+                    *numbers_per_step,
+                    *local_size,
+                    *threads,
+                    algorithm,
+                ) {
+                    Ok(throughput) => throughput,
+                    Err(e) => {
+                        log::warn!(
+                            "Failed with local size {}, {} numbers per step and {} threads: {}",
+                            local_size,
+                            numbers_per_step,
+                            threads,
+                            e
+                        );
+                        continue;
+                    }
+                };
+                println!(
+                    "local size {}, {} numbers per step, {} threads: {:.0} numbers/s",
+                    local_size, numbers_per_step, threads, throughput
+                );
+
+                if best.as_ref().map_or(true, |(t, _)| throughput > *t) {
+                    best = Some((
+                        throughput,
+                        DeviceProfile {
+                            device: controller.device_name(),
+                            driver_version: controller.driver_version(),
+                            algorithm: opts.algorithm.to_string(),
+                            local_size: *local_size,
+                            numbers_per_step: *numbers_per_step,
+                            threads: *threads,
+                        },
+                    ));
+                }
+            }
+        }
+    }
+
+    match best {
+        Some((throughput, profile)) => {
+            println!(
+                "Best: local size {}, {} numbers per step, {} threads with {:.0} numbers/s",
+                profile.local_size, profile.numbers_per_step, profile.threads, throughput
+            );
+            let mut profiles = DeviceProfiles::load(&opts.profiles_file)?;
+            profiles.insert(profile);
+            profiles.save(&opts.profiles_file)?;
+
+            Ok(())
+        }
+        None => Err(DemoError::Argument(
+            "No valid combination of launch parameters found".to_string(),
+        )),
+    }
+}
+
+/// Calculates the primes of the given number of steps with one combination of launch parameters
+/// and returns the throughput in numbers per second.
+/// The backends are shared with the other combinations so a failed one must not keep them locked.
+fn tune_configuration(
+    controller: &KernelController,
+    start: u64,
+    steps: usize,
+    numbers_per_step: usize,
+    local_size: usize,
+    threads: usize,
+    algorithm: PrimeAlgorithm,
+) -> Result<f64, OCLStreamError> {
+    let mut controller = controller.clone();
+    controller.set_concurrency(threads);
+    let range = (numbers_per_step as u64 * 2).saturating_mul(steps as u64);
+    let mut stream = controller.calculate_primes(
+        start,
+        start.saturating_add(range),
+        numbers_per_step,
+        local_size,
+        algorithm,
+        DEFAULT_REORDER_BUFFER,
+    );
+    let start = Instant::now();
+    let mut steps = 0;
+
+    loop {
+        match stream.next() {
+            Ok(_) => steps += 1,
+            Err(OCLStreamError::RecvError(_)) => break,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok((steps * numbers_per_step * 2) as f64 / start.elapsed().as_secs_f64())
+}
+
 /// Benchmarks the local size used for calculations
-fn bench_local_size(opts: BenchLocalSize, mut controller: KernelController) -> OCLStreamResult<()> {
+fn bench_local_size(opts: BenchLocalSize, mut controller: KernelController) -> DemoResult<()> {
     let use_stdout = opts.bench_options.general_options.stdout;
     if use_stdout {
         log::set_max_level(LevelFilter::Warn);
     }
-    set_output_colored(opts.bench_options.general_options.color);
-    controller.set_concurrency(opts.bench_options.general_options.threads);
+    set_output_colored(opts.bench_options.general_options.color.clone());
+    controller.set_concurrency(
+        opts.bench_options
+            .general_options
+            .threads
+            .unwrap_or(DEFAULT_THREADS),
+    );
+
+    validate_range(
+        "local size",
+        opts.local_size_start,
+        opts.local_size_step,
+        opts.local_size_stop,
+    )?;
+    controller.validate_bench_launch(None, opts.global_size)?;
 
     let bench_output = opts
         .bench_options
         .benchmark_file
+        .clone()
         .unwrap_or(PathBuf::from(format!(
-            "bench_local_{}-{}-{}_g{}_r{}_s{}_{}.csv",
+            "bench_local_{}-{}-{}_g{}_r{}_s{}_{}.{}",
             opts.local_size_start,
             opts.local_size_step,
             opts.local_size_stop,
             opts.global_size,
             opts.bench_options.repetitions,
             opts.bench_options.calculation_steps,
-            Local::now().format("%Y%m%d%H%M%S")
+            Local::now().format("%Y%m%d%H%M%S"),
+            record_extension(&opts.bench_options.bench_format)
         )));
-    let csv_columns = [
-        "timestamp",
-        "local_size",
-        "global_size",
-        "calc_count",
-        "write_duration",
-        "gpu_duration",
-        "read_duration",
-    ];
-
-    let csv_writer = get_csv_writer(&bench_output, &csv_columns, use_stdout);
+    let parameters = JsonObject::new()
+        .field("command", "bench-local-size")
+        .field("global_size", opts.global_size)
+        .field("local_size_start", opts.local_size_start)
+        .field("local_size_step", opts.local_size_step)
+        .field("local_size_stop", opts.local_size_stop);
+    let (bench_writer, raw_writer, summary_writer) = get_bench_writers(
+        &opts.bench_options,
+        &bench_output,
+        parameters,
+        &controller.device_infos(),
+    )?;
     let stream = controller.bench_local_size(
         opts.global_size,
         opts.local_size_start,
@@ -164,60 +1026,180 @@ fn bench_local_size(opts: BenchLocalSize, mut controller: KernelController) -> O
         opts.bench_options.calculation_steps,
         opts.bench_options.repetitions,
     )?;
-    read_bench_results(opts.bench_options.calculation_steps, csv_writer, stream);
+    let (_, failures) = read_bench_results(
+        opts.bench_options.repetitions,
+        bench_writer,
+        raw_writer,
+        summary_writer,
+        stream,
+    )?;
 
-    Ok(())
+    check_bench_failures(failures)
 }
 
-/// Returns a csv writer to either stdout or a file
-fn get_csv_writer(
+/// Returns the writer for the aggregated statistics to either stdout or a file
+/// and the writers for the raw results and the summary if files for them were provided
+fn get_bench_writers(
+    bench_options: &BenchOptions,
     bench_output: &PathBuf,
-    csv_columns: &[&str; 7],
-    use_stdout: bool,
-) -> ThreadedCSVWriter {
-    if use_stdout {
-        ThreadedCSVWriter::new(io::stdout(), csv_columns)
+    parameters: JsonObject,
+    devices: &[DeviceInfo],
+) -> io::Result<(RecordWriter, Option<RecordWriter>, Option<SummaryWriter>)> {
+    let format = &bench_options.bench_format;
+    let record_errors = bench_options.record_errors;
+    let parameters = parameters
+        .field("calculation_steps", bench_options.calculation_steps)
+        .field("repetitions", bench_options.repetitions);
+
+    let summary_writer = match &bench_options.summary_file {
+        Some(path) => Some(summary_writer(path, parameters.clone(), devices)?),
+        None => None,
+    };
+    let bench_writer = if bench_options.general_options.stdout {
+        record_writer::<Timestamped<AggregatedBenchStatistics>, _>(
+            io::stdout(),
+            format,
+            false,
+            record_errors,
+            parameters.clone(),
+            devices,
+            WriterOptions::default(),
+        )
     } else {
-        let bench_writer = open_write_buffered(&bench_output);
-        ThreadedCSVWriter::new(bench_writer, csv_columns)
+        record_writer::<Timestamped<AggregatedBenchStatistics>, _>(
+            open_write_buffered(bench_output)?,
+            format,
+            false,
+            record_errors,
+            parameters.clone(),
+            devices,
+            WriterOptions::default(),
+        )
+    };
+    let raw_writer = match &bench_options.raw_output_file {
+        Some(path) => Some(record_writer::<Timestamped<BenchStatistics>, _>(
+            open_write_buffered(path)?,
+            format,
+            false,
+            record_errors,
+            parameters,
+            devices,
+            WriterOptions::default(),
+        )),
+        None => None,
+    };
+
+    Ok((bench_writer, raw_writer, summary_writer))
+}
+
+/// Creates the writer for records of the type R in the given format.
+/// The files get a header unless the writer appends to an existing file.
+fn record_writer<R, W>(
+    writer: W,
+    format: &RecordFormat,
+    append: bool,
+    record_errors: bool,
+    parameters: JsonObject,
+    devices: &[DeviceInfo],
+    options: WriterOptions,
+) -> RecordWriter
+where
+    R: CsvRecord,
+    W: Write + Send + Sync + 'static,
+{
+    match format {
+        RecordFormat::Csv => {
+            let columns = record_columns::<R>(record_errors);
+            RecordWriter::Csv(if append {
+                ThreadedCSVWriter::append(writer, &columns, options)
+            } else {
+                ThreadedCSVWriter::new(writer, &columns, options)
+            })
+        }
+        RecordFormat::JsonLines => RecordWriter::JsonLines(if append {
+            ThreadedJsonWriter::append(writer, devices.len(), record_errors, options)
+        } else {
+            ThreadedJsonWriter::new(
+                writer,
+                parameters,
+                devices.iter().map(|d| JsonObject::from(d).into()).collect(),
+                record_errors,
+                options,
+            )
+        }),
+    }
+}
+
+/// Creates the writer for the JSON document with the summary of a run
+fn summary_writer(
+    path: &PathBuf,
+    parameters: JsonObject,
+    devices: &[DeviceInfo],
+) -> io::Result<SummaryWriter> {
+    Ok(JsonSummaryWriter::new(
+        open_write_buffered(path)?,
+        parameters,
+        devices.iter().map(|d| JsonObject::from(d).into()).collect(),
+    ))
+}
+
+/// Returns the file extension of the record format
+fn record_extension(format: &RecordFormat) -> &'static str {
+    match format {
+        RecordFormat::Csv => "csv",
+        RecordFormat::JsonLines => "jsonl",
     }
 }
 
 /// Benchmarks the global size used for calculations
-fn bench_global_size(
-    opts: BenchGlobalSize,
-    mut controller: KernelController,
-) -> OCLStreamResult<()> {
+fn bench_global_size(opts: BenchGlobalSize, mut controller: KernelController) -> DemoResult<()> {
     let use_stdout = opts.bench_options.general_options.stdout;
     if use_stdout {
         log::set_max_level(LevelFilter::Warn);
     }
-    set_output_colored(opts.bench_options.general_options.color);
-    controller.set_concurrency(opts.bench_options.general_options.threads);
+    set_output_colored(opts.bench_options.general_options.color.clone());
+    controller.set_concurrency(
+        opts.bench_options
+            .general_options
+            .threads
+            .unwrap_or(DEFAULT_THREADS),
+    );
+
+    validate_range(
+        "global size",
+        opts.global_size_start,
+        opts.global_size_step,
+        opts.global_size_stop,
+    )?;
+    controller.validate_bench_launch(Some(opts.local_size), opts.global_size_stop)?;
 
     let bench_output = opts
         .bench_options
         .benchmark_file
+        .clone()
         .unwrap_or(PathBuf::from(format!(
-            "bench_global_{}-{}-{}_l{}_r{}_s{}_{}.csv",
+            "bench_global_{}-{}-{}_l{}_r{}_s{}_{}.{}",
             opts.global_size_start,
             opts.global_size_step,
             opts.global_size_stop,
             opts.local_size,
             opts.bench_options.repetitions,
             opts.bench_options.calculation_steps,
-            Local::now().format("%Y%m%d%H%M%S")
+            Local::now().format("%Y%m%d%H%M%S"),
+            record_extension(&opts.bench_options.bench_format)
         )));
-    let csv_columns = [
-        "timestamp",
-        "local_size",
-        "global_size",
-        "calc_count",
-        "write_duration",
-        "gpu_duration",
-        "read_duration",
-    ];
-    let csv_writer = get_csv_writer(&bench_output, &csv_columns, use_stdout);
+    let parameters = JsonObject::new()
+        .field("command", "bench-global-size")
+        .field("local_size", opts.local_size)
+        .field("global_size_start", opts.global_size_start)
+        .field("global_size_step", opts.global_size_step)
+        .field("global_size_stop", opts.global_size_stop);
+    let (bench_writer, raw_writer, summary_writer) = get_bench_writers(
+        &opts.bench_options,
+        &bench_output,
+        parameters,
+        &controller.device_infos(),
+    )?;
 
     let stream = controller.bench_global_size(
         opts.local_size,
@@ -227,72 +1209,309 @@ fn bench_global_size(
         opts.bench_options.calculation_steps,
         opts.bench_options.repetitions,
     )?;
-    read_bench_results(opts.bench_options.calculation_steps, csv_writer, stream);
+    let (_, failures) = read_bench_results(
+        opts.bench_options.repetitions,
+        bench_writer,
+        raw_writer,
+        summary_writer,
+        stream,
+    )?;
 
-    Ok(())
+    check_bench_failures(failures)
 }
 
-/// Reads benchmark results from the stream and prints
-/// them to the console
+/// Benchmarks every combination of local and global sizes
+fn bench_grid(opts: BenchGrid, mut controller: KernelController) -> DemoResult<()> {
+    let use_stdout = opts.bench_options.general_options.stdout;
+    if use_stdout {
+        log::set_max_level(LevelFilter::Warn);
+    }
+    set_output_colored(opts.bench_options.general_options.color.clone());
+    controller.set_concurrency(
+        opts.bench_options
+            .general_options
+            .threads
+            .unwrap_or(DEFAULT_THREADS),
+    );
+
+    validate_range(
+        "local size",
+        opts.local_size_start,
+        opts.local_size_step,
+        opts.local_size_stop,
+    )?;
+    validate_range(
+        "global size",
+        opts.global_size_start,
+        opts.global_size_step,
+        opts.global_size_stop,
+    )?;
+
+    let file_name = format!(
+        "bench_grid_{}-{}-{}_{}-{}-{}_{}_r{}_s{}_{}",
+        opts.local_size_start,
+        opts.local_size_step,
+        opts.local_size_stop,
+        opts.global_size_start,
+        opts.global_size_step,
+        opts.global_size_stop,
+        opts.stepping.to_string().to_lowercase(),
+        opts.bench_options.repetitions,
+        opts.bench_options.calculation_steps,
+        Local::now().format("%Y%m%d%H%M%S")
+    );
+    let bench_output = opts
+        .bench_options
+        .benchmark_file
+        .clone()
+        .unwrap_or(PathBuf::from(format!(
+            "{}.{}",
+            file_name,
+            record_extension(&opts.bench_options.bench_format)
+        )));
+    let matrix_output = opts
+        .matrix_file
+        .unwrap_or(PathBuf::from(format!("{}_matrix.csv", file_name)));
+    let parameters = JsonObject::new()
+        .field("command", "bench-grid")
+        .field("local_size_start", opts.local_size_start)
+        .field("local_size_step", opts.local_size_step)
+        .field("local_size_stop", opts.local_size_stop)
+        .field("global_size_start", opts.global_size_start)
+        .field("global_size_step", opts.global_size_step)
+        .field("global_size_stop", opts.global_size_stop)
+        .field("stepping", opts.stepping.to_string());
+    let (bench_writer, raw_writer, summary_writer) = get_bench_writers(
+        &opts.bench_options,
+        &bench_output,
+        parameters,
+        &controller.device_infos(),
+    )?;
+
+    let local_sizes = size_steps(
+        opts.local_size_start,
+        opts.local_size_step,
+        opts.local_size_stop,
+        &opts.stepping,
+    );
+    let global_sizes = size_steps(
+        opts.global_size_start,
+        opts.global_size_step,
+        opts.global_size_stop,
+        &opts.stepping,
+    );
+    let stream = controller.bench_grid(
+        local_sizes.clone(),
+        global_sizes.clone(),
+        opts.bench_options.calculation_steps,
+        opts.bench_options.repetitions,
+    )?;
+    let (results, failures) = read_bench_results(
+        opts.bench_options.repetitions,
+        bench_writer,
+        raw_writer,
+        summary_writer,
+        stream,
+    )?;
+
+    let mut columns = vec!["local_size/global_size".to_string()];
+    columns.extend(global_sizes.iter().map(|g| g.to_string()));
+    let columns = columns.iter().map(String::as_str).collect::<Vec<&str>>();
+    let mut matrix_writer = ThreadedCSVWriter::new(
+        open_write_buffered(&matrix_output)?,
+        &columns,
+        WriterOptions::default(),
+    );
+
+    for local_size in local_sizes {
+        let mut row = vec![local_size.to_string()];
+        row.extend(global_sizes.iter().map(|global_size| {
+            results
+                .iter()
+                .find(|r| r.local_size == local_size && r.global_size == *global_size)
+                .map(|r| r.calc_duration.mean.to_string())
+                .unwrap_or_default()
+        }));
+        matrix_writer.add_row(row)?;
+    }
+    matrix_writer.close()?;
+
+    check_bench_failures(failures)
+}
+
+/// Returns the values from start up to and including stop increasing
+/// either by the step or by doubling the previous value.
+/// The values end before the next one would overflow.
+fn size_steps(start: usize, step: usize, stop: usize, stepping: &Stepping) -> Vec<usize> {
+    let mut sizes = Vec::new();
+    let mut size = Some(start.max(1));
+
+    while let Some(current) = size.filter(|s| *s <= stop) {
+        sizes.push(current);
+        size = match stepping {
+            Stepping::Linear => current.checked_add(step.max(1)),
+            Stepping::Pow2 => current.checked_mul(2),
+        };
+    }
+
+    sizes
+}
+
+/// Reads benchmark results from the stream and writes the
+/// statistics of every configuration once all repetitions are done.
+/// Returns the statistics and the number of failed benchmarks.
 fn read_bench_results(
-    calculation_steps: u32,
-    mut csv_writer: ThreadedCSVWriter,
+    repetitions: usize,
+    mut bench_writer: RecordWriter,
+    mut raw_writer: Option<RecordWriter>,
+    summary_writer: Option<SummaryWriter>,
     mut stream: OCLStream<BenchStatistics>,
-) {
+) -> io::Result<(Vec<AggregatedBenchStatistics>, usize)> {
+    let mut aggregator = BenchAggregator::new(repetitions);
+    let mut results = Vec::new();
+    let mut failures = 0;
+
     loop {
-        match stream.next() {
-            Ok(stats) => {
-                log::debug!("{:?}", stats);
-                csv_writer.add_row(vec![
-                    Local::now().format("%Y-%m-%dT%H:%M:%S.%f").to_string(),
-                    stats.local_size.to_string(),
-                    stats.global_size.to_string(),
-                    calculation_steps.to_string(),
-                    duration_to_ms_string(&stats.write_duration),
-                    duration_to_ms_string(&stats.calc_duration),
-                    duration_to_ms_string(&stats.read_duration),
-                ])
-            }
-            _ => {
-                break;
+        let stats = match stream.next() {
+            Ok(stats) => stats,
+            Err(OCLStreamError::RecvError(_)) => break,
+            Err(e) => {
+                log::error!("{}", e);
+                failures += 1;
+                if bench_writer.records_errors() {
+                    bench_writer.add_error(&e)?;
+                }
+                continue;
             }
+        };
+        log::debug!("{:?}", stats);
+        if let Some(raw_writer) = &mut raw_writer {
+            raw_writer.add_record(&Timestamped::now(&stats))?;
+        }
+        if let Some(aggregated) = aggregator.add(stats) {
+            bench_writer.add_record(&Timestamped::now(&aggregated))?;
+            results.push(aggregated);
         }
     }
-    csv_writer.close();
+    for aggregated in aggregator.finish() {
+        log::warn!(
+            "Only {} of {} repetitions finished for local size {} and global size {}",
+            aggregated.repetitions,
+            repetitions,
+            aggregated.local_size,
+            aggregated.global_size
+        );
+        bench_writer.add_record(&Timestamped::now(&aggregated))?;
+        results.push(aggregated);
+    }
+    let best = results
+        .iter()
+        .filter(|r| r.calc_duration.mean.is_finite())
+        .min_by(|a, b| {
+            a.calc_duration
+                .mean
+                .partial_cmp(&b.calc_duration.mean)
+                .unwrap()
+        });
+    if let Some(summary_writer) = summary_writer {
+        summary_writer.write(
+            JsonObject::new()
+                .field("configurations", results.len())
+                .field("failures", failures)
+                .field("fastest", best.map(|best| best.to_json())),
+        )?;
+    }
+    bench_writer.close()?;
+    if let Some(raw_writer) = raw_writer {
+        raw_writer.close()?;
+    }
+
+    Ok((results, failures))
+}
+
+/// Returns an error if any benchmark failed
+fn check_bench_failures(failures: usize) -> DemoResult<()> {
+    if failures > 0 {
+        Err(DemoError::OpenCL(format!("{} benchmarks failed", failures)))
+    } else {
+        Ok(())
+    }
 }
 
-fn validate_primes_on_cpu(primes: &Vec<u64>) {
+fn validate_primes_on_cpu(primes: &Vec<u64>) -> DemoResult<()> {
     log::debug!("Validating primes on the cpu");
     let failures = primes
         .par_iter()
         .filter(|n| !is_prime(**n))
         .collect::<Vec<&u64>>();
-    if failures.len() > 0 {
-        panic!(
+    if !failures.is_empty() {
+        Err(DemoError::Validation(format!(
             "{} failures in prime calculation: {:?}",
             failures.len(),
             failures
-        );
+        )))
     } else {
         log::debug!("No failures found.");
+        Ok(())
     }
 }
 
-fn duration_to_ms_string(duration: &Duration) -> String {
-    format!("{}", duration.as_secs_f64() * 1000f64)
-}
-
 /// opens a file in a buffered writer
 /// if it already exists it will be recreated
-fn open_write_buffered(path: &PathBuf) -> BufWriter<File> {
-    BufWriter::new(
-        OpenOptions::new()
-            .truncate(true)
-            .write(true)
-            .create(true)
-            .open(path)
-            .expect("Failed to open file!"),
-    )
+fn open_write_buffered(path: &PathBuf) -> io::Result<BufWriter<File>> {
+    let file = OpenOptions::new()
+        .truncate(true)
+        .write(true)
+        .create(true)
+        .open(path)?;
+
+    Ok(BufWriter::new(file))
+}
+
+/// opens a file in a buffered writer that appends to it
+/// after discarding everything beyond the given length
+fn open_append_buffered(path: &PathBuf, len: u64) -> io::Result<BufWriter<File>> {
+    let mut file = OpenOptions::new().write(true).create(true).open(path)?;
+    file.set_len(len)?;
+    file.seek(SeekFrom::End(0))?;
+
+    Ok(BufWriter::new(file))
+}
+
+/// Makes sure that every row of an existing csv file has the expected columns before appending to it
+fn check_csv_columns(path: &PathBuf, columns: &[String]) -> DemoResult<()> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut reader = CsvReader::new(io::BufReader::new(file))?;
+    if reader.columns() != columns {
+        return Err(DemoError::Argument(format!(
+            "The file {:?} has the columns {} but {} are expected",
+            path,
+            reader.columns().join(","),
+            columns.join(",")
+        )));
+    }
+    // the appended rows only line up if every existing row has a value for every column
+    loop {
+        match reader.read_row() {
+            Ok(Some(_)) => {}
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                return Err(DemoError::Argument(format!(
+                    "The file {:?} can't be appended to: {}",
+                    path, e
+                )))
+            }
+        }
+    }
+}
+
+/// Returns the current length of a file
+fn file_len(path: &PathBuf) -> u64 {
+    fs::metadata(path).map(|m| m.len()).unwrap_or(0)
 }
 
 fn set_output_colored(colored: UseColors) {
@@ -302,3 +1521,185 @@ fn set_output_colored(colored: UseColors) {
         _ => {}
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use std::sync::mpsc;
+
+    /// Creates an empty directory for the files of a test
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        dir
+    }
+
+    /// Runs calculate-primes on the cpu with the files in the directory
+    fn run_calculate_primes(dir: &Path, args: &[&str]) -> DemoResult<()> {
+        let file = |name: &str| dir.join(name).to_str().unwrap().to_string();
+        let files = [
+            "-o".to_string(),
+            file("primes.txt"),
+            "--timings-output".to_string(),
+            file("timings.jsonl"),
+            "--checkpoint".to_string(),
+            file("primes.checkpoint"),
+            "--profiles".to_string(),
+            file("profiles.txt"),
+        ];
+        let opts = Opts::from_iter_safe(
+            ["rust-opencl-demo", "calculate-primes"]
+                .iter()
+                .copied()
+                .chain(files.iter().map(String::as_str))
+                .chain(args.iter().copied()),
+        )
+        .unwrap();
+        let controller = KernelController::new(Backend::Cpu, None, &[]).unwrap();
+
+        match opts.command {
+            Command::CalculatePrimes(prime_opts) => calculate_primes(prime_opts, controller),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn size_steps_include_the_stop() {
+        assert_eq!(size_steps(1, 1, 4, &Stepping::Linear), vec![1, 2, 3, 4]);
+        assert_eq!(size_steps(2, 2, 9, &Stepping::Linear), vec![2, 4, 6, 8]);
+        assert_eq!(size_steps(1, 0, 8, &Stepping::Pow2), vec![1, 2, 4, 8]);
+        assert_eq!(size_steps(3, 0, 12, &Stepping::Pow2), vec![3, 6, 12]);
+        assert_eq!(size_steps(4, 4, 4, &Stepping::Linear), vec![4]);
+        assert_eq!(size_steps(0, 2, 4, &Stepping::Linear), vec![1, 3]);
+    }
+
+    #[test]
+    fn size_steps_larger_than_the_range() {
+        assert_eq!(size_steps(5, 10, 8, &Stepping::Linear), vec![5]);
+        assert_eq!(size_steps(5, 10, 15, &Stepping::Linear), vec![5, 15]);
+        assert_eq!(size_steps(5, 0, 9, &Stepping::Pow2), vec![5]);
+        assert!(size_steps(9, 1, 8, &Stepping::Linear).is_empty());
+    }
+
+    #[test]
+    fn size_steps_near_the_maximum() {
+        let max = usize::MAX;
+        assert_eq!(
+            size_steps(max - 1, 1, max, &Stepping::Linear),
+            vec![max - 1, max]
+        );
+        assert_eq!(
+            size_steps(max - 5, 10, max, &Stepping::Linear),
+            vec![max - 5]
+        );
+        let largest_power = 1 << (usize::BITS - 1);
+        assert_eq!(
+            size_steps(largest_power / 2, 0, max, &Stepping::Pow2),
+            vec![largest_power / 2, largest_power]
+        );
+        assert_eq!(size_steps(max, 0, max, &Stepping::Pow2), vec![max]);
+    }
+
+    #[test]
+    fn resume_with_other_parameters_fails() {
+        let dir = test_dir("resume-parameters");
+        let run = |args: &[&str]| {
+            let mut all_args = vec!["--end", "70000", "--numbers-per-step", "16384"];
+            all_args.extend(args);
+            run_calculate_primes(&dir, &all_args)
+        };
+        run(&[]).unwrap();
+        let results = vec![
+            run(&["--resume", "--algorithm", "Sieve"]),
+            run(&["--resume", "--no-cache"]),
+            run(&["--resume", "--timings-format", "JsonLines"]),
+            run(&["--resume"]),
+        ];
+        fs::remove_dir_all(&dir).unwrap();
+
+        for result in &results[..3] {
+            assert!(
+                matches!(result, Err(DemoError::Argument(_))),
+                "{:?}",
+                result
+            );
+        }
+        assert!(results[3].is_ok());
+    }
+
+    #[test]
+    fn resumed_json_lines_have_one_header() {
+        let dir = test_dir("resume-json-lines");
+        let summary_file = dir.join("summary.json");
+        let run = |end: &str, resume: bool| {
+            let mut args = vec!["--numbers-per-step", "16384", "--end", end];
+            args.extend(&["--timings-format", "JsonLines"]);
+            args.extend(&["--summary-output", summary_file.to_str().unwrap()]);
+            if resume {
+                args.push("--resume");
+            }
+            run_calculate_primes(&dir, &args)
+        };
+        // a run up to 70000 with the checkpoint of the full range stands in for an interrupted run
+        run("70000", false).unwrap();
+        let checkpoint_file = dir.join("primes.checkpoint");
+        let mut checkpoint = Checkpoint::load(&checkpoint_file).unwrap();
+        checkpoint.parameters.end = 140_000;
+        checkpoint.parameters.cache_limit = integer_sqrt(140_000 + 2 * 16384);
+        checkpoint.save(&checkpoint_file).unwrap();
+        run("140000", true).unwrap();
+
+        let timings = fs::read_to_string(dir.join("timings.jsonl")).unwrap();
+        let primes = fs::read_to_string(dir.join("primes.txt")).unwrap();
+        let summary = fs::read_to_string(&summary_file).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(timings.matches(r#""type":"header""#).count(), 1);
+        assert!(timings.starts_with(r#"{"type":"header""#));
+        assert!(!timings.contains("summary"));
+        // the summary document is replaced by the resumed run
+        assert_eq!(summary.lines().count(), 1);
+        assert!(summary.contains(r#""parameters":{"command":"calculate-primes""#));
+        assert!(summary.contains(r#""summary":{"offset":140000,"#));
+        assert!(summary.ends_with(",\"error\":null}}\n"));
+        let expected = (2..140_000)
+            .filter(|n| is_prime(*n))
+            .map(|n| n.to_string())
+            .collect::<Vec<String>>();
+        assert_eq!(primes.lines().collect::<Vec<&str>>(), expected);
+    }
+
+    #[test]
+    fn failed_tune_configuration_releases_the_device() {
+        let controller = match KernelController::new(Backend::OpenCL, None, &[]) {
+            Ok(controller) => controller,
+            Err(e) => {
+                eprintln!("Skipping the OpenCL test: {}", e);
+                return;
+            }
+        };
+        let algorithm = prime_algorithm(&Algorithm::TrialDivision, true, 16384);
+        let too_large = controller.max_local_size(algorithm.kernel_name()).unwrap() * 2;
+        assert!(tune_configuration(&controller, 0, 2, 1024, too_large, 2, algorithm).is_err());
+
+        // the next configuration reuses the backends of the failed one
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            sender.send(tune_configuration(&controller, 0, 2, 1024, 64, 2, algorithm).is_ok())
+        });
+        assert!(receiver
+            .recv_timeout(Duration::from_secs(60))
+            .expect("The device is still locked"));
+    }
+
+    #[test]
+    fn tune_configuration_on_cpu() {
+        let controller = KernelController::new(Backend::Cpu, None, &[]).unwrap();
+        let algorithm = prime_algorithm(&Algorithm::Sieve, true, 16384);
+
+        assert!(tune_configuration(&controller, 0, 4, 16384, 64, 2, algorithm).unwrap() > 0.0);
+    }
+}
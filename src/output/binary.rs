@@ -0,0 +1,313 @@
+/*
+ * opencl demos with rust
+ * Copyright (C) 2021 trivernis
+ * See LICENSE for more information
+ */
+
+use std::convert::TryInto;
+use std::fs::OpenOptions;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+pub const MAGIC: [u8; 4] = *b"PRMS";
+pub const VERSION: u8 = 1;
+pub const HEADER_LEN: u64 = 64;
+
+/// Number of primes between two entries of the varint index
+pub const INDEX_INTERVAL: u64 = 4096;
+
+pub const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// Binary encoding of the primes in a prime file
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BinaryFormat {
+    /// One bit per odd number in the range
+    Bitmap = 1,
+    /// Half the distance to the previous prime as LEB128 varint
+    /// with an index of every INDEX_INTERVAL-th prime at the end
+    Varint = 2,
+}
+
+/// Header at the start of a binary prime file.
+/// Only odd primes are stored so 2 is never part of a file.
+#[derive(Clone, Debug)]
+pub struct PrimeFileHeader {
+    pub format: BinaryFormat,
+    /// The index of the algorithm in the values of the --algorithm option
+    pub algorithm: u8,
+    /// The first number of the range. This number is always odd.
+    pub start: u64,
+    /// The exclusive end of the range
+    pub end: u64,
+    pub count: u64,
+    /// FNV-1a hash of everything after the header
+    pub checksum: u64,
+    /// File offset of the varint index
+    pub index_offset: u64,
+    /// Number of entries in the varint index
+    pub index_len: u64,
+}
+
+impl PrimeFileHeader {
+    pub fn to_bytes(&self) -> [u8; HEADER_LEN as usize] {
+        let mut bytes = [0u8; HEADER_LEN as usize];
+        bytes[0..4].copy_from_slice(&MAGIC);
+        bytes[4] = VERSION;
+        bytes[5] = self.format as u8;
+        bytes[6] = self.algorithm;
+        bytes[8..16].copy_from_slice(&self.start.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.end.to_le_bytes());
+        bytes[24..32].copy_from_slice(&self.count.to_le_bytes());
+        bytes[32..40].copy_from_slice(&self.checksum.to_le_bytes());
+        bytes[40..48].copy_from_slice(&self.index_offset.to_le_bytes());
+        bytes[48..56].copy_from_slice(&self.index_len.to_le_bytes());
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; HEADER_LEN as usize]) -> io::Result<Self> {
+        if bytes[0..4] != MAGIC || bytes[4] != VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Not a prime file or unsupported version",
+            ));
+        }
+        let format = match bytes[5] {
+            1 => BinaryFormat::Bitmap,
+            2 => BinaryFormat::Varint,
+            f => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Unknown prime file format {}", f),
+                ))
+            }
+        };
+        let read_u64 = |i: usize| u64::from_le_bytes(bytes[i..i + 8].try_into().unwrap());
+
+        Ok(Self {
+            format,
+            algorithm: bytes[6],
+            start: read_u64(8),
+            end: read_u64(16),
+            count: read_u64(24),
+            checksum: read_u64(32),
+            index_offset: read_u64(40),
+            index_len: read_u64(48),
+        })
+    }
+}
+
+/// Stateful encoder that serializes chunks of ascending odd primes.
+/// A placeholder header is emitted first and needs to be replaced
+/// with `finish_prime_file` once all primes have been written.
+pub struct PrimeEncoder {
+    format: BinaryFormat,
+    start: u64,
+    started: bool,
+    /// The next odd number that hasn't been encoded
+    cursor: u64,
+    pending_byte: u8,
+    pending_bits: u8,
+    count: u64,
+    checksum: u64,
+    payload_len: u64,
+    index: Vec<(u64, u64)>,
+}
+
+impl PrimeEncoder {
+    /// Creates a new encoder for the range beginning with start
+    pub fn new(format: BinaryFormat, mut start: u64) -> Self {
+        if start % 2 == 0 {
+            start += 1;
+        }
+        Self {
+            format,
+            start,
+            started: false,
+            cursor: start,
+            pending_byte: 0,
+            pending_bits: 0,
+            count: 0,
+            checksum: FNV_OFFSET,
+            payload_len: 0,
+            index: Vec::new(),
+        }
+    }
+
+    /// Encodes a chunk of ascending primes that are greater than the ones encoded before.
+    /// The even prime 2 is skipped because only odd numbers are stored.
+    pub fn encode(&mut self, primes: &[u64]) -> Vec<u8> {
+        let mut bytes = self.start_bytes();
+        let mut payload = Vec::with_capacity(primes.len());
+
+        for prime in primes {
+            if *prime < self.cursor || prime % 2 == 0 {
+                continue;
+            }
+            match self.format {
+                BinaryFormat::Bitmap => {
+                    self.push_zeros((prime - self.cursor) / 2, &mut payload);
+                    self.push_bit(true, &mut payload);
+                }
+                BinaryFormat::Varint => {
+                    if self.count % INDEX_INTERVAL == 0 {
+                        self.index
+                            .push((*prime, self.payload_len + payload.len() as u64));
+                    }
+                    write_varint((prime - self.cursor) / 2, &mut payload);
+                }
+            }
+            self.cursor = prime + 2;
+            self.count += 1;
+        }
+        self.append_payload(&payload);
+        bytes.append(&mut payload);
+
+        bytes
+    }
+
+    /// Finishes the payload for the range up to the exclusive end
+    /// and returns the remaining bytes with the final header
+    pub fn finish(&mut self, end: u64, algorithm: u8) -> (Vec<u8>, PrimeFileHeader) {
+        let mut bytes = self.start_bytes();
+        let mut payload = Vec::new();
+        let end = end.max(self.cursor);
+        let mut index_offset = 0;
+
+        match self.format {
+            BinaryFormat::Bitmap => {
+                self.push_zeros((end - self.cursor + 1) / 2, &mut payload);
+                if self.pending_bits > 0 {
+                    payload.push(self.pending_byte);
+                    self.pending_byte = 0;
+                    self.pending_bits = 0;
+                }
+            }
+            BinaryFormat::Varint => {
+                index_offset = HEADER_LEN + self.payload_len;
+                for (prime, offset) in &self.index {
+                    payload.extend_from_slice(&prime.to_le_bytes());
+                    payload.extend_from_slice(&offset.to_le_bytes());
+                }
+            }
+        }
+        self.cursor = end;
+        self.append_payload(&payload);
+        bytes.append(&mut payload);
+
+        let header = PrimeFileHeader {
+            format: self.format,
+            algorithm,
+            start: self.start,
+            end,
+            count: self.count,
+            checksum: self.checksum,
+            index_offset,
+            index_len: self.index.len() as u64,
+        };
+
+        (bytes, header)
+    }
+
+    /// Returns the placeholder header if nothing has been emitted yet
+    fn start_bytes(&mut self) -> Vec<u8> {
+        if self.started {
+            Vec::new()
+        } else {
+            self.started = true;
+            vec![0u8; HEADER_LEN as usize]
+        }
+    }
+
+    fn append_payload(&mut self, payload: &[u8]) {
+        self.checksum = fnv1a(self.checksum, payload);
+        self.payload_len += payload.len() as u64;
+    }
+
+    fn push_bit(&mut self, bit: bool, payload: &mut Vec<u8>) {
+        if bit {
+            self.pending_byte |= 1 << self.pending_bits;
+        }
+        self.pending_bits += 1;
+
+        if self.pending_bits == 8 {
+            payload.push(self.pending_byte);
+            self.pending_byte = 0;
+            self.pending_bits = 0;
+        }
+    }
+
+    fn push_zeros(&mut self, mut count: u64, payload: &mut Vec<u8>) {
+        while count > 0 && self.pending_bits != 0 {
+            self.push_bit(false, payload);
+            count -= 1;
+        }
+        payload.resize(payload.len() + (count / 8) as usize, 0);
+        for _ in 0..count % 8 {
+            self.push_bit(false, payload);
+        }
+    }
+}
+
+/// Appends the remaining bytes of the encoder to the prime file
+/// and replaces the placeholder header with the final one
+pub fn finish_prime_file(
+    path: &Path,
+    encoder: &mut PrimeEncoder,
+    end: u64,
+    algorithm: u8,
+) -> io::Result<PrimeFileHeader> {
+    let (bytes, header) = encoder.finish(end, algorithm);
+    let mut file = OpenOptions::new().write(true).open(path)?;
+
+    file.seek(SeekFrom::End(0))?;
+    file.write_all(&bytes)?;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&header.to_bytes())?;
+    file.flush()?;
+
+    Ok(header)
+}
+
+/// Updates an FNV-1a hash with the given bytes
+pub fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+
+    hash
+}
+
+/// Writes a LEB128 encoded unsigned integer
+pub fn write_varint(mut value: u64, bytes: &mut Vec<u8>) {
+    while value >= 0x80 {
+        bytes.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+/// Reads a LEB128 encoded unsigned integer
+pub fn read_varint<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut value = 0u64;
+    let mut shift = 0;
+    let mut byte = [0u8; 1];
+
+    loop {
+        reader.read_exact(&mut byte)?;
+        value |= ((byte[0] & 0x7f) as u64) << shift;
+
+        if byte[0] & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+        if shift >= 64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Varint is too long",
+            ));
+        }
+    }
+}
@@ -4,53 +4,172 @@
  * See LICENSE for more information
  */
 
-use crate::output::threaded::ThreadedWriter;
+use crate::output::threaded::{ThreadedWriter, WriterOptions};
+use chrono::{DateTime, Local};
 use std::collections::HashMap;
-use std::io::Write;
+use std::fmt::Display;
+use std::io::{self, BufRead, ErrorKind, Write};
+
+/// The format of the timestamps in the first column of the files
+pub const TIMESTAMP_FORMAT: &str = "%Y-%m-%dT%H:%M:%S.%f";
+const ERROR_COLUMN: &str = "error";
+
+/// A value that is written as one row of a csv file
+pub trait CsvRecord {
+    /// Returns the names of the columns
+    fn columns() -> Vec<String>;
+
+    /// Returns the values in the order of the columns
+    fn values(&self) -> Vec<String>;
+}
+
+impl<R: CsvRecord> CsvRecord for &R {
+    fn columns() -> Vec<String> {
+        R::columns()
+    }
+
+    fn values(&self) -> Vec<String> {
+        (*self).values()
+    }
+}
+
+/// A record with the time it was created in an additional first column
+pub struct Timestamped<R> {
+    pub timestamp: DateTime<Local>,
+    pub record: R,
+}
+
+impl<R> Timestamped<R> {
+    /// Stamps the record with the current time
+    pub fn now(record: R) -> Self {
+        Self {
+            timestamp: Local::now(),
+            record,
+        }
+    }
+}
+
+impl<R: CsvRecord> CsvRecord for Timestamped<R> {
+    fn columns() -> Vec<String> {
+        let mut columns = vec!["timestamp".to_string()];
+        columns.append(&mut R::columns());
+
+        columns
+    }
+
+    fn values(&self) -> Vec<String> {
+        let mut values = vec![self.timestamp.format(TIMESTAMP_FORMAT).to_string()];
+        values.append(&mut self.record.values());
+
+        values
+    }
+}
+
+/// Returns the columns of the record with an additional error column
+/// if errors are recorded
+pub fn record_columns<R: CsvRecord>(record_errors: bool) -> Vec<String> {
+    let mut columns = R::columns();
+    if record_errors {
+        columns.push(ERROR_COLUMN.to_string());
+    }
+
+    columns
+}
 
 pub struct ThreadedCSVWriter {
-    inner: ThreadedWriter<String>,
+    inner: ThreadedWriter<Vec<String>>,
     columns: Vec<String>,
 }
 
 impl ThreadedCSVWriter {
     /// Creates a new CSVWriter with a defined list of columns
-    pub fn new<W>(writer: W, columns: &[&str]) -> Self
+    pub fn new<W, S>(writer: W, columns: &[S], options: WriterOptions) -> Self
     where
         W: Write + Send + Sync + 'static,
+        S: AsRef<str>,
     {
-        let column_vec = columns
+        let mut csv_writer = Self::append(writer, columns, options);
+        // a failed header write is returned by the next row
+        let _ = csv_writer.add_row(csv_writer.columns.clone());
+
+        csv_writer
+    }
+
+    /// Creates a new CSVWriter that appends to existing content
+    /// without writing the header again
+    pub fn append<W, S>(writer: W, columns: &[S], options: WriterOptions) -> Self
+    where
+        W: Write + Send + Sync + 'static,
+        S: AsRef<str>,
+    {
+        let columns = columns
             .iter()
-            .map(|column| column.to_string())
+            .map(|column| column.as_ref().to_string())
             .collect::<Vec<String>>();
-        log::trace!("Creating new CSV Writer with columns: {:?}", column_vec);
+        log::trace!("Creating CSV Writer with columns: {:?}", columns);
 
-        let writer = ThreadedWriter::new(writer, |v: String| v.as_bytes().to_vec());
-        let mut csv_writer = Self {
-            inner: writer,
-            columns: column_vec.clone(),
-        };
-        csv_writer.add_row(column_vec);
+        Self {
+            inner: ThreadedWriter::with_options(writer, options, write_csv_row),
+            columns,
+        }
+    }
 
-        csv_writer
+    /// Returns if the last column stores the errors of failed calculations
+    pub fn records_errors(&self) -> bool {
+        self.columns.last().map(String::as_str) == Some(ERROR_COLUMN)
     }
 
-    /// Adds a new row of values to the file
-    pub fn add_row(&mut self, items: Vec<String>) {
+    /// Adds a new row of values to the file.
+    /// The row needs to have a value for every column.
+    /// Returns the error of a previous write if one failed.
+    pub fn add_row(&mut self, items: Vec<String>) -> io::Result<()> {
         log::trace!("Adding row to CSV: {:?}", items);
-        self.inner.write(
-            items
-                .iter()
-                .fold("".to_string(), |a, b| format!("{},{}", a, b))
-                .trim_start_matches(',')
-                .to_string()
-                + "\n",
-        );
+        if items.len() != self.columns.len() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "The row has {} values but the file has {} columns",
+                    items.len(),
+                    self.columns.len()
+                ),
+            ));
+        }
+
+        self.inner.write(items)
+    }
+
+    /// Adds a record to the file. The error column is left empty
+    pub fn add_record<R: CsvRecord>(&mut self, record: &R) -> io::Result<()> {
+        let mut items = record.values();
+        if self.records_errors() {
+            items.push(String::new());
+        }
+
+        self.add_row(items)
+    }
+
+    /// Adds a row that only contains the timestamp and the error
+    pub fn add_error<E: Display>(&mut self, error: &E) -> io::Result<()> {
+        if !self.records_errors() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "The file has no error column",
+            ));
+        }
+        let mut items = vec![String::new(); self.columns.len()];
+        if self.columns[0] == "timestamp" {
+            items[0] = Local::now().format(TIMESTAMP_FORMAT).to_string();
+        }
+        if let Some(last) = items.last_mut() {
+            *last = error.to_string();
+        }
+
+        self.add_row(items)
     }
 
     /// Adds a new row of values stored in a map to the file
     #[allow(dead_code)]
-    pub fn add_row_map(&mut self, item_map: &HashMap<String, String>) {
+    pub fn add_row_map(&mut self, item_map: &HashMap<String, String>) -> io::Result<()> {
         let mut items = Vec::new();
         for key in &self.columns {
             items.push(item_map.get(key).cloned().unwrap_or("".to_string()));
@@ -59,7 +178,281 @@ impl ThreadedCSVWriter {
         self.add_row(items)
     }
 
-    pub fn close(self) {
+    /// Waits until all previously added rows are flushed
+    pub fn flush(&self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    /// Closes the writer and returns the first error that occurred while writing
+    pub fn close(self) -> io::Result<()> {
         self.inner.close()
     }
 }
+
+/// Serializes the values as a csv row as described in RFC 4180.
+/// Values with commas, quotes or line breaks are quoted and quotes are doubled.
+fn write_csv_row(items: Vec<String>, output: &mut Vec<u8>) {
+    for (index, item) in items.iter().enumerate() {
+        if index > 0 {
+            output.push(b',');
+        }
+        if item.contains(&[',', '"', '\r', '\n'][..]) {
+            output.push(b'"');
+            output.extend_from_slice(item.replace('"', "\"\"").as_bytes());
+            output.push(b'"');
+        } else {
+            output.extend_from_slice(item.as_bytes());
+        }
+    }
+    output.push(b'\n');
+}
+
+/// Reads csv files as written by the ThreadedCSVWriter or other RFC 4180 writers.
+/// The first row is read as the header.
+pub struct CsvReader<R: BufRead> {
+    reader: R,
+    columns: Vec<String>,
+}
+
+impl<R: BufRead> CsvReader<R> {
+    /// Creates a new reader and reads the header
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let columns = read_csv_row(&mut reader)?.ok_or_else(|| {
+            io::Error::new(ErrorKind::UnexpectedEof, "The csv file has no header")
+        })?;
+
+        Ok(Self { reader, columns })
+    }
+
+    /// Returns the columns of the header
+    pub fn columns(&self) -> &[String] {
+        &self.columns
+    }
+
+    /// Reads the next row. Every row needs to have a value for every column.
+    /// Returns None at the end of the file.
+    pub fn read_row(&mut self) -> io::Result<Option<Vec<String>>> {
+        let row = match read_csv_row(&mut self.reader)? {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+        if row.len() != self.columns.len() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "The row has {} values but the file has {} columns",
+                    row.len(),
+                    self.columns.len()
+                ),
+            ));
+        }
+
+        Ok(Some(row))
+    }
+}
+
+/// Reads one row that may span several lines if quoted values contain line breaks.
+/// Line breaks can be a line feed or a carriage return followed by a line feed.
+fn read_csv_row<R: BufRead>(reader: &mut R) -> io::Result<Option<Vec<String>>> {
+    let mut line = String::new();
+    let mut values = Vec::new();
+    let mut value = String::new();
+    let mut quoted = false;
+
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            if quoted {
+                return Err(io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "The csv file ends inside a quoted value",
+                ));
+            }
+            if values.is_empty() && value.is_empty() {
+                return Ok(None);
+            }
+            values.push(value);
+            return Ok(Some(values));
+        }
+        let mut chars = line.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '"' if quoted => {
+                    if chars.peek() == Some(&'"') {
+                        chars.next();
+                        value.push('"');
+                    } else {
+                        quoted = false;
+                    }
+                }
+                '"' if value.is_empty() => quoted = true,
+                ',' if !quoted => values.push(std::mem::take(&mut value)),
+                '\r' if !quoted && chars.peek() == Some(&'\n') => {}
+                '\n' if !quoted => {
+                    values.push(value);
+                    return Ok(Some(values));
+                }
+                c => value.push(c),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::threaded::FlushPolicy;
+    use std::fs::{self, File};
+    use std::io::BufReader;
+    use std::process;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    struct Measurement {
+        name: String,
+        value: u64,
+    }
+
+    impl CsvRecord for Measurement {
+        fn columns() -> Vec<String> {
+            vec!["name".to_string(), "value".to_string()]
+        }
+
+        fn values(&self) -> Vec<String> {
+            vec![self.name.clone(), self.value.to_string()]
+        }
+    }
+
+    /// Discards the written bytes and counts the flushes
+    struct FlushCounter(Arc<AtomicUsize>);
+
+    impl Write for FlushCounter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    fn flushes_of_three_rows(flush_policy: FlushPolicy) -> usize {
+        let flushes = Arc::new(AtomicUsize::new(0));
+        let options = WriterOptions {
+            flush_policy,
+            ..WriterOptions::default()
+        };
+        let mut writer =
+            ThreadedCSVWriter::new(FlushCounter(Arc::clone(&flushes)), &["value"], options);
+        for value in 0..3 {
+            writer.add_row(vec![value.to_string()]).unwrap();
+        }
+        writer.close().unwrap();
+
+        flushes.load(Ordering::SeqCst)
+    }
+
+    fn read_rows<R: BufRead>(reader: &mut CsvReader<R>) -> Vec<Vec<String>> {
+        let mut rows = Vec::new();
+        while let Some(row) = reader.read_row().unwrap() {
+            rows.push(row);
+        }
+
+        rows
+    }
+
+    #[test]
+    fn quoted_values_round_trip() {
+        let rows = vec![
+            vec!["plain".to_string(), "".to_string(), "a,b".to_string()],
+            vec![
+                "\"quoted\"".to_string(),
+                "line\nbreak".to_string(),
+                "carriage\r\nreturn".to_string(),
+            ],
+            vec!["\r".to_string(), ",\",\"".to_string(), "end\n".to_string()],
+        ];
+        let mut output = Vec::new();
+        for row in &rows {
+            write_csv_row(row.clone(), &mut output);
+        }
+        assert!(output.starts_with(b"plain,,\"a,b\"\n\"\"\"quoted\"\"\","));
+
+        let mut reader = CsvReader::new(&output[..]).unwrap();
+        assert_eq!(reader.columns(), &rows[0][..]);
+        assert_eq!(read_rows(&mut reader), rows[1..].to_vec());
+    }
+
+    #[test]
+    fn crlf_line_breaks_are_read() {
+        let mut reader = CsvReader::new(&b"a,b\r\n1,\"x\r\ny\"\r\n"[..]).unwrap();
+
+        assert_eq!(reader.columns(), &["a", "b"]);
+        assert_eq!(read_rows(&mut reader), vec![vec!["1", "x\r\ny"]]);
+    }
+
+    #[test]
+    fn rows_with_a_wrong_column_count_are_rejected() {
+        let mut reader = CsvReader::new(&b"a,b\n1,2\n1,2,3\n"[..]).unwrap();
+
+        assert!(reader.read_row().unwrap().is_some());
+        assert!(reader.read_row().is_err());
+        assert!(CsvReader::new(&b"a,b\n\"1,2\n"[..])
+            .unwrap()
+            .read_row()
+            .is_err());
+    }
+
+    #[test]
+    fn error_records_have_every_column() {
+        let path = std::env::temp_dir().join(format!("csv-errors-{}.csv", process::id()));
+        let columns = record_columns::<Timestamped<Measurement>>(true);
+        let mut writer = ThreadedCSVWriter::new(
+            File::create(&path).unwrap(),
+            &columns,
+            WriterOptions::default(),
+        );
+        assert!(writer.records_errors());
+
+        writer
+            .add_record(&Timestamped::now(Measurement {
+                name: "first, \"quoted\"".to_string(),
+                value: 1,
+            }))
+            .unwrap();
+        writer.add_error(&"failed\non two lines").unwrap();
+        assert!(writer.add_row(vec!["too few".to_string()]).is_err());
+        writer.close().unwrap();
+
+        let mut reader = CsvReader::new(BufReader::new(File::open(&path).unwrap())).unwrap();
+        assert_eq!(reader.columns(), &["timestamp", "name", "value", "error"]);
+        let rows = read_rows(&mut reader);
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(rows.len(), 2);
+        assert_eq!(&rows[0][1..], &["first, \"quoted\"", "1", ""]);
+        assert!(!rows[1][0].is_empty());
+        assert_eq!(&rows[1][1..], &["", "", "failed\non two lines"]);
+    }
+
+    #[test]
+    fn missing_error_column_is_rejected() {
+        let columns = record_columns::<Measurement>(false);
+        let mut writer = ThreadedCSVWriter::new(io::sink(), &columns, WriterOptions::default());
+
+        assert!(!writer.records_errors());
+        assert!(writer.add_error(&"failed").is_err());
+        writer.close().unwrap();
+    }
+
+    #[test]
+    fn writer_options_are_used() {
+        // the header and every row are flushed when they are written
+        assert_eq!(flushes_of_three_rows(FlushPolicy::PerChunk), 4);
+        // the header with the first row reaches 8 bytes and the last two rows are flushed on close
+        assert_eq!(flushes_of_three_rows(FlushPolicy::Bytes(8)), 2);
+        assert_eq!(flushes_of_three_rows(FlushPolicy::OnClose), 1);
+    }
+}
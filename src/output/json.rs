@@ -0,0 +1,540 @@
+/*
+ * opencl demos with rust
+ * Copyright (C) 2021 trivernis
+ * See LICENSE for more information
+ */
+
+use crate::output::csv::{Timestamped, TIMESTAMP_FORMAT};
+use crate::output::text::push_u64;
+use crate::output::threaded::{MemorySize, ThreadedWriter, WriterOptions};
+use chrono::Local;
+use std::fmt::Display;
+use std::io::{self, Write};
+use std::mem::size_of;
+
+/// A JSON value with the fields of objects in insertion order
+#[derive(Clone, Debug)]
+pub enum JsonValue {
+    Null,
+    Bool(bool),
+    UInt(u64),
+    Float(f64),
+    String(String),
+    Array(Vec<JsonValue>),
+    Object(JsonObject),
+}
+
+impl JsonValue {
+    /// Appends the serialized value to the output
+    pub fn write(&self, output: &mut Vec<u8>) {
+        match self {
+            JsonValue::Null => output.extend_from_slice(b"null"),
+            JsonValue::Bool(true) => output.extend_from_slice(b"true"),
+            JsonValue::Bool(false) => output.extend_from_slice(b"false"),
+            JsonValue::UInt(number) => push_u64(output, *number),
+            // JSON has no representation for infinity and NaN
+            JsonValue::Float(number) if !number.is_finite() => output.extend_from_slice(b"null"),
+            JsonValue::Float(number) => {
+                write!(output, "{}", number).unwrap();
+            }
+            JsonValue::String(string) => write_json_string(string, output),
+            JsonValue::Array(values) => {
+                output.push(b'[');
+                for (index, value) in values.iter().enumerate() {
+                    if index > 0 {
+                        output.push(b',');
+                    }
+                    value.write(output);
+                }
+                output.push(b']');
+            }
+            JsonValue::Object(object) => object.write(output),
+        }
+    }
+}
+
+impl From<bool> for JsonValue {
+    fn from(value: bool) -> Self {
+        JsonValue::Bool(value)
+    }
+}
+
+impl From<u64> for JsonValue {
+    fn from(value: u64) -> Self {
+        JsonValue::UInt(value)
+    }
+}
+
+impl From<u32> for JsonValue {
+    fn from(value: u32) -> Self {
+        JsonValue::UInt(value as u64)
+    }
+}
+
+impl From<usize> for JsonValue {
+    fn from(value: usize) -> Self {
+        JsonValue::UInt(value as u64)
+    }
+}
+
+impl From<f64> for JsonValue {
+    fn from(value: f64) -> Self {
+        JsonValue::Float(value)
+    }
+}
+
+impl From<&str> for JsonValue {
+    fn from(value: &str) -> Self {
+        JsonValue::String(value.to_string())
+    }
+}
+
+impl From<String> for JsonValue {
+    fn from(value: String) -> Self {
+        JsonValue::String(value)
+    }
+}
+
+impl From<JsonObject> for JsonValue {
+    fn from(value: JsonObject) -> Self {
+        JsonValue::Object(value)
+    }
+}
+
+impl<T: Into<JsonValue>> From<Option<T>> for JsonValue {
+    fn from(value: Option<T>) -> Self {
+        value.map(Into::into).unwrap_or(JsonValue::Null)
+    }
+}
+
+impl<T: Into<JsonValue>> From<Vec<T>> for JsonValue {
+    fn from(values: Vec<T>) -> Self {
+        JsonValue::Array(values.into_iter().map(Into::into).collect())
+    }
+}
+
+impl MemorySize for JsonValue {
+    fn memory_size(&self) -> usize {
+        size_of::<JsonValue>()
+            + match self {
+                JsonValue::String(string) => string.capacity(),
+                JsonValue::Array(values) => values.iter().map(JsonValue::memory_size).sum(),
+                JsonValue::Object(object) => object
+                    .fields
+                    .iter()
+                    .map(|(name, value)| name.capacity() + value.memory_size())
+                    .sum(),
+                _ => 0,
+            }
+    }
+}
+
+/// A JSON object that is built by chaining fields
+#[derive(Clone, Debug, Default)]
+pub struct JsonObject {
+    fields: Vec<(String, JsonValue)>,
+}
+
+impl JsonObject {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a field to the object
+    pub fn field<V: Into<JsonValue>>(mut self, name: &str, value: V) -> Self {
+        self.fields.push((name.to_string(), value.into()));
+
+        self
+    }
+
+    /// Adds all fields of the other object
+    pub fn extend(mut self, other: JsonObject) -> Self {
+        self.fields.extend(other.fields);
+
+        self
+    }
+
+    fn write(&self, output: &mut Vec<u8>) {
+        output.push(b'{');
+        for (index, (name, value)) in self.fields.iter().enumerate() {
+            if index > 0 {
+                output.push(b',');
+            }
+            write_json_string(name, output);
+            output.push(b':');
+            value.write(output);
+        }
+        output.push(b'}');
+    }
+}
+
+/// A value that is written as one JSON record
+pub trait JsonRecord {
+    /// The value of the type field of the record
+    const TYPE: &'static str;
+
+    /// Returns the fields of the record
+    fn to_json(&self) -> JsonObject;
+
+    /// Returns the index of the device the record was calculated on
+    fn device(&self) -> Option<usize> {
+        None
+    }
+}
+
+impl<R: JsonRecord> JsonRecord for &R {
+    const TYPE: &'static str = R::TYPE;
+
+    fn to_json(&self) -> JsonObject {
+        (*self).to_json()
+    }
+
+    fn device(&self) -> Option<usize> {
+        (*self).device()
+    }
+}
+
+impl<R: JsonRecord> JsonRecord for Timestamped<R> {
+    const TYPE: &'static str = R::TYPE;
+
+    fn to_json(&self) -> JsonObject {
+        JsonObject::new()
+            .field(
+                "timestamp",
+                self.timestamp.format(TIMESTAMP_FORMAT).to_string(),
+            )
+            .extend(self.record.to_json())
+    }
+
+    fn device(&self) -> Option<usize> {
+        self.record.device()
+    }
+}
+
+/// Writes records as JSON Lines with one JSON object per line.
+/// The first record is a header with the parameters of the run and the metadata of all devices.
+/// The other records reference their device by its index in the devices of the header.
+pub struct ThreadedJsonWriter {
+    inner: ThreadedWriter<JsonValue>,
+    device_count: usize,
+    record_errors: bool,
+}
+
+impl ThreadedJsonWriter {
+    /// Creates a new writer and writes the header with the parameters and the device metadata
+    pub fn new<W>(
+        writer: W,
+        parameters: JsonObject,
+        devices: Vec<JsonValue>,
+        record_errors: bool,
+        options: WriterOptions,
+    ) -> Self
+    where
+        W: Write + Send + Sync + 'static,
+    {
+        let mut json_writer = Self::append(writer, devices.len(), record_errors, options);
+        // a failed header write is returned by the next record
+        let _ = json_writer.write(
+            JsonObject::new()
+                .field("type", "header")
+                .field(
+                    "timestamp",
+                    Local::now().format(TIMESTAMP_FORMAT).to_string(),
+                )
+                .field("parameters", parameters)
+                .field("devices", devices),
+        );
+
+        json_writer
+    }
+
+    /// Creates a new writer that appends to a file with an existing header
+    /// without writing the header again
+    pub fn append<W>(
+        writer: W,
+        device_count: usize,
+        record_errors: bool,
+        options: WriterOptions,
+    ) -> Self
+    where
+        W: Write + Send + Sync + 'static,
+    {
+        Self {
+            inner: ThreadedWriter::with_options(writer, options, write_json_line),
+            device_count,
+            record_errors,
+        }
+    }
+
+    /// Returns if errors are written as records
+    pub fn records_errors(&self) -> bool {
+        self.record_errors
+    }
+
+    /// Adds a record with the index of the device it was calculated on.
+    /// Records that weren't calculated on a single device have no device index.
+    pub fn add_record<R: JsonRecord>(&mut self, record: &R) -> io::Result<()> {
+        let object = JsonObject::new()
+            .field("type", R::TYPE)
+            .extend(record.to_json());
+        // every record of a run with only one device was calculated on it
+        let device = match record.device() {
+            None if self.device_count == 1 => Some(0),
+            device => device,
+        };
+        let object = match device {
+            Some(device) => object.field("device", device),
+            None => object,
+        };
+
+        self.write(object)
+    }
+
+    /// Adds a record that only contains the error
+    pub fn add_error<E: Display>(&mut self, error: &E) -> io::Result<()> {
+        self.write(
+            JsonObject::new()
+                .field("type", "error")
+                .field(
+                    "timestamp",
+                    Local::now().format(TIMESTAMP_FORMAT).to_string(),
+                )
+                .field("error", error.to_string()),
+        )
+    }
+
+    /// Waits until all previously added records are flushed
+    pub fn flush(&self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    /// Closes the writer and returns the first error that occurred while writing
+    pub fn close(self) -> io::Result<()> {
+        self.inner.close()
+    }
+
+    fn write(&mut self, object: JsonObject) -> io::Result<()> {
+        self.inner.write(object.into())
+    }
+}
+
+/// Writes the summary of a run as a single JSON document
+/// together with the parameters and the metadata of all devices
+pub struct JsonSummaryWriter<W: Write> {
+    writer: W,
+    parameters: JsonObject,
+    devices: Vec<JsonValue>,
+}
+
+impl<W: Write> JsonSummaryWriter<W> {
+    pub fn new(writer: W, parameters: JsonObject, devices: Vec<JsonValue>) -> Self {
+        Self {
+            writer,
+            parameters,
+            devices,
+        }
+    }
+
+    /// Writes the document with the summary once the run is finished
+    pub fn write(mut self, summary: JsonObject) -> io::Result<()> {
+        let document = JsonObject::new()
+            .field(
+                "timestamp",
+                Local::now().format(TIMESTAMP_FORMAT).to_string(),
+            )
+            .field("parameters", self.parameters)
+            .field("devices", self.devices)
+            .field("summary", summary);
+        let mut output = Vec::new();
+        JsonValue::from(document).write(&mut output);
+        output.push(b'\n');
+        self.writer.write_all(&output)?;
+
+        self.writer.flush()
+    }
+}
+
+/// Serializes the value as one line of a JSON Lines file
+fn write_json_line(value: JsonValue, output: &mut Vec<u8>) {
+    value.write(output);
+    output.push(b'\n');
+}
+
+/// Writes the string with quotes and escapes quotes, backslashes and control characters
+fn write_json_string(string: &str, output: &mut Vec<u8>) {
+    output.push(b'"');
+    for c in string.chars() {
+        match c {
+            '"' => output.extend_from_slice(b"\\\""),
+            '\\' => output.extend_from_slice(b"\\\\"),
+            '\n' => output.extend_from_slice(b"\\n"),
+            '\r' => output.extend_from_slice(b"\\r"),
+            '\t' => output.extend_from_slice(b"\\t"),
+            c if (c as u32) < 0x20 => {
+                write!(output, "\\u{:04x}", c as u32).unwrap();
+            }
+            c => {
+                let mut bytes = [0u8; 4];
+                output.extend_from_slice(c.encode_utf8(&mut bytes).as_bytes());
+            }
+        }
+    }
+    output.push(b'"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, File};
+    use std::process;
+
+    fn to_string(value: JsonValue) -> String {
+        let mut output = Vec::new();
+        value.write(&mut output);
+
+        String::from_utf8(output).unwrap()
+    }
+
+    struct Step {
+        count: u64,
+        device: Option<usize>,
+    }
+
+    impl JsonRecord for Step {
+        const TYPE: &'static str = "step";
+
+        fn to_json(&self) -> JsonObject {
+            JsonObject::new().field("count", self.count)
+        }
+
+        fn device(&self) -> Option<usize> {
+            self.device
+        }
+    }
+
+    #[test]
+    fn strings_are_escaped() {
+        assert_eq!(to_string("plain".into()), r#""plain""#);
+        assert_eq!(
+            to_string(r#"a "quoted" value"#.into()),
+            r#""a \"quoted\" value""#
+        );
+        assert_eq!(to_string(r"C:\path\\".into()), r#""C:\\path\\\\""#);
+        assert_eq!(
+            to_string("line\nbreak\r\ttab".into()),
+            r#""line\nbreak\r\ttab""#
+        );
+        assert_eq!(
+            to_string("\u{0}\u{1}\u{1b}\u{1f} \u{7f}".into()),
+            "\"\\u0000\\u0001\\u001b\\u001f \u{7f}\""
+        );
+        assert_eq!(to_string("Grüße ✓".into()), "\"Grüße ✓\"");
+    }
+
+    #[test]
+    fn field_names_are_escaped() {
+        let object = JsonObject::new().field("a\"b", 1u64).field("c\\d", "e");
+
+        assert_eq!(to_string(object.into()), r#"{"a\"b":1,"c\\d":"e"}"#);
+    }
+
+    #[test]
+    fn non_finite_floats_are_null() {
+        assert_eq!(to_string(f64::NAN.into()), "null");
+        assert_eq!(to_string(f64::INFINITY.into()), "null");
+        assert_eq!(to_string(f64::NEG_INFINITY.into()), "null");
+        assert_eq!(to_string(1.5f64.into()), "1.5");
+        assert_eq!(to_string(vec![0.25, f64::NAN].into()), "[0.25,null]");
+    }
+
+    #[test]
+    fn header_is_written_once() {
+        let path = std::env::temp_dir().join(format!("json-header-{}.jsonl", process::id()));
+        let devices = vec![
+            JsonObject::new().field("name", "first").into(),
+            JsonObject::new().field("name", "second").into(),
+        ];
+        let parameters = JsonObject::new().field("start", 1u64);
+        let mut writer = ThreadedJsonWriter::new(
+            File::create(&path).unwrap(),
+            parameters,
+            devices,
+            true,
+            WriterOptions::default(),
+        );
+
+        writer
+            .add_record(&Step {
+                count: 1,
+                device: Some(1),
+            })
+            .unwrap();
+        writer
+            .add_record(&Step {
+                count: 2,
+                device: None,
+            })
+            .unwrap();
+        writer.add_error(&"failed").unwrap();
+        writer.close().unwrap();
+
+        let content = fs::read_to_string(&path).unwrap();
+        fs::remove_file(path).unwrap();
+        let lines = content.lines().collect::<Vec<&str>>();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with(r#"{"type":"header","timestamp":""#));
+        assert!(lines[0].ends_with(
+            r#","parameters":{"start":1},"devices":[{"name":"first"},{"name":"second"}]}"#
+        ));
+        assert_eq!(lines[1], r#"{"type":"step","count":1,"device":1}"#);
+        assert_eq!(lines[2], r#"{"type":"step","count":2}"#);
+        assert!(lines[3].starts_with(r#"{"type":"error","timestamp":""#));
+        assert!(lines[3].ends_with(r#","error":"failed"}"#));
+        assert_eq!(content.matches("parameters").count(), 1);
+    }
+
+    #[test]
+    fn records_of_a_single_device_reference_it() {
+        let path = std::env::temp_dir().join(format!("json-single-{}.jsonl", process::id()));
+        let devices = vec![JsonObject::new().field("name", "only").into()];
+        let mut writer = ThreadedJsonWriter::new(
+            File::create(&path).unwrap(),
+            JsonObject::new(),
+            devices,
+            false,
+            WriterOptions::default(),
+        );
+
+        writer
+            .add_record(&Step {
+                count: 3,
+                device: None,
+            })
+            .unwrap();
+        writer.close().unwrap();
+
+        let content = fs::read_to_string(&path).unwrap();
+        fs::remove_file(path).unwrap();
+        assert_eq!(
+            content.lines().nth(1),
+            Some(r#"{"type":"step","count":3,"device":0}"#)
+        );
+    }
+
+    #[test]
+    fn summary_is_a_single_document() {
+        let mut output = Vec::new();
+        let devices = vec![JsonObject::new().field("name", "only").into()];
+        JsonSummaryWriter::new(&mut output, JsonObject::new().field("start", 1u64), devices)
+            .write(JsonObject::new().field("primes", 4u64))
+            .unwrap();
+
+        let content = String::from_utf8(output).unwrap();
+        assert_eq!(content.lines().count(), 1);
+        assert!(content.starts_with(r#"{"timestamp":""#));
+        assert!(content.ends_with(
+            r#","parameters":{"start":1},"devices":[{"name":"only"}],"summary":{"primes":4}}
+"#
+        ));
+    }
+}
@@ -3,5 +3,11 @@
  * Copyright (C) 2021 trivernis
  * See LICENSE for more information
  */
+pub mod binary;
 pub mod csv;
+pub mod json;
+pub mod ordered;
+pub mod reader;
+pub mod records;
+pub mod text;
 pub mod threaded;
@@ -0,0 +1,238 @@
+/*
+ * opencl demos with rust
+ * Copyright (C) 2021 trivernis
+ * See LICENSE for more information
+ */
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use ocl_stream::stream::OCLStream;
+use ocl_stream::utils::result::{OCLStreamError, OCLStreamResult};
+use parking_lot::{Condvar, Mutex};
+
+/// A value tagged with the index of the step it was calculated in
+#[derive(Clone, Debug)]
+pub struct Indexed<T> {
+    index: usize,
+    value: T,
+}
+
+impl<T> Indexed<T> {
+    pub fn new(index: usize, value: T) -> Self {
+        Self { index, value }
+    }
+}
+
+/// Limits how far producers can run ahead of the next value
+/// that is emitted by the ordered stream
+pub struct ReorderWindow {
+    state: Mutex<WindowState>,
+    condvar: Condvar,
+    size: usize,
+}
+
+struct WindowState {
+    next_index: usize,
+    /// Set when the ordered stream is dropped and no value will be emitted anymore
+    cancelled: bool,
+}
+
+impl ReorderWindow {
+    /// Creates a new window that allows `size` values to be buffered
+    pub fn new(size: usize) -> Arc<Self> {
+        Arc::new(Self {
+            state: Mutex::new(WindowState {
+                next_index: 0,
+                cancelled: false,
+            }),
+            condvar: Condvar::new(),
+            size: size.max(1),
+        })
+    }
+
+    /// Blocks until the value with the given index fits into the reorder buffer.
+    /// Returns immediately once the ordered stream is dropped so that the producers
+    /// stop when sending the value fails.
+    pub fn wait_for(&self, index: usize) {
+        let mut state = self.state.lock();
+
+        while !state.cancelled && index >= state.next_index + self.size {
+            log::trace!("Waiting for index {} to be emitted", state.next_index);
+            self.condvar.wait(&mut state);
+        }
+    }
+
+    /// Moves the window by one value
+    fn advance(&self) {
+        self.state.lock().next_index += 1;
+        self.condvar.notify_all();
+    }
+
+    /// Wakes all waiting producers because no further values are emitted
+    fn cancel(&self) {
+        self.state.lock().cancelled = true;
+        self.condvar.notify_all();
+    }
+}
+
+/// Stream that emits the values of an indexed stream strictly in the order of their index
+pub struct OrderedStream<T>
+where
+    T: Send + Sync,
+{
+    inner: OCLStream<Indexed<T>>,
+    buffer: BTreeMap<usize, T>,
+    window: Arc<ReorderWindow>,
+    next_index: usize,
+}
+
+impl<T> OrderedStream<T>
+where
+    T: Send + Sync,
+{
+    /// Creates a new ordered stream. The producers of the inner stream
+    /// need to wait for the window before sending a value.
+    pub fn new(inner: OCLStream<Indexed<T>>, window: Arc<ReorderWindow>) -> Self {
+        Self {
+            inner,
+            buffer: BTreeMap::new(),
+            window,
+            next_index: 0,
+        }
+    }
+
+    /// Returns the next value in order of the index.
+    /// When the inner stream ends, the remaining buffered values are emitted
+    /// in order before the end of the stream is returned.
+    pub fn next(&mut self) -> OCLStreamResult<T> {
+        loop {
+            if let Some(value) = self.buffer.remove(&self.next_index) {
+                return Ok(self.emit(value));
+            }
+            match self.inner.next() {
+                Ok(indexed) => {
+                    log::trace!("Buffering value with index {}", indexed.index);
+                    self.buffer.insert(indexed.index, indexed.value);
+                }
+                Err(OCLStreamError::RecvError(e)) => {
+                    return match self.buffer.keys().next().cloned() {
+                        Some(index) => {
+                            log::warn!(
+                                "Stream ended before values {}..{} were received",
+                                self.next_index,
+                                index
+                            );
+                            self.next_index = index;
+                            let value = self.buffer.remove(&index).unwrap();
+                            Ok(self.emit(value))
+                        }
+                        None => Err(OCLStreamError::RecvError(e)),
+                    };
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn emit(&mut self, value: T) -> T {
+        self.next_index += 1;
+        self.window.advance();
+
+        value
+    }
+}
+
+impl<T> Drop for OrderedStream<T>
+where
+    T: Send + Sync,
+{
+    fn drop(&mut self) {
+        self.window.cancel();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ocl_stream::stream;
+    use std::thread;
+    use std::time::Duration;
+
+    fn collect(stream: &mut OrderedStream<usize>) -> Vec<usize> {
+        let mut values = Vec::new();
+        loop {
+            match stream.next() {
+                Ok(value) => values.push(value),
+                Err(OCLStreamError::RecvError(_)) => return values,
+                Err(e) => panic!("{}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn values_are_emitted_in_order() {
+        let (inner, sender) = stream::bounded(8);
+        let window = ReorderWindow::new(4);
+        for index in &[3, 1, 0, 2] {
+            window.wait_for(*index);
+            sender.send(Indexed::new(*index, *index * 10)).unwrap();
+        }
+        drop(sender);
+
+        let mut stream = OrderedStream::new(inner, window);
+        assert_eq!(collect(&mut stream), vec![0, 10, 20, 30]);
+    }
+
+    #[test]
+    fn producers_finishing_out_of_order_wait_for_the_window() {
+        let (inner, sender) = stream::bounded(2);
+        let window = ReorderWindow::new(2);
+        let producers = (0..4)
+            .map(|producer| {
+                let sender = sender.clone();
+                let window = Arc::clone(&window);
+                thread::spawn(move || {
+                    // the later producers finish their first value earlier
+                    for index in (producer..16).step_by(4) {
+                        thread::sleep(Duration::from_millis(4 - producer as u64));
+                        window.wait_for(index);
+                        sender.send(Indexed::new(index, index)).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        drop(sender);
+
+        let mut stream = OrderedStream::new(inner, window);
+        assert_eq!(collect(&mut stream), (0..16).collect::<Vec<usize>>());
+        for producer in producers {
+            producer.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn dropping_the_stream_releases_the_producers() {
+        let (inner, sender) = stream::bounded(1);
+        let window = ReorderWindow::new(1);
+        let producer_window = Arc::clone(&window);
+        let producer = thread::spawn(move || {
+            let mut sent = 0;
+            for index in 0..1000 {
+                producer_window.wait_for(index);
+                if sender.send(Indexed::new(index, index)).is_err() {
+                    break;
+                }
+                sent += 1;
+            }
+            sent
+        });
+
+        let mut stream = OrderedStream::new(inner, window);
+        assert_eq!(stream.next().unwrap(), 0);
+        assert_eq!(stream.next().unwrap(), 1);
+        drop(stream);
+
+        assert!(producer.join().unwrap() < 1000);
+    }
+}
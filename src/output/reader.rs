@@ -0,0 +1,398 @@
+/*
+ * opencl demos with rust
+ * Copyright (C) 2021 trivernis
+ * See LICENSE for more information
+ */
+
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use crate::output::binary::{
+    fnv1a, read_varint, BinaryFormat, PrimeFileHeader, FNV_OFFSET, HEADER_LEN, INDEX_INTERVAL,
+};
+
+const READ_BLOCK_SIZE: usize = 64 * 1024;
+
+/// Reader for the binary prime files written by calculate-primes.
+/// Only odd primes are stored so 2 is never counted or iterated.
+pub struct PrimeFileReader {
+    path: PathBuf,
+    file: File,
+    header: PrimeFileHeader,
+    /// Every INDEX_INTERVAL-th prime with the offset of its value in the payload
+    index: Vec<(u64, u64)>,
+}
+
+impl PrimeFileReader {
+    /// Opens a prime file and reads its header and index
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+        let mut header_bytes = [0u8; HEADER_LEN as usize];
+        file.read_exact(&mut header_bytes)?;
+        let header = PrimeFileHeader::from_bytes(&header_bytes)?;
+
+        let mut index = Vec::with_capacity(header.index_len as usize);
+        if header.format == BinaryFormat::Varint {
+            file.seek(SeekFrom::Start(header.index_offset))?;
+            let mut reader = BufReader::new(&mut file);
+            let mut entry = [0u8; 16];
+
+            for _ in 0..header.index_len {
+                reader.read_exact(&mut entry)?;
+                index.push((
+                    u64::from_le_bytes(entry[0..8].try_into().unwrap()),
+                    u64::from_le_bytes(entry[8..16].try_into().unwrap()),
+                ));
+            }
+        }
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            header,
+            index,
+        })
+    }
+
+    pub fn header(&self) -> &PrimeFileHeader {
+        &self.header
+    }
+
+    /// Checks the content of the file against the checksum in the header
+    pub fn verify(&mut self) -> io::Result<bool> {
+        self.file.seek(SeekFrom::Start(HEADER_LEN))?;
+        let mut hash = FNV_OFFSET;
+        let mut buffer = vec![0u8; READ_BLOCK_SIZE];
+
+        loop {
+            let read = self.file.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            hash = fnv1a(hash, &buffer[..read]);
+        }
+
+        Ok(hash == self.header.checksum)
+    }
+
+    /// Returns if the number is a prime according to the file.
+    /// Numbers outside of the range of the file result in an error.
+    pub fn is_prime(&mut self, n: u64) -> io::Result<bool> {
+        if n < self.header.start || n >= self.header.end {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} is outside of the range {}..{} of the file",
+                    n, self.header.start, self.header.end
+                ),
+            ));
+        }
+        if n % 2 == 0 {
+            return Ok(n == 2);
+        }
+
+        match self.header.format {
+            BinaryFormat::Bitmap => {
+                let bit = (n - self.header.start) / 2;
+                self.file.seek(SeekFrom::Start(HEADER_LEN + bit / 8))?;
+                let mut byte = [0u8; 1];
+                self.file.read_exact(&mut byte)?;
+
+                Ok(byte[0] & (1 << (bit % 8)) != 0)
+            }
+            BinaryFormat::Varint => {
+                let block = self.index.partition_point(|(p, _)| *p <= n);
+                if block == 0 {
+                    return Ok(false);
+                }
+                let (mut value, offset) = self.index[block - 1];
+                let mut remaining = self.header.count - (block as u64 - 1) * INDEX_INTERVAL - 1;
+                let mut reader = self.block_reader(offset)?;
+
+                while value < n && remaining > 0 {
+                    value += 2 + 2 * read_varint(&mut reader)?;
+                    remaining -= 1;
+                }
+
+                Ok(value == n)
+            }
+        }
+    }
+
+    /// Returns the number of primes in the given range of numbers
+    pub fn count(&mut self, range: Range<u64>) -> io::Result<u64> {
+        let lower = range.start.max(self.header.start);
+        let upper = range.end.min(self.header.end);
+        if lower >= upper {
+            return Ok(0);
+        }
+
+        match self.header.format {
+            BinaryFormat::Bitmap => {
+                let first_bit = (lower - self.header.start + 1) / 2;
+                let last_bit = (upper - self.header.start + 1) / 2;
+                self.count_bits(first_bit, last_bit)
+            }
+            BinaryFormat::Varint => Ok(self.rank(upper)? - self.rank(lower)?),
+        }
+    }
+
+    /// Returns an iterator over all primes of the file in ascending order
+    pub fn primes(&self) -> io::Result<PrimeIter> {
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(HEADER_LEN))?;
+
+        Ok(PrimeIter {
+            reader: BufReader::new(file),
+            format: self.header.format,
+            cursor: self.header.start,
+            remaining: self.header.count,
+            byte: 0,
+            bits_left: 0,
+        })
+    }
+
+    /// Returns the number of primes below n in a varint file
+    fn rank(&mut self, n: u64) -> io::Result<u64> {
+        let block = self.index.partition_point(|(p, _)| *p < n);
+        if block == 0 {
+            return Ok(0);
+        }
+        let (mut value, offset) = self.index[block - 1];
+        let mut rank = (block as u64 - 1) * INDEX_INTERVAL + 1;
+        let count = self.header.count;
+        let mut reader = self.block_reader(offset)?;
+
+        while rank < count {
+            value += 2 + 2 * read_varint(&mut reader)?;
+            if value >= n {
+                break;
+            }
+            rank += 1;
+        }
+
+        Ok(rank)
+    }
+
+    /// Returns a reader positioned after the value of the index entry at the given offset
+    fn block_reader(&mut self, offset: u64) -> io::Result<BufReader<&mut File>> {
+        self.file.seek(SeekFrom::Start(HEADER_LEN + offset))?;
+        let mut reader = BufReader::new(&mut self.file);
+        read_varint(&mut reader)?;
+
+        Ok(reader)
+    }
+
+    /// Counts the set bits in the bitmap between the first (inclusive) and last (exclusive) bit
+    fn count_bits(&mut self, first_bit: u64, last_bit: u64) -> io::Result<u64> {
+        let mut byte_index = first_bit / 8;
+        let last_byte = (last_bit + 7) / 8;
+        let mut buffer = vec![0u8; READ_BLOCK_SIZE];
+        let mut count = 0u64;
+        self.file.seek(SeekFrom::Start(HEADER_LEN + byte_index))?;
+
+        while byte_index < last_byte {
+            let len = ((last_byte - byte_index) as usize).min(READ_BLOCK_SIZE);
+            self.file.read_exact(&mut buffer[..len])?;
+
+            for byte in &buffer[..len] {
+                let mut mask = 0xffu8;
+                if byte_index == first_bit / 8 {
+                    mask &= 0xff << (first_bit % 8);
+                }
+                if byte_index == last_bit / 8 {
+                    mask &= !(0xff << (last_bit % 8));
+                }
+                count += (byte & mask).count_ones() as u64;
+                byte_index += 1;
+            }
+        }
+
+        Ok(count)
+    }
+}
+
+/// Iterator over the primes of a prime file
+pub struct PrimeIter {
+    reader: BufReader<File>,
+    format: BinaryFormat,
+    /// The next odd number that hasn't been read
+    cursor: u64,
+    remaining: u64,
+    byte: u8,
+    bits_left: u8,
+}
+
+impl PrimeIter {
+    fn read_next(&mut self) -> io::Result<u64> {
+        match self.format {
+            BinaryFormat::Bitmap => loop {
+                if self.bits_left == 0 {
+                    let mut byte = [0u8; 1];
+                    self.reader.read_exact(&mut byte)?;
+                    self.byte = byte[0];
+                    self.bits_left = 8;
+                }
+                let number = self.cursor;
+                let is_prime = self.byte & 1 == 1;
+                self.byte >>= 1;
+                self.bits_left -= 1;
+                self.cursor += 2;
+
+                if is_prime {
+                    return Ok(number);
+                }
+            },
+            BinaryFormat::Varint => {
+                let number = self.cursor + 2 * read_varint(&mut self.reader)?;
+                self.cursor = number + 2;
+
+                Ok(number)
+            }
+        }
+    }
+}
+
+impl Iterator for PrimeIter {
+    type Item = io::Result<u64>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let result = self.read_next();
+        match result {
+            Ok(_) => self.remaining -= 1,
+            Err(_) => self.remaining = 0,
+        }
+
+        Some(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel_controller::primes::is_prime;
+    use crate::output::binary::{finish_prime_file, PrimeEncoder};
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use std::process;
+
+    /// Writes the odd primes in the range to a prime file in chunks
+    /// and returns the path of the file with the primes
+    fn write_prime_file(
+        name: &str,
+        format: BinaryFormat,
+        range: Range<u64>,
+    ) -> (PathBuf, Vec<u64>) {
+        let path = std::env::temp_dir().join(format!("{}-{}.bin", name, process::id()));
+        let primes = range
+            .clone()
+            .filter(|n| n % 2 == 1 && is_prime(*n))
+            .collect::<Vec<u64>>();
+        let mut encoder = PrimeEncoder::new(format, range.start);
+        let mut file = File::create(&path).unwrap();
+
+        for chunk in primes.chunks(1000) {
+            file.write_all(&encoder.encode(chunk)).unwrap();
+        }
+        drop(file);
+        let header = finish_prime_file(&path, &mut encoder, range.end, 0).unwrap();
+        assert_eq!(header.count, primes.len() as u64);
+
+        (path, primes)
+    }
+
+    fn check_round_trip(name: &str, format: BinaryFormat, range: Range<u64>) {
+        let (path, primes) = write_prime_file(name, format, range.clone());
+        let mut reader = PrimeFileReader::open(&path).unwrap();
+        // even starts are moved to the next odd number
+        let file_range = reader.header().start..reader.header().end;
+
+        assert!(reader.verify().unwrap());
+        assert_eq!(reader.header().format, format);
+        assert_eq!(
+            reader
+                .primes()
+                .unwrap()
+                .collect::<io::Result<Vec<u64>>>()
+                .unwrap(),
+            primes
+        );
+        // the first and last numbers of every index block and a sample of the other numbers
+        let numbers = primes
+            .iter()
+            .step_by(INDEX_INTERVAL as usize)
+            .flat_map(|p| p - 2..p + 3)
+            .chain(range.clone().step_by(37))
+            .filter(|n| file_range.contains(n));
+        for n in numbers {
+            assert_eq!(reader.is_prime(n).unwrap(), n == 2 || is_prime(n), "{}", n);
+        }
+        assert!(reader.is_prime(file_range.end).is_err());
+
+        let width = (range.end - range.start) / 7;
+        for lower in (range.start..range.end).step_by(width as usize) {
+            for upper in [lower, lower + 1, lower + width, range.end + 10].iter() {
+                let expected = primes
+                    .iter()
+                    .filter(|p| (lower..*upper).contains(p))
+                    .count();
+                assert_eq!(
+                    reader.count(lower..*upper).unwrap(),
+                    expected as u64,
+                    "{}..{}",
+                    lower,
+                    upper
+                );
+            }
+        }
+        assert_eq!(reader.count(0..u64::MAX).unwrap(), primes.len() as u64);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn bitmap_round_trip() {
+        check_round_trip("bitmap-round-trip", BinaryFormat::Bitmap, 0..100_000);
+        check_round_trip(
+            "bitmap-round-trip-odd",
+            BinaryFormat::Bitmap,
+            1_000_001..1_100_003,
+        );
+    }
+
+    #[test]
+    fn varint_round_trip() {
+        check_round_trip("varint-round-trip", BinaryFormat::Varint, 0..100_000);
+        check_round_trip(
+            "varint-round-trip-odd",
+            BinaryFormat::Varint,
+            1_000_001..1_100_003,
+        );
+    }
+
+    #[test]
+    fn corrupted_file_fails_verification() {
+        for format in [BinaryFormat::Bitmap, BinaryFormat::Varint].iter() {
+            let (path, _) = write_prime_file("corrupted", *format, 0..10_000);
+            let mut file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&path)
+                .unwrap();
+            let mut byte = [0u8; 1];
+            file.seek(SeekFrom::Start(HEADER_LEN + 10)).unwrap();
+            file.read_exact(&mut byte).unwrap();
+            file.seek(SeekFrom::Start(HEADER_LEN + 10)).unwrap();
+            file.write_all(&[byte[0] ^ 0x10]).unwrap();
+            drop(file);
+
+            assert!(!PrimeFileReader::open(&path).unwrap().verify().unwrap());
+            fs::remove_file(path).unwrap();
+        }
+    }
+}
@@ -0,0 +1,244 @@
+/*
+ * opencl demos with rust
+ * Copyright (C) 2021 trivernis
+ * See LICENSE for more information
+ */
+
+use std::fmt::Display;
+use std::io;
+use std::time::Duration;
+
+use crate::benching::statistics::{AggregatedBenchStatistics, DurationStatistics};
+use crate::kernel_controller::bench::BenchStatistics;
+use crate::kernel_controller::DeviceInfo;
+use crate::output::csv::{CsvRecord, ThreadedCSVWriter};
+use crate::output::json::{JsonObject, JsonRecord, ThreadedJsonWriter};
+
+/// The timing of a step of the prime calculation
+pub struct PrimeTiming {
+    /// The first prime of the step. Steps without primes have none
+    pub first: Option<u64>,
+    pub count: usize,
+    pub duration: Duration,
+    pub device: usize,
+}
+
+impl CsvRecord for PrimeTiming {
+    fn columns() -> Vec<String> {
+        columns(&["first", "count", "duration", "device"])
+    }
+
+    fn values(&self) -> Vec<String> {
+        vec![
+            self.first
+                .map(|first| first.to_string())
+                .unwrap_or_default(),
+            self.count.to_string(),
+            duration_to_ms_string(&self.duration),
+            self.device.to_string(),
+        ]
+    }
+}
+
+impl JsonRecord for PrimeTiming {
+    const TYPE: &'static str = "prime_timing";
+
+    fn to_json(&self) -> JsonObject {
+        JsonObject::new()
+            .field("first", self.first)
+            .field("count", self.count)
+            .field("duration", duration_to_ms(&self.duration))
+    }
+
+    fn device(&self) -> Option<usize> {
+        Some(self.device)
+    }
+}
+
+/// The timing of a step of the prime count
+pub struct CountTiming {
+    pub offset: u64,
+    pub count: u64,
+    /// The number of primes counted up to and including this step
+    pub total: u64,
+    pub duration: Duration,
+    pub device: usize,
+}
+
+impl CsvRecord for CountTiming {
+    fn columns() -> Vec<String> {
+        columns(&["offset", "count", "total", "duration", "device"])
+    }
+
+    fn values(&self) -> Vec<String> {
+        vec![
+            self.offset.to_string(),
+            self.count.to_string(),
+            self.total.to_string(),
+            duration_to_ms_string(&self.duration),
+            self.device.to_string(),
+        ]
+    }
+}
+
+impl CsvRecord for BenchStatistics {
+    fn columns() -> Vec<String> {
+        columns(&[
+            "local_size",
+            "global_size",
+            "calc_count",
+            "write_duration",
+            "gpu_duration",
+            "read_duration",
+        ])
+    }
+
+    fn values(&self) -> Vec<String> {
+        vec![
+            self.local_size.to_string(),
+            self.global_size.to_string(),
+            self.calc_count.to_string(),
+            duration_to_ms_string(&self.write_duration),
+            duration_to_ms_string(&self.calc_duration),
+            duration_to_ms_string(&self.read_duration),
+        ]
+    }
+}
+
+impl JsonRecord for BenchStatistics {
+    const TYPE: &'static str = "bench_run";
+
+    fn to_json(&self) -> JsonObject {
+        JsonObject::new()
+            .field("local_size", self.local_size)
+            .field("global_size", self.global_size)
+            .field("calc_count", self.calc_count)
+            .field("write_duration", duration_to_ms(&self.write_duration))
+            .field("gpu_duration", duration_to_ms(&self.calc_duration))
+            .field("read_duration", duration_to_ms(&self.read_duration))
+    }
+}
+
+impl CsvRecord for AggregatedBenchStatistics {
+    fn columns() -> Vec<String> {
+        let mut columns = columns(&["local_size", "global_size", "calc_count", "repetitions"]);
+        columns.append(&mut DurationStatistics::columns("write_duration"));
+        columns.append(&mut DurationStatistics::columns("gpu_duration"));
+        columns.append(&mut DurationStatistics::columns("read_duration"));
+
+        columns
+    }
+
+    fn values(&self) -> Vec<String> {
+        let mut values = vec![
+            self.local_size.to_string(),
+            self.global_size.to_string(),
+            self.calc_count.to_string(),
+            self.repetitions.to_string(),
+        ];
+        values.append(&mut self.write_duration.values());
+        values.append(&mut self.calc_duration.values());
+        values.append(&mut self.read_duration.values());
+
+        values
+    }
+}
+
+impl JsonRecord for AggregatedBenchStatistics {
+    const TYPE: &'static str = "bench_statistics";
+
+    fn to_json(&self) -> JsonObject {
+        JsonObject::new()
+            .field("local_size", self.local_size)
+            .field("global_size", self.global_size)
+            .field("calc_count", self.calc_count)
+            .field("repetitions", self.repetitions)
+            .field("write_duration", JsonObject::from(&self.write_duration))
+            .field("gpu_duration", JsonObject::from(&self.calc_duration))
+            .field("read_duration", JsonObject::from(&self.read_duration))
+    }
+}
+
+impl From<&DurationStatistics> for JsonObject {
+    fn from(statistics: &DurationStatistics) -> Self {
+        JsonObject::new()
+            .field("mean", statistics.mean)
+            .field("median", statistics.median)
+            .field("min", statistics.min)
+            .field("max", statistics.max)
+            .field("stddev", statistics.stddev)
+            .field("ci95_low", statistics.ci95_low)
+            .field("ci95_high", statistics.ci95_high)
+    }
+}
+
+impl From<&DeviceInfo> for JsonObject {
+    fn from(info: &DeviceInfo) -> Self {
+        JsonObject::new()
+            .field("index", info.index)
+            .field("name", info.name.as_str())
+            .field("driver_version", info.driver_version.as_str())
+            .field("cpu", info.cpu)
+            .field("max_work_group_size", info.max_work_group_size)
+            .field("max_mem_alloc_size", info.max_mem_alloc_size)
+            .field("local_mem_size", info.local_mem_size)
+    }
+}
+
+/// Writes timing and benchmark records either as csv or as JSON Lines
+pub enum RecordWriter {
+    Csv(ThreadedCSVWriter),
+    JsonLines(ThreadedJsonWriter),
+}
+
+impl RecordWriter {
+    /// Returns if failed calculations are recorded
+    pub fn records_errors(&self) -> bool {
+        match self {
+            RecordWriter::Csv(writer) => writer.records_errors(),
+            RecordWriter::JsonLines(writer) => writer.records_errors(),
+        }
+    }
+
+    pub fn add_record<R: CsvRecord + JsonRecord>(&mut self, record: &R) -> io::Result<()> {
+        match self {
+            RecordWriter::Csv(writer) => writer.add_record(record),
+            RecordWriter::JsonLines(writer) => writer.add_record(record),
+        }
+    }
+
+    pub fn add_error<E: Display>(&mut self, error: &E) -> io::Result<()> {
+        match self {
+            RecordWriter::Csv(writer) => writer.add_error(error),
+            RecordWriter::JsonLines(writer) => writer.add_error(error),
+        }
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        match self {
+            RecordWriter::Csv(writer) => writer.flush(),
+            RecordWriter::JsonLines(writer) => writer.flush(),
+        }
+    }
+
+    pub fn close(self) -> io::Result<()> {
+        match self {
+            RecordWriter::Csv(writer) => writer.close(),
+            RecordWriter::JsonLines(writer) => writer.close(),
+        }
+    }
+}
+
+fn columns(names: &[&str]) -> Vec<String> {
+    names.iter().map(|name| name.to_string()).collect()
+}
+
+/// Returns the duration in milliseconds
+fn duration_to_ms(duration: &Duration) -> f64 {
+    duration.as_secs_f64() * 1000f64
+}
+
+/// Formats the duration in milliseconds
+fn duration_to_ms_string(duration: &Duration) -> String {
+    duration_to_ms(duration).to_string()
+}
@@ -0,0 +1,88 @@
+/*
+ * opencl demos with rust
+ * Copyright (C) 2021 trivernis
+ * See LICENSE for more information
+ */
+
+/// The decimal representations of all numbers below 100
+const DIGIT_PAIRS: &[u8; 200] = b"\
+    0001020304050607080910111213141516171819\
+    2021222324252627282930313233343536373839\
+    4041424344454647484950515253545556575859\
+    6061626364656667686970717273747576777879\
+    8081828384858687888990919293949596979899";
+
+/// Appends the decimal representation of the number without allocating a string.
+/// Two digits are converted at once to halve the number of divisions.
+pub fn push_u64(output: &mut Vec<u8>, mut number: u64) {
+    // u64::MAX has 20 digits
+    let mut digits = [0u8; 20];
+    let mut index = digits.len();
+
+    while number >= 100 {
+        let pair = (number % 100) as usize * 2;
+        number /= 100;
+        index -= 2;
+        digits[index..index + 2].copy_from_slice(&DIGIT_PAIRS[pair..pair + 2]);
+    }
+    if number >= 10 {
+        let pair = number as usize * 2;
+        index -= 2;
+        digits[index..index + 2].copy_from_slice(&DIGIT_PAIRS[pair..pair + 2]);
+    } else {
+        index -= 1;
+        digits[index] = b'0' + number as u8;
+    }
+
+    output.extend_from_slice(&digits[index..]);
+}
+
+/// Serializes the numbers with one number per line.
+/// Every line ends with a newline so that chunks can be concatenated.
+pub fn format_numbers(numbers: Vec<u64>, output: &mut Vec<u8>) {
+    // most numbers have less than 16 digits
+    output.reserve(numbers.len() * 16);
+    for number in numbers {
+        push_u64(output, number);
+        output.push(b'\n');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_string(number: u64) -> String {
+        let mut output = Vec::new();
+        push_u64(&mut output, number);
+
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn numbers_match_the_standard_formatting() {
+        let mut numbers = vec![0, 9, 10, 99, 100, 101, 999, 1000, u64::MAX, u64::MAX - 1];
+        numbers.extend((0..20).map(|exponent| 10u64.pow(exponent)));
+        numbers.extend((1..20).map(|exponent| 10u64.pow(exponent) - 1));
+        numbers.extend((0..64).map(|shift| 1u64 << shift));
+        for number in numbers {
+            assert_eq!(to_string(number), format!("{}", number));
+        }
+        assert_eq!(to_string(u64::MAX), "18446744073709551615");
+    }
+
+    #[test]
+    fn every_number_ends_with_a_newline() {
+        let mut output = Vec::new();
+        format_numbers(Vec::new(), &mut output);
+        assert!(output.is_empty());
+
+        format_numbers(vec![2, 3, 5], &mut output);
+        format_numbers(Vec::new(), &mut output);
+        format_numbers(vec![0, u64::MAX], &mut output);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            format!("2\n3\n5\n0\n{}\n", u64::MAX)
+        );
+    }
+}
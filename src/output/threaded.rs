@@ -4,57 +4,499 @@
  * See LICENSE for more information
  */
 
-use crossbeam_channel::Sender;
-use std::io::Write;
-use std::mem;
+use crate::kernel_controller::factor::Factorization;
+use crossbeam_channel::{Sender, TrySendError};
+use parking_lot::{Condvar, Mutex};
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, Write};
+use std::mem::{self, size_of};
+use std::str::FromStr;
+use std::sync::Arc;
 use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const DEFAULT_CAPACITY: usize = 1024;
+
+/// Decides when the writer thread flushes the underlying writer.
+/// Explicit flushes and closing the writer always flush.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlushPolicy {
+    /// Flushes after every written value
+    PerChunk,
+    /// Flushes once at least this number of bytes has been written since the last flush
+    Bytes(usize),
+    /// Only flushes when requested or when the writer is closed
+    OnClose,
+}
+
+impl FlushPolicy {
+    /// Returns if the writer should be flushed with the given number of unflushed bytes
+    fn should_flush(&self, unflushed: usize) -> bool {
+        match self {
+            FlushPolicy::PerChunk => true,
+            FlushPolicy::Bytes(limit) => unflushed >= *limit,
+            FlushPolicy::OnClose => false,
+        }
+    }
+}
+
+impl FromStr for FlushPolicy {
+    type Err = String;
+
+    /// Parses 'chunk', 'close' or a number of bytes
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "chunk" => Ok(FlushPolicy::PerChunk),
+            "close" => Ok(FlushPolicy::OnClose),
+            bytes => usize::from_str(bytes).map(FlushPolicy::Bytes).map_err(|_| {
+                format!(
+                    "Invalid flush policy '{}'. Expected 'chunk', 'close' or a number of bytes",
+                    s
+                )
+            }),
+        }
+    }
+}
+
+/// Settings of the threaded writer
+#[derive(Clone, Copy, Debug)]
+pub struct WriterOptions {
+    pub flush_policy: FlushPolicy,
+    /// The maximum number of values waiting to be written
+    pub capacity: usize,
+    /// The maximum number of bytes the values waiting to be written may occupy.
+    /// A value that exceeds the budget on its own is queued once the queue is empty.
+    pub memory_budget: Option<usize>,
+}
+
+impl Default for WriterOptions {
+    fn default() -> Self {
+        Self {
+            flush_policy: FlushPolicy::PerChunk,
+            capacity: DEFAULT_CAPACITY,
+            memory_budget: None,
+        }
+    }
+}
+
+/// Values whose memory is accounted for the memory budget of the writer
+pub trait MemorySize {
+    /// Returns the approximate number of bytes the value occupies
+    fn memory_size(&self) -> usize;
+}
+
+impl MemorySize for Vec<String> {
+    fn memory_size(&self) -> usize {
+        self.capacity() * size_of::<String>() + self.iter().map(String::capacity).sum::<usize>()
+    }
+}
+
+impl MemorySize for Vec<u64> {
+    fn memory_size(&self) -> usize {
+        self.capacity() * size_of::<u64>()
+    }
+}
+
+impl MemorySize for Vec<(u64, bool)> {
+    fn memory_size(&self) -> usize {
+        self.capacity() * size_of::<(u64, bool)>()
+    }
+}
+
+impl MemorySize for Vec<Factorization> {
+    fn memory_size(&self) -> usize {
+        self.capacity() * size_of::<Factorization>()
+            + self
+                .iter()
+                .map(|(_, factors)| factors.capacity() * size_of::<u64>())
+                .sum::<usize>()
+    }
+}
+
+/// Snapshot of the queue of the writer
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WriterMetrics {
+    /// The number of values that haven't been written yet
+    pub queue_depth: usize,
+    pub queued_bytes: usize,
+    pub peak_queue_depth: usize,
+    pub peak_queued_bytes: usize,
+    pub written_values: u64,
+    pub written_bytes: u64,
+    /// The time writes waited for space in the queue
+    pub blocked: Duration,
+}
+
+impl Display for WriterMetrics {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} values ({} bytes) written, {} values ({} bytes) queued, peak queue {} values ({} bytes), blocked for {} ms",
+            self.written_values,
+            self.written_bytes,
+            self.queue_depth,
+            self.queued_bytes,
+            self.peak_queue_depth,
+            self.peak_queued_bytes,
+            self.blocked.as_secs_f64() * 1000f64
+        )
+    }
+}
+
+/// State shared between the writer and its thread
+#[derive(Default)]
+struct WriterStatus {
+    metrics: WriterMetrics,
+    /// The first error of the writer thread
+    error: Option<io::Error>,
+    stopped: bool,
+}
+
+#[derive(Default)]
+struct SharedStatus {
+    status: Mutex<WriterStatus>,
+    /// Notified whenever a value left the queue or the thread stopped
+    dequeued: Condvar,
+}
+
+impl SharedStatus {
+    /// Removes a value from the queue and records the error of the write.
+    /// The number of written bytes is None if the value wasn't written.
+    fn dequeue(&self, size: usize, written: Option<usize>, result: &io::Result<()>) {
+        let mut status = self.status.lock();
+        let metrics = &mut status.metrics;
+        metrics.queue_depth -= 1;
+        metrics.queued_bytes -= size;
+        if let Some(written) = written {
+            metrics.written_values += 1;
+            metrics.written_bytes += written as u64;
+        }
+        mem::drop(status);
+        self.record(result);
+    }
+
+    /// Stores the first error of the writer thread and wakes up blocked writes
+    fn record(&self, result: &io::Result<()>) {
+        let mut status = self.status.lock();
+        if status.error.is_none() {
+            status.error = copy_result(result).err();
+        }
+        self.dequeued.notify_all();
+    }
+}
+
+/// Wakes up blocked writes when the writer thread stops, even if it panicked
+struct StopGuard(Arc<SharedStatus>);
+
+impl Drop for StopGuard {
+    fn drop(&mut self) {
+        self.0.status.lock().stopped = true;
+        self.0.dequeued.notify_all();
+    }
+}
+
+/// Message sent to the writer thread
+enum WriterMessage<T> {
+    /// The value and its memory size
+    Value(T, usize),
+    Flush(Sender<io::Result<()>>),
+}
 
 pub struct ThreadedWriter<T>
 where
     T: Send + Sync,
 {
-    handle: JoinHandle<()>,
-    tx: Sender<T>,
+    handle: JoinHandle<io::Result<()>>,
+    tx: Sender<WriterMessage<T>>,
+    shared: Arc<SharedStatus>,
+    memory_budget: Option<usize>,
 }
 
 impl<T> ThreadedWriter<T>
 where
-    T: Send + Sync + 'static,
+    T: MemorySize + Send + Sync + 'static,
 {
-    /// Creates a new threaded writer
-    pub fn new<W, F>(mut writer: W, serializer: F) -> Self
+    /// Creates a new threaded writer.
+    /// The serializer appends the bytes of a value to the buffer which is
+    /// reused for every value.
+    /// After a write error no further values are written and the error
+    /// is returned by the next write, flush or when closing the writer.
+    pub fn with_options<W, F>(mut writer: W, options: WriterOptions, mut serializer: F) -> Self
     where
-        F: Fn(T) -> Vec<u8> + Send + Sync + 'static,
+        F: FnMut(T, &mut Vec<u8>) + Send + 'static,
         W: Write + Send + Sync + 'static,
     {
-        log::trace!("Creating new threaded writer");
-        let (tx, rx) = crossbeam_channel::bounded(1024);
+        log::trace!("Creating new threaded writer with {:?}", options);
+        let (tx, rx) = crossbeam_channel::bounded(options.capacity.max(1));
+        let shared = Arc::new(SharedStatus::default());
+        let guard = StopGuard(Arc::clone(&shared));
+        let policy = options.flush_policy;
         let handle = thread::Builder::new()
             .name("io-thread".to_string())
             .spawn(move || {
                 log::trace!("Writing thread running");
+                let shared = &guard.0;
+                let mut result = Ok(());
+                let mut buffer = Vec::new();
+                let mut unflushed = 0;
 
-                for value in rx {
-                    log::trace!("Writing received value");
-                    let mut bytes = serializer(value);
-                    writer.write_all(&mut bytes[..]).unwrap();
-                    writer.flush().unwrap();
+                for message in rx {
+                    match message {
+                        WriterMessage::Value(value, size) if result.is_ok() => {
+                            log::trace!("Writing received value");
+                            buffer.clear();
+                            serializer(value, &mut buffer);
+                            result = writer.write_all(&buffer);
+                            unflushed += buffer.len();
+                            if result.is_ok() && policy.should_flush(unflushed) {
+                                result = writer.flush();
+                                unflushed = 0;
+                            }
+                            if let Err(e) = &result {
+                                log::error!("Failed to write value: {}", e);
+                            }
+                            let written = result.as_ref().ok().map(|_| buffer.len());
+                            shared.dequeue(size, written, &result);
+                        }
+                        WriterMessage::Value(_, size) => {
+                            log::trace!("Discarding value after write error");
+                            shared.dequeue(size, None, &result);
+                        }
+                        WriterMessage::Flush(ack) => {
+                            log::trace!("Flushing writer");
+                            if result.is_ok() {
+                                result = writer.flush();
+                                unflushed = 0;
+                            }
+                            shared.record(&result);
+                            let _ = ack.send(copy_result(&result));
+                        }
+                    }
+                }
+                if result.is_ok() && unflushed > 0 {
+                    result = writer.flush();
                 }
+
+                result
             })
-            .unwrap();
-        Self { handle, tx }
+            .expect("Failed to spawn writer thread");
+
+        Self {
+            handle,
+            tx,
+            shared,
+            memory_budget: options.memory_budget,
+        }
     }
 
-    /// Writes a value
-    pub fn write(&self, value: T) {
+    /// Queues a value for writing.
+    /// Blocks while the queue is full or the queued values exceed the memory budget.
+    /// Returns the error of a previous write if one failed.
+    pub fn write(&self, value: T) -> io::Result<()> {
         log::trace!("Writing into threaded writer");
-        self.tx.send(value).unwrap();
+        let size = value.memory_size();
+        let mut blocked = Duration::default();
+        {
+            let mut status = self.shared.status.lock();
+            if let Some(budget) = self.memory_budget {
+                while status.metrics.queue_depth > 0
+                    && status.metrics.queued_bytes + size > budget
+                    && status.error.is_none()
+                    && !status.stopped
+                {
+                    let wait_start = Instant::now();
+                    self.shared.dequeued.wait(&mut status);
+                    blocked += wait_start.elapsed();
+                }
+            }
+            if let Err(e) = status.check() {
+                status.metrics.blocked += blocked;
+                return Err(e);
+            }
+            let metrics = &mut status.metrics;
+            metrics.queue_depth += 1;
+            metrics.queued_bytes += size;
+            metrics.peak_queue_depth = metrics.peak_queue_depth.max(metrics.queue_depth);
+            metrics.peak_queued_bytes = metrics.peak_queued_bytes.max(metrics.queued_bytes);
+        }
+        let sent = match self.tx.try_send(WriterMessage::Value(value, size)) {
+            Ok(()) => true,
+            Err(TrySendError::Full(message)) => {
+                let wait_start = Instant::now();
+                let sent = self.tx.send(message).is_ok();
+                blocked += wait_start.elapsed();
+                sent
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        };
+
+        let mut status = self.shared.status.lock();
+        let metrics = &mut status.metrics;
+        metrics.blocked += blocked;
+        if sent {
+            Ok(())
+        } else {
+            // the value never reached the writer thread
+            metrics.queue_depth -= 1;
+            metrics.queued_bytes -= size;
+            Err(thread_stopped())
+        }
+    }
+
+    /// Waits until all previously written values are flushed to the underlying writer
+    pub fn flush(&self) -> io::Result<()> {
+        log::trace!("Waiting for threaded writer to flush");
+        let (ack_tx, ack_rx) = crossbeam_channel::bounded(1);
+        self.tx
+            .send(WriterMessage::Flush(ack_tx))
+            .map_err(|_| thread_stopped())?;
+
+        ack_rx.recv().map_err(|_| thread_stopped())?
+    }
+
+    /// Returns the current metrics of the queue
+    pub fn metrics(&self) -> WriterMetrics {
+        self.shared.status.lock().metrics
     }
 
     /// Closes the channel to the writer and waits for the writer thread to stop
-    pub fn close(self) {
+    pub fn close(self) -> io::Result<()> {
         log::trace!("Closing file and shutting down thread");
         mem::drop(self.tx);
-        self.handle.join().unwrap();
+
+        let result = self.handle.join().unwrap_or_else(|_| {
+            Err(io::Error::new(
+                io::ErrorKind::Other,
+                "Writer thread panicked",
+            ))
+        });
+        log::debug!(
+            "Closed threaded writer: {}",
+            self.shared.status.lock().metrics
+        );
+
+        result
+    }
+}
+
+impl WriterStatus {
+    /// Returns the error of the writer thread
+    fn check(&self) -> io::Result<()> {
+        match &self.error {
+            Some(e) => Err(io::Error::new(e.kind(), e.to_string())),
+            None if self.stopped => Err(thread_stopped()),
+            None => Ok(()),
+        }
+    }
+}
+
+fn thread_stopped() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "The writer thread stopped")
+}
+
+/// Copies the result of the writer thread as io errors can't be cloned
+fn copy_result(result: &io::Result<()>) -> io::Result<()> {
+    match result {
+        Ok(()) => Ok(()),
+        Err(e) => Err(io::Error::new(e.kind(), e.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossbeam_channel::Receiver;
+
+    /// Fails every write
+    struct FailingWriter;
+
+    impl Write for FailingWriter {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::PermissionDenied, "disk full"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Waits for a permit before every write
+    struct GatedWriter(Receiver<()>);
+
+    impl Write for GatedWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.recv().unwrap();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn serialize(values: Vec<u64>, output: &mut Vec<u8>) {
+        output.extend(values.iter().map(|v| *v as u8));
+    }
+
+    #[test]
+    fn write_errors_are_returned() {
+        let writer =
+            ThreadedWriter::with_options(FailingWriter, WriterOptions::default(), serialize);
+        // the error is only known after the value has been queued
+        writer.write(vec![1]).unwrap();
+
+        let error = writer.flush().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        let error = writer.write(vec![2]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(error.to_string(), "disk full");
+
+        let metrics = writer.metrics();
+        assert_eq!(metrics.queue_depth, 0);
+        assert_eq!(metrics.queued_bytes, 0);
+        assert_eq!(metrics.written_values, 0);
+        assert_eq!(
+            writer.close().unwrap_err().kind(),
+            io::ErrorKind::PermissionDenied
+        );
+    }
+
+    #[test]
+    fn memory_budget_blocks_until_values_are_written() {
+        let value_size = vec![0u64; 4].memory_size();
+        let (permits, gate) = crossbeam_channel::unbounded();
+        let options = WriterOptions {
+            memory_budget: Some(value_size),
+            ..WriterOptions::default()
+        };
+        let writer = Arc::new(ThreadedWriter::with_options(
+            GatedWriter(gate),
+            options,
+            serialize,
+        ));
+        writer.write(vec![0u64; 4]).unwrap();
+
+        let (done_tx, done_rx) = crossbeam_channel::bounded(1);
+        let blocked_writer = Arc::clone(&writer);
+        let handle = thread::spawn(move || {
+            blocked_writer.write(vec![1u64; 4]).unwrap();
+            done_tx.send(()).unwrap();
+        });
+        // the second value doesn't fit into the budget until the first one is written
+        assert!(done_rx.recv_timeout(Duration::from_millis(100)).is_err());
+        assert_eq!(writer.metrics().queue_depth, 1);
+
+        permits.send(()).unwrap();
+        done_rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        handle.join().unwrap();
+        permits.send(()).unwrap();
+
+        let writer = Arc::try_unwrap(writer).ok().unwrap();
+        writer.flush().unwrap();
+        let metrics = writer.metrics();
+        assert_eq!(metrics.written_values, 2);
+        assert_eq!(metrics.peak_queued_bytes, value_size);
+        assert!(metrics.blocked >= Duration::from_millis(100));
+        writer.close().unwrap();
     }
 }
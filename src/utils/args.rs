@@ -4,16 +4,44 @@
  * See LICENSE for more information
  */
 
+use crate::output::threaded::FlushPolicy;
 use std::path::PathBuf;
 use structopt::StructOpt;
 
 #[derive(StructOpt, Clone, Debug)]
 #[structopt()]
-pub enum Opts {
+pub struct Opts {
+    /// The backend that is used for calculations.
+    /// With auto the cpu is used if no OpenCL device is available.
+    #[structopt(long = "backend", global = true, possible_values = &Backend::variants(), case_insensitive = true, default_value = "auto")]
+    pub backend: Backend,
+
+    /// The OpenCL platform that is used.
+    /// It can be selected by its index or a part of its name as listed by the info command.
+    #[structopt(long = "platform", global = true)]
+    pub platform: Option<String>,
+
+    /// The OpenCL device that is used.
+    /// It can be selected by its index on the platform or a part of its name as listed by the info command.
+    /// The option can be repeated and 'all' selects every device to distribute
+    /// the prime calculation on multiple devices.
+    #[structopt(long = "device", global = true, number_of_values = 1)]
+    pub devices: Vec<String>,
+
+    #[structopt(subcommand)]
+    pub command: Command,
+}
+
+#[derive(StructOpt, Clone, Debug)]
+pub enum Command {
     /// Calculates primes on the GPU
     #[structopt(name = "calculate-primes")]
     CalculatePrimes(CalculatePrimes),
 
+    /// Counts primes on the GPU without reading them from the device
+    #[structopt(name = "count-primes")]
+    CountPrimes(CountPrimes),
+
     /// Benchmarks the local size value
     #[structopt(name = "bench-local-size")]
     BenchLocalSize(BenchLocalSize),
@@ -22,7 +50,27 @@ pub enum Opts {
     #[structopt(name = "bench-global-size")]
     BenchGlobalSize(BenchGlobalSize),
 
-    /// Prints GPU information
+    /// Benchmarks every combination of local and global sizes
+    #[structopt(name = "bench-grid")]
+    BenchGrid(BenchGrid),
+
+    /// Finds the best launch parameters for the prime calculation on the device
+    #[structopt(name = "tune")]
+    Tune(Tune),
+
+    /// Checks arbitrary numbers for primality and prints them in the input order
+    #[structopt(name = "is-prime")]
+    IsPrime(IsPrime),
+
+    /// Factors arbitrary numbers and prints them in the input order
+    #[structopt(name = "factor")]
+    Factor(Factor),
+
+    /// Verifies a binary prime file and looks up primes in it
+    #[structopt(name = "read-primes")]
+    ReadPrimes(ReadPrimes),
+
+    /// Prints information about all OpenCL platforms and devices
     Info,
 }
 
@@ -35,7 +83,7 @@ pub struct CalculatePrimes {
     #[structopt(long = "start", default_value = "0")]
     pub start_offset: u64,
 
-    /// The maximum number to calculate to
+    /// The end of the range. Only primes below the end are calculated.
     #[structopt(long = "end", default_value = "9223372036854775807")]
     pub max_number: u64,
 
@@ -43,34 +91,341 @@ pub struct CalculatePrimes {
     #[structopt(short = "o", long = "output", default_value = "primes.txt")]
     pub output_file: PathBuf,
 
+    /// The format of the output file.
+    /// Bitmap stores one bit per odd number in the calculated range.
+    /// DeltaVarint stores the distances between primes as variable length integers.
+    /// Both binary formats can't be written to stdout or resumed.
+    #[structopt(long = "output-format", possible_values = &OutputFormat::variants(), case_insensitive = true, default_value = "text")]
+    pub output_format: OutputFormat,
+
+    /// When the primes are flushed to the output.
+    /// 'chunk' flushes after every step, 'close' only at the end
+    /// and a number flushes once that many bytes were written.
+    /// Checkpoints always flush the output.
+    #[structopt(long = "flush", default_value = "chunk")]
+    pub flush: FlushPolicy,
+
+    /// The maximum number of steps that wait to be written.
+    /// The calculation pauses when the queue is full.
+    #[structopt(long = "write-queue", default_value = "1024")]
+    pub write_queue: usize,
+
+    /// The maximum memory in MiB the primes waiting to be written may occupy.
+    /// The calculation pauses when the budget is exhausted. Unlimited if no value is provided.
+    #[structopt(long = "write-memory")]
+    pub write_memory: Option<usize>,
+
     /// The output file for timings
     #[structopt(long = "timings-output", default_value = "timings.csv")]
     pub timings_file: PathBuf,
 
+    /// The format of the timings file.
+    /// JsonLines writes a header with the parameters and the devices
+    /// and one JSON object per step.
+    #[structopt(long = "timings-format", possible_values = &RecordFormat::variants(), case_insensitive = true, default_value = "csv")]
+    pub timings_format: RecordFormat,
+
+    /// The output file for a JSON document with the parameters, the devices
+    /// and a summary of the whole calculation
+    #[structopt(long = "summary-output")]
+    pub summary_file: Option<PathBuf>,
+
+    /// Records failed steps in the timings file.
+    /// Csv files get an additional error column that is empty for successful steps.
+    #[structopt(long = "record-errors")]
+    pub record_errors: bool,
+
     /// The local size for the tasks.
     /// The value for numbers_per_step needs to be divisible by this number.
     /// The maximum local size depends on the gpu capabilities.
-    /// If no value is provided, OpenCL chooses it automatically.
+    /// If no value is provided, the tuned value for the device or 128 is used.
     #[structopt(long = "local-size")]
     pub local_size: Option<usize>,
 
     /// The amount of numbers that are checked per step. Even numbers are ignored so the
     /// Range actually goes to numbers_per_step * 2.
-    #[structopt(long = "numbers-per-step", default_value = "33554432")]
-    pub numbers_per_step: usize,
+    /// If no value is provided, the tuned value for the device or 33554432 is used.
+    #[structopt(long = "numbers-per-step")]
+    pub numbers_per_step: Option<usize>,
+
+    /// The file with the launch parameters found by the tune command
+    #[structopt(long = "profiles", default_value = "device_profiles.txt")]
+    pub profiles_file: PathBuf,
+
+    /// The algorithm that is used to find primes.
+    /// The sieve algorithm sieves segments of odd numbers in local memory.
+    /// The Miller-Rabin test is deterministic and correct for every 64 bit number.
+    #[structopt(long = "algorithm", possible_values = &Algorithm::variants(), case_insensitive = true, default_value = "TrialDivision")]
+    pub algorithm: Algorithm,
+
+    /// The amount of odd numbers that are sieved by one work group with the sieve algorithm.
+    /// The segment is stored in local memory so it can't be larger than the local memory size.
+    #[structopt(long = "segment-size", default_value = "16384")]
+    pub segment_size: usize,
 
     /// If the prime numbers should be used for the divisibility check instead of using
     /// an optimized auto-increment loop.
     #[structopt(long = "no-cache")]
     pub no_cache: bool,
 
+    /// The file the primes of the cache are loaded from and stored in
+    /// so that they don't need to be calculated again by later runs
+    #[structopt(long = "prime-cache")]
+    pub prime_cache_file: Option<PathBuf>,
+
+    /// Uploads the odd numbers to the devices instead of generating them in the kernels
+    /// to measure the cost of the upload. The sieve always generates the numbers.
+    #[structopt(long = "upload-numbers")]
+    pub upload_numbers: bool,
+
     /// If the calculated prime numbers should be validated on the cpu by a simple prime algorithm
     #[structopt(long = "cpu-validate")]
     pub cpu_validate: bool,
 
-    /// Calculates primes on the cpu instead using the same algorithm
+    /// The maximum number of steps that are buffered to write the primes in ascending order.
+    /// Calculations pause when a step is further ahead than this number.
+    #[structopt(long = "reorder-buffer", default_value = "16")]
+    pub reorder_buffer: usize,
+
+    /// The file that stores the progress of the calculation after every step
+    #[structopt(long = "checkpoint", default_value = "primes.checkpoint")]
+    pub checkpoint_file: PathBuf,
+
+    /// Continues the calculation from the checkpoint and appends
+    /// to the output and timings files instead of recreating them.
+    /// The end, algorithm, numbers per step, formats and prime cache need to match the checkpoint.
+    #[structopt(long = "resume", conflicts_with = "stdout")]
+    pub resume: bool,
+
+    /// Calculates primes with the selected algorithm on the cpu backend
+    /// instead of the OpenCL devices. Overrides the --backend option.
     #[structopt(long = "use-cpu")]
     pub use_cpu: bool,
+
+    /// Calculates primes on the cpu and the OpenCL devices at the same time.
+    /// Each device takes more numbers at once the faster it is.
+    #[structopt(long = "hybrid", conflicts_with = "use-cpu")]
+    pub hybrid: bool,
+}
+
+#[derive(StructOpt, Clone, Debug)]
+pub struct CountPrimes {
+    #[structopt(flatten)]
+    pub general_options: GeneralOptions,
+
+    /// The number to start with
+    #[structopt(long = "start", default_value = "0")]
+    pub start_offset: u64,
+
+    /// The end of the range. Only primes below the end are counted.
+    #[structopt(long = "end", default_value = "4294967296")]
+    pub max_number: u64,
+
+    /// The output file for the timings with the number of primes of every step
+    /// and the total number of primes up to the step
+    #[structopt(long = "timings-output", default_value = "count_timings.csv")]
+    pub timings_file: PathBuf,
+
+    /// Records failed steps in the timings file with an additional error column
+    /// that is empty for successful steps
+    #[structopt(long = "record-errors")]
+    pub record_errors: bool,
+
+    /// The local size for the tasks.
+    /// The maximum local size depends on the gpu capabilities.
+    /// If no value is provided, 128 is used.
+    #[structopt(long = "local-size")]
+    pub local_size: Option<usize>,
+
+    /// The amount of odd numbers that are counted per step.
+    /// If no value is provided, 33554432 is used.
+    #[structopt(long = "numbers-per-step")]
+    pub numbers_per_step: Option<usize>,
+
+    /// The algorithm that is used to find primes
+    #[structopt(long = "algorithm", possible_values = &Algorithm::variants(), case_insensitive = true, default_value = "Sieve")]
+    pub algorithm: Algorithm,
+
+    /// The amount of odd numbers that are sieved by one work group with the sieve algorithm.
+    /// The segment is stored in local memory so it can't be larger than the local memory size.
+    #[structopt(long = "segment-size", default_value = "16384")]
+    pub segment_size: usize,
+
+    /// If the divisibility check should use an optimized auto-increment loop
+    /// instead of the cached primes
+    #[structopt(long = "no-cache")]
+    pub no_cache: bool,
+
+    /// The file the primes of the cache are loaded from and stored in
+    /// so that they don't need to be calculated again by later runs
+    #[structopt(long = "prime-cache")]
+    pub prime_cache_file: Option<PathBuf>,
+
+    /// Uploads the odd numbers to the devices instead of generating them in the kernels
+    /// to measure the cost of the upload. The sieve always generates the numbers.
+    #[structopt(long = "upload-numbers")]
+    pub upload_numbers: bool,
+
+    /// The maximum number of steps that are buffered to write the counts in ascending order
+    #[structopt(long = "reorder-buffer", default_value = "16")]
+    pub reorder_buffer: usize,
+}
+
+#[derive(StructOpt, Clone, Debug)]
+pub struct IsPrime {
+    #[structopt(flatten)]
+    pub general_options: GeneralOptions,
+
+    #[structopt(flatten)]
+    pub input_options: NumberInputOptions,
+
+    /// The output file for the results.
+    /// Every line contains the number and if it is a prime separated by a comma.
+    #[structopt(short = "o", long = "output", default_value = "is_prime.csv")]
+    pub output_file: PathBuf,
+
+    /// The algorithm that is used to check the numbers.
+    /// Trial division uses cached primes for numbers below 2^56.
+    /// The Miller-Rabin test is faster for large numbers.
+    /// The sieve can't be used to check arbitrary numbers.
+    #[structopt(long = "algorithm", possible_values = &Algorithm::variants(), case_insensitive = true, default_value = "TrialDivision")]
+    pub algorithm: Algorithm,
+
+    /// If the divisibility check should use an optimized auto-increment loop
+    /// instead of the cached primes
+    #[structopt(long = "no-cache")]
+    pub no_cache: bool,
+}
+
+#[derive(StructOpt, Clone, Debug)]
+pub struct Factor {
+    #[structopt(flatten)]
+    pub general_options: GeneralOptions,
+
+    #[structopt(flatten)]
+    pub input_options: NumberInputOptions,
+
+    /// The output file for the results.
+    /// Every line contains the number and its prime factors separated by spaces.
+    #[structopt(short = "o", long = "output", default_value = "factors.csv")]
+    pub output_file: PathBuf,
+}
+
+#[derive(StructOpt, Clone, Debug)]
+pub struct ReadPrimes {
+    /// The binary prime file written by calculate-primes
+    #[structopt(short = "i", long = "input")]
+    pub input_file: PathBuf,
+
+    /// Prints if the number is a prime according to the file.
+    /// The option can be repeated and the numbers need to be in the range of the file.
+    #[structopt(long = "is-prime", number_of_values = 1)]
+    pub numbers: Vec<u64>,
+
+    /// Prints the number of primes in the range
+    #[structopt(long = "count")]
+    pub count: bool,
+
+    /// Prints the primes in the range
+    #[structopt(long = "print")]
+    pub print: bool,
+
+    /// The first number of the range that is counted or printed.
+    /// Defaults to the start of the file.
+    #[structopt(long = "start")]
+    pub start: Option<u64>,
+
+    /// The exclusive end of the range that is counted or printed.
+    /// Defaults to the end of the file.
+    #[structopt(long = "end")]
+    pub end: Option<u64>,
+}
+
+#[derive(StructOpt, Clone, Debug)]
+pub struct NumberInputOptions {
+    /// The file the numbers are read from. Reads from stdin if no file is given.
+    #[structopt(short = "i", long = "input")]
+    pub input_file: Option<PathBuf>,
+
+    /// The format of the input.
+    /// Text expects one number per line.
+    /// Binary expects consecutive little endian 64 bit integers.
+    #[structopt(long = "input-format", possible_values = &InputFormat::variants(), case_insensitive = true, default_value = "text")]
+    pub input_format: InputFormat,
+
+    /// The local size for the tasks.
+    /// If no value is provided, 128 is used.
+    #[structopt(long = "local-size")]
+    pub local_size: Option<usize>,
+
+    /// The amount of numbers that are processed with one kernel launch.
+    /// The last batch is padded to be divisible by the local size.
+    #[structopt(long = "batch-size", default_value = "1048576")]
+    pub batch_size: usize,
+
+    /// The maximum number of batches that are buffered to write the results in the input order
+    #[structopt(long = "reorder-buffer", default_value = "16")]
+    pub reorder_buffer: usize,
+
+    /// When the results are flushed to the output.
+    /// 'chunk' flushes after every batch, 'close' only at the end
+    /// and a number flushes once that many bytes were written.
+    #[structopt(long = "flush", default_value = "chunk")]
+    pub flush: FlushPolicy,
+
+    /// The maximum number of batches that wait to be written.
+    /// The processing pauses when the queue is full.
+    #[structopt(long = "write-queue", default_value = "1024")]
+    pub write_queue: usize,
+
+    /// The maximum memory in MiB the results waiting to be written may occupy.
+    /// The processing pauses when the budget is exhausted. Unlimited if no value is provided.
+    #[structopt(long = "write-memory")]
+    pub write_memory: Option<usize>,
+}
+
+#[derive(StructOpt, Clone, Debug)]
+pub struct Tune {
+    /// The local sizes that are tried.
+    /// Values greater than the maximum work group size of the device are skipped.
+    #[structopt(
+        long = "local-sizes",
+        use_delimiter = true,
+        default_value = "32,64,128,256,512"
+    )]
+    pub local_sizes: Vec<usize>,
+
+    /// The amounts of numbers per step that are tried
+    #[structopt(
+        long = "numbers-per-step",
+        use_delimiter = true,
+        default_value = "1048576,4194304,16777216,33554432"
+    )]
+    pub numbers_per_step: Vec<usize>,
+
+    /// The numbers of threads that are tried
+    #[structopt(long = "threads", use_delimiter = true, default_value = "1,2,4")]
+    pub threads: Vec<usize>,
+
+    /// The number to start the calculations with
+    #[structopt(long = "start", default_value = "0")]
+    pub start_offset: u64,
+
+    /// How many steps are calculated for every combination
+    #[structopt(long = "steps", default_value = "4")]
+    pub steps: usize,
+
+    /// The algorithm that is tuned
+    #[structopt(long = "algorithm", possible_values = &Algorithm::variants(), case_insensitive = true, default_value = "TrialDivision")]
+    pub algorithm: Algorithm,
+
+    /// The amount of odd numbers that are sieved by one work group with the sieve algorithm
+    #[structopt(long = "segment-size", default_value = "16384")]
+    pub segment_size: usize,
+
+    /// The file the best launch parameters are stored in
+    #[structopt(long = "profiles", default_value = "device_profiles.txt")]
+    pub profiles_file: PathBuf,
 }
 
 #[derive(StructOpt, Clone, Debug)]
@@ -119,6 +474,47 @@ pub struct BenchGlobalSize {
     pub local_size: usize,
 }
 
+#[derive(StructOpt, Clone, Debug)]
+pub struct BenchGrid {
+    #[structopt(flatten)]
+    pub bench_options: BenchOptions,
+
+    /// The initial number for the local size
+    #[structopt(long = "local-size-start", default_value = "32")]
+    pub local_size_start: usize,
+
+    /// The amount the local size increases by every step with linear stepping
+    #[structopt(long = "local-size-step", default_value = "32")]
+    pub local_size_step: usize,
+
+    /// The maximum amount of the local size.
+    /// Values greater than the maximum work group size of the device are skipped.
+    #[structopt(long = "local-size-stop", default_value = "1024")]
+    pub local_size_stop: usize,
+
+    /// The start value for the used global size
+    #[structopt(long = "global-size-start", default_value = "1024")]
+    pub global_size_start: usize,
+
+    /// The amount the global size increases by every step with linear stepping
+    #[structopt(long = "global-size-step", default_value = "1024")]
+    pub global_size_step: usize,
+
+    /// The stop value for the used global size
+    #[structopt(long = "global-size-stop", default_value = "1048576")]
+    pub global_size_stop: usize,
+
+    /// How the local and global sizes increase.
+    /// With pow2 every value is double the previous one and the step values are ignored.
+    #[structopt(long = "stepping", possible_values = &Stepping::variants(), case_insensitive = true, default_value = "linear")]
+    pub stepping: Stepping,
+
+    /// The output file for the matrix of the mean GPU durations
+    /// with a row for every local size and a column for every global size
+    #[structopt(long = "matrix-output")]
+    pub matrix_file: Option<PathBuf>,
+}
+
 #[derive(StructOpt, Clone, Debug)]
 pub struct BenchOptions {
     #[structopt(flatten)]
@@ -132,10 +528,31 @@ pub struct BenchOptions {
     #[structopt(short = "o", long = "bench-output")]
     pub benchmark_file: Option<PathBuf>,
 
-    /// The average of n runs that is used instead of using one value only.
-    /// By default the benchmark for each step is only run once
+    /// How often the benchmark is run for every configuration.
+    /// The output contains the mean, median, min, max, standard deviation
+    /// and 95% confidence interval of the durations of all runs.
     #[structopt(short = "r", long = "repetitions", default_value = "1")]
     pub repetitions: usize,
+
+    /// The output file for the durations of every single run
+    #[structopt(long = "raw-output")]
+    pub raw_output_file: Option<PathBuf>,
+
+    /// The format of the benchmark and raw output files.
+    /// JsonLines writes a header with the parameters and the device
+    /// and one JSON object per result.
+    #[structopt(long = "bench-format", possible_values = &RecordFormat::variants(), case_insensitive = true, default_value = "csv")]
+    pub bench_format: RecordFormat,
+
+    /// The output file for a JSON document with the parameters, the device
+    /// and a summary of the benchmark
+    #[structopt(long = "summary-output")]
+    pub summary_file: Option<PathBuf>,
+
+    /// Records failed benchmarks in the benchmark and raw output files.
+    /// Csv files get an additional error column that is empty for successful benchmarks.
+    #[structopt(long = "record-errors")]
+    pub record_errors: bool,
 }
 
 #[derive(StructOpt, Clone, Debug)]
@@ -144,15 +561,67 @@ pub struct GeneralOptions {
     #[structopt(long = "color", possible_values = &UseColors::variants(), case_insensitive = true, default_value = "auto")]
     pub color: UseColors,
 
-    /// number of used threads
-    #[structopt(short = "p", long = "threads", default_value = "2")]
-    pub threads: usize,
+    /// number of used threads.
+    /// Defaults to 2 or the tuned value for the device when calculating primes.
+    #[structopt(short = "p", long = "threads")]
+    pub threads: Option<usize>,
 
     /// Print results to stdout instead of writing them into a file
     #[structopt(long = "stdout")]
     pub stdout: bool,
 }
 
+arg_enum! {
+    #[derive(Clone, Debug)]
+    pub enum Backend {
+        Auto,
+        OpenCL,
+        Cpu,
+    }
+}
+
+arg_enum! {
+    #[derive(Clone, Debug)]
+    pub enum Algorithm {
+        TrialDivision,
+        Sieve,
+        MillerRabin,
+    }
+}
+
+arg_enum! {
+    #[derive(Clone, Debug)]
+    pub enum OutputFormat {
+        Text,
+        Bitmap,
+        DeltaVarint,
+    }
+}
+
+arg_enum! {
+    #[derive(Clone, Debug)]
+    pub enum RecordFormat {
+        Csv,
+        JsonLines,
+    }
+}
+
+arg_enum! {
+    #[derive(Clone, Debug)]
+    pub enum InputFormat {
+        Text,
+        Binary,
+    }
+}
+
+arg_enum! {
+    #[derive(Clone, Debug)]
+    pub enum Stepping {
+        Linear,
+        Pow2,
+    }
+}
+
 arg_enum! {
     #[derive(Clone, Debug)]
     pub enum UseColors {
@@ -161,3 +630,22 @@ arg_enum! {
         Auto,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Opts, structopt::clap::Error> {
+        Opts::from_iter_safe(std::iter::once("rust-opencl-demo").chain(args.iter().copied()))
+    }
+
+    #[test]
+    fn record_errors_is_only_accepted_with_timings() {
+        for command in &["calculate-primes", "count-primes", "bench-local-size"] {
+            assert!(parse(&[command, "--record-errors"]).is_ok(), "{}", command);
+        }
+        for command in &["is-prime", "factor"] {
+            assert!(parse(&[command, "--record-errors"]).is_err(), "{}", command);
+        }
+    }
+}
@@ -0,0 +1,231 @@
+/*
+ * opencl demos with rust
+ * Copyright (C) 2021 trivernis
+ * See LICENSE for more information
+ */
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::utils::error::{DemoError, DemoResult};
+
+/// State of a prime calculation that allows resuming it.
+/// The lengths are the sizes of the output files when the
+/// checkpoint was written so that content written afterwards can be discarded.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Checkpoint {
+    /// The first number that hasn't been calculated yet.
+    /// Every number below it has been written to the output.
+    pub offset: u64,
+    pub output_len: u64,
+    pub timings_len: u64,
+    pub parameters: CheckpointParameters,
+}
+
+/// The parameters of a prime calculation that need to be the same
+/// when it is resumed so that the output of both runs fits together
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CheckpointParameters {
+    pub end: u64,
+    pub algorithm: String,
+    pub numbers_per_step: usize,
+    pub output_format: String,
+    pub timings_format: String,
+    /// The limit of the cached primes used by the trial division or 0 without the cache
+    pub cache_limit: u64,
+}
+
+impl Checkpoint {
+    /// Reads a checkpoint from the given file
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let content = fs::read_to_string(path)?;
+        let mut checkpoint = Self::default();
+        let parameters = &mut checkpoint.parameters;
+
+        for line in content.lines().filter(|l| !l.trim().is_empty()) {
+            let (key, value) = line.split_once('=').ok_or_else(|| invalid_data(line))?;
+            let value = value.trim();
+
+            match key.trim() {
+                "offset" => checkpoint.offset = parse(line, value)?,
+                "output_len" => checkpoint.output_len = parse(line, value)?,
+                "timings_len" => checkpoint.timings_len = parse(line, value)?,
+                "end" => parameters.end = parse(line, value)?,
+                "algorithm" => parameters.algorithm = value.to_string(),
+                "numbers_per_step" => parameters.numbers_per_step = parse(line, value)?,
+                "output_format" => parameters.output_format = value.to_string(),
+                "timings_format" => parameters.timings_format = value.to_string(),
+                "cache_limit" => parameters.cache_limit = parse(line, value)?,
+                _ => return Err(invalid_data(line)),
+            }
+        }
+
+        Ok(checkpoint)
+    }
+
+    /// Writes the checkpoint into a temporary file and moves it
+    /// to the given path so that the checkpoint is never partially written
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let tmp_path = path.with_extension("tmp");
+        let parameters = &self.parameters;
+        let content = format!(
+            "offset={}\noutput_len={}\ntimings_len={}\nend={}\nalgorithm={}\nnumbers_per_step={}\noutput_format={}\ntimings_format={}\ncache_limit={}\n",
+            self.offset,
+            self.output_len,
+            self.timings_len,
+            parameters.end,
+            parameters.algorithm,
+            parameters.numbers_per_step,
+            parameters.output_format,
+            parameters.timings_format,
+            parameters.cache_limit
+        );
+        fs::write(&tmp_path, content)?;
+
+        fs::rename(tmp_path, path)
+    }
+
+    /// Returns an error if the checkpoint was written by a run with other parameters
+    pub fn verify(&self, parameters: &CheckpointParameters) -> DemoResult<()> {
+        let checkpoint = &self.parameters;
+        let mismatches = [
+            (
+                "end",
+                checkpoint.end.to_string(),
+                parameters.end.to_string(),
+            ),
+            (
+                "algorithm",
+                checkpoint.algorithm.clone(),
+                parameters.algorithm.clone(),
+            ),
+            (
+                "numbers per step",
+                checkpoint.numbers_per_step.to_string(),
+                parameters.numbers_per_step.to_string(),
+            ),
+            (
+                "output format",
+                checkpoint.output_format.clone(),
+                parameters.output_format.clone(),
+            ),
+            (
+                "timings format",
+                checkpoint.timings_format.clone(),
+                parameters.timings_format.clone(),
+            ),
+            (
+                "prime cache limit",
+                checkpoint.cache_limit.to_string(),
+                parameters.cache_limit.to_string(),
+            ),
+        ]
+        .iter()
+        .filter(|(_, checkpoint, current)| checkpoint != current)
+        .map(|(name, checkpoint, current)| {
+            format!("{} {} instead of {}", name, current, checkpoint)
+        })
+        .collect::<Vec<String>>();
+
+        if mismatches.is_empty() {
+            Ok(())
+        } else {
+            Err(DemoError::Argument(format!(
+                "The checkpoint was written with other parameters. The run uses the {}",
+                mismatches.join(", the ")
+            )))
+        }
+    }
+}
+
+fn parse<T: FromStr>(line: &str, value: &str) -> io::Result<T> {
+    T::from_str(value).map_err(|_| invalid_data(line))
+}
+
+fn invalid_data(line: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid checkpoint entry '{}'", line),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    fn checkpoint() -> Checkpoint {
+        Checkpoint {
+            offset: 1_048_577,
+            output_len: 4096,
+            timings_len: 512,
+            parameters: CheckpointParameters {
+                end: 10_000_000,
+                algorithm: "TrialDivision".to_string(),
+                numbers_per_step: 16384,
+                output_format: "Text".to_string(),
+                timings_format: "Csv".to_string(),
+                cache_limit: 3162,
+            },
+        }
+    }
+
+    fn load_content(name: &str, content: &str) -> io::Result<Checkpoint> {
+        let path = std::env::temp_dir().join(format!("{}-{}.checkpoint", name, process::id()));
+        fs::write(&path, content).unwrap();
+        let checkpoint = Checkpoint::load(&path);
+        fs::remove_file(path).unwrap();
+
+        checkpoint
+    }
+
+    #[test]
+    fn save_and_load() {
+        let path = std::env::temp_dir().join(format!("round-trip-{}.checkpoint", process::id()));
+        checkpoint().save(&path).unwrap();
+        let loaded = Checkpoint::load(&path).unwrap();
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(loaded, checkpoint());
+    }
+
+    #[test]
+    fn malformed_entries_are_rejected() {
+        for content in &[
+            "offset\n",
+            "offset=abc\n",
+            "offset=-1\n",
+            "numbers_per_step=1.5\n",
+            "unknown=1\n",
+            "cache_len=12\n",
+        ] {
+            let error = load_content("malformed", content).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{}", content);
+        }
+        assert!(load_content("empty", "\n").is_ok());
+    }
+
+    #[test]
+    fn other_parameters_are_rejected() {
+        let checkpoint = checkpoint();
+        assert!(checkpoint.verify(&checkpoint.parameters).is_ok());
+
+        let mut parameters = checkpoint.parameters.clone();
+        parameters.end += 1;
+        parameters.algorithm = "Sieve".to_string();
+        match checkpoint.verify(&parameters) {
+            Err(DemoError::Argument(message)) => {
+                assert!(message.contains("end 10000001 instead of 10000000"));
+                assert!(message.contains("algorithm Sieve instead of TrialDivision"));
+                assert!(!message.contains("numbers per step"));
+            }
+            result => panic!("Unexpected result {:?}", result),
+        }
+
+        let mut parameters = checkpoint.parameters.clone();
+        parameters.cache_limit = 0;
+        assert!(checkpoint.verify(&parameters).is_err());
+    }
+}
@@ -0,0 +1,82 @@
+/*
+ * opencl demos with rust
+ * Copyright (C) 2021 trivernis
+ * See LICENSE for more information
+ */
+
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+
+use ocl_stream::utils::result::OCLStreamError;
+
+pub type DemoResult<T> = Result<T, DemoError>;
+
+/// Errors that abort the execution of a command
+#[derive(Debug)]
+pub enum DemoError {
+    /// An error of the OpenCL runtime or of the executor threads
+    OpenCL(String),
+
+    /// An error while reading or writing files
+    Io(io::Error),
+
+    /// The calculated results are wrong
+    Validation(String),
+
+    /// The given arguments can't be used together or with the device
+    Argument(String),
+}
+
+impl DemoError {
+    /// Returns the exit code of the process for the error
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            DemoError::Argument(_) => 2,
+            DemoError::OpenCL(_) => 3,
+            DemoError::Io(_) => 4,
+            DemoError::Validation(_) => 5,
+        }
+    }
+}
+
+impl Display for DemoError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            DemoError::OpenCL(e) => write!(f, "OpenCL Error: {}", e),
+            DemoError::Io(e) => write!(f, "IO Error: {}", e),
+            DemoError::Validation(e) => write!(f, "Validation Error: {}", e),
+            DemoError::Argument(e) => write!(f, "Invalid Arguments: {}", e),
+        }
+    }
+}
+
+impl Error for DemoError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DemoError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for DemoError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<ocl::Error> for DemoError {
+    fn from(e: ocl::Error) -> Self {
+        Self::OpenCL(format!("{}", e))
+    }
+}
+
+impl From<OCLStreamError> for DemoError {
+    fn from(e: OCLStreamError) -> Self {
+        match e {
+            OCLStreamError::OCLError(e) => Self::OpenCL(e),
+            e => Self::OpenCL(format!("{}", e)),
+        }
+    }
+}
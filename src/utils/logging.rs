@@ -18,7 +18,7 @@ pub fn init_logger() {
             let color = get_level_style(record.level());
             let mut thread_name = format!(
                 "thread::{}",
-                thread::current().name().unwrap_or("main").to_string()
+                thread::current().name().unwrap_or("main")
             );
             thread_name.truncate(34);
             let mut target = record.target().to_string();
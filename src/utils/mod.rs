@@ -4,5 +4,9 @@
  * See LICENSE for more information
  */
 pub mod args;
+pub mod checkpoint;
+pub mod error;
 pub mod logging;
+pub mod numbers;
+pub mod profile;
 pub mod progress;
@@ -0,0 +1,105 @@
+/*
+ * opencl demos with rust
+ * Copyright (C) 2021 trivernis
+ * See LICENSE for more information
+ */
+
+use std::io::{self, BufRead, BufReader, ErrorKind, Read};
+use std::mem::size_of;
+use std::str::FromStr;
+
+/// A batch of numbers tagged with its index in the input
+pub type NumberBatch = (usize, Vec<u64>);
+
+/// The encoding of the numbers that are read
+#[derive(Clone, Copy, Debug)]
+pub enum NumberFormat {
+    /// One decimal number per line. Empty lines are skipped.
+    Text,
+
+    /// Consecutive little endian 64 bit integers
+    Binary,
+}
+
+/// Reads unsigned 64 bit numbers in batches
+pub struct NumberReader {
+    reader: BufReader<Box<dyn Read + Send>>,
+    format: NumberFormat,
+    line: usize,
+}
+
+impl NumberReader {
+    /// Creates a new reader for numbers in the given format
+    pub fn new(reader: Box<dyn Read + Send>, format: NumberFormat) -> Self {
+        Self {
+            reader: BufReader::new(reader),
+            format,
+            line: 0,
+        }
+    }
+
+    /// Reads up to `count` numbers.
+    /// An empty batch is returned when the end of the input is reached.
+    pub fn read_batch(&mut self, count: usize) -> io::Result<Vec<u64>> {
+        let mut numbers = Vec::with_capacity(count);
+
+        while numbers.len() < count {
+            let number = match self.format {
+                NumberFormat::Text => self.read_line()?,
+                NumberFormat::Binary => self.read_binary()?,
+            };
+            match number {
+                Some(number) => numbers.push(number),
+                None => break,
+            }
+        }
+
+        Ok(numbers)
+    }
+
+    /// Reads the next non-empty line and parses it as a number
+    fn read_line(&mut self) -> io::Result<Option<u64>> {
+        let mut line = String::new();
+
+        loop {
+            line.clear();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            self.line += 1;
+            let value = line.trim();
+
+            if !value.is_empty() {
+                return u64::from_str(value).map(Some).map_err(|e| {
+                    io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Invalid number '{}' in line {}: {}", value, self.line, e),
+                    )
+                });
+            }
+        }
+    }
+
+    /// Reads the next little endian number
+    fn read_binary(&mut self) -> io::Result<Option<u64>> {
+        let mut bytes = [0u8; size_of::<u64>()];
+        let mut read = 0;
+
+        while read < bytes.len() {
+            match self.reader.read(&mut bytes[read..]) {
+                Ok(0) => break,
+                Ok(len) => read += len,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        match read {
+            0 => Ok(None),
+            8 => Ok(Some(u64::from_le_bytes(bytes))),
+            _ => Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("The input ends with an incomplete number of {} bytes", read),
+            )),
+        }
+    }
+}
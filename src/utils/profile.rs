@@ -0,0 +1,117 @@
+/*
+ * opencl demos with rust
+ * Copyright (C) 2021 trivernis
+ * See LICENSE for more information
+ */
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+/// The best launch parameters for an algorithm on a device
+#[derive(Clone, Debug)]
+pub struct DeviceProfile {
+    pub device: String,
+    pub driver_version: String,
+    pub algorithm: String,
+    pub local_size: usize,
+    pub numbers_per_step: usize,
+    pub threads: usize,
+}
+
+/// Collection of device profiles stored as blocks of key=value lines
+#[derive(Clone, Debug, Default)]
+pub struct DeviceProfiles {
+    profiles: Vec<DeviceProfile>,
+}
+
+impl DeviceProfiles {
+    /// Reads the profiles from the given file.
+    /// A missing file results in an empty collection.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e),
+        };
+        let mut profiles = Vec::new();
+
+        for block in content.split("\n\n").filter(|b| !b.trim().is_empty()) {
+            let mut profile = DeviceProfile {
+                device: String::new(),
+                driver_version: String::new(),
+                algorithm: String::new(),
+                local_size: 0,
+                numbers_per_step: 0,
+                threads: 0,
+            };
+            for line in block.lines().filter(|l| !l.trim().is_empty()) {
+                let (key, value) = line.split_once('=').ok_or_else(|| invalid_data(line))?;
+                let value = value.trim();
+                let parse_usize = || usize::from_str(value).map_err(|_| invalid_data(line));
+
+                match key.trim() {
+                    "device" => profile.device = value.to_string(),
+                    "driver_version" => profile.driver_version = value.to_string(),
+                    "algorithm" => profile.algorithm = value.to_string(),
+                    "local_size" => profile.local_size = parse_usize()?,
+                    "numbers_per_step" => profile.numbers_per_step = parse_usize()?,
+                    "threads" => profile.threads = parse_usize()?,
+                    _ => return Err(invalid_data(line)),
+                }
+            }
+            profiles.push(profile);
+        }
+
+        Ok(Self { profiles })
+    }
+
+    /// Writes the profiles into a temporary file and moves it to the given path
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let tmp_path = path.with_extension("tmp");
+        let content = self
+            .profiles
+            .iter()
+            .map(|p| {
+                format!(
+                    "device={}\ndriver_version={}\nalgorithm={}\nlocal_size={}\nnumbers_per_step={}\nthreads={}\n",
+                    p.device, p.driver_version, p.algorithm, p.local_size, p.numbers_per_step, p.threads
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n");
+        fs::write(&tmp_path, content)?;
+
+        fs::rename(tmp_path, path)
+    }
+
+    /// Returns the profile for the algorithm on the device with the given driver version
+    pub fn get(
+        &self,
+        device: &str,
+        driver_version: &str,
+        algorithm: &str,
+    ) -> Option<&DeviceProfile> {
+        self.profiles.iter().find(|p| {
+            p.device == device && p.driver_version == driver_version && p.algorithm == algorithm
+        })
+    }
+
+    /// Adds a profile and replaces the previous one of the same device, driver and algorithm
+    pub fn insert(&mut self, profile: DeviceProfile) {
+        self.profiles.retain(|p| {
+            p.device != profile.device
+                || p.driver_version != profile.driver_version
+                || p.algorithm != profile.algorithm
+        });
+        self.profiles.push(profile);
+    }
+}
+
+fn invalid_data(line: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid profile entry '{}'", line),
+    )
+}